@@ -0,0 +1,350 @@
+//! Prometheus HTTP API served from TDengine metadata and data.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use actix_web::{http::header, http::StatusCode, web, HttpRequest, HttpResponse, ResponseError};
+use serde_json::json;
+
+use bailongma::discovery::{self, TimeRange};
+use bailongma::exposition::{self, Format};
+use bailongma::federate;
+use bailongma::promql::{self, PromqlError};
+use bailongma::selector::parse_selector;
+use bailongma::*;
+
+use crate::auth::{AuthError, Permission};
+use crate::AppState;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadData(String),
+    #[error("{0}")]
+    Execution(String),
+    #[error(transparent)]
+    Read(#[from] PrometheusReaderError),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+}
+
+impl From<PromqlError> for ApiError {
+    fn from(err: PromqlError) -> Self {
+        match err {
+            PromqlError::Parse(err) => ApiError::BadData(err.to_string()),
+            PromqlError::Read(err) => ApiError::Read(err),
+            PromqlError::Eval(err) => ApiError::Execution(err),
+        }
+    }
+}
+
+impl ApiError {
+    fn error_type(&self) -> &'static str {
+        match self {
+            ApiError::BadData(_) => "bad_data",
+            ApiError::Execution(_) => "execution",
+            ApiError::Read(PrometheusReaderError::Timeout(_)) => "timeout",
+            ApiError::Read(err) if err.is_guardrail() => "execution",
+            ApiError::Read(_) => "internal",
+            ApiError::Auth(_) => "unauthorized",
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadData(_) => StatusCode::BAD_REQUEST,
+            ApiError::Execution(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Read(PrometheusReaderError::Timeout(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Read(err) if err.is_guardrail() => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Read(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Auth(err) => err.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Auth(err) = self {
+            return err.error_response();
+        }
+        HttpResponse::build(self.status_code()).json(json!({
+            "status": "error",
+            "errorType": self.error_type(),
+            "error": self.to_string(),
+        }))
+    }
+}
+
+type ApiResult = Result<HttpResponse, ApiError>;
+
+fn success<T: serde::Serialize>(data: T) -> ApiResult {
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "success",
+        "data": data,
+    })))
+}
+
+/// Parse an RFC3339 or unix timestamp (in seconds, may be fractional) into milliseconds.
+pub fn parse_time(value: &str) -> Result<i64, ApiError> {
+    if let Ok(secs) = value.parse::<f64>() {
+        return Ok((secs * 1000.).round() as i64);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp_millis())
+        .map_err(|_| ApiError::BadData(format!("cannot parse {:?} to a valid timestamp", value)))
+}
+
+/// Max points per series of a range query, as Prometheus limits.
+const MAX_POINTS_PER_SERIES: i64 = 11000;
+
+/// Parse a duration like `5m` or a float number of seconds into milliseconds.
+pub fn parse_duration(value: &str) -> Result<i64, ApiError> {
+    if let Ok(secs) = value.parse::<f64>() {
+        return Ok((secs * 1000.).round() as i64);
+    }
+    promql::parse_duration(value)
+        .ok_or_else(|| ApiError::BadData(format!("cannot parse {:?} to a valid duration", value)))
+}
+
+/// Request parameters from both url query and url-encoded form body.
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn new(
+        query: web::Query<Vec<(String, String)>>,
+        form: Option<web::Form<Vec<(String, String)>>>,
+    ) -> Self {
+        let mut params = query.into_inner();
+        if let Some(form) = form {
+            params.extend(form.into_inner());
+        }
+        Params(params)
+    }
+
+    /// Last value of the parameter.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn database(&self) -> String {
+        self.get("database").unwrap_or("prometheus").to_string()
+    }
+
+    pub fn time(&self, key: &str) -> Result<Option<i64>, ApiError> {
+        self.get(key).map(parse_time).transpose()
+    }
+
+    pub fn required(&self, key: &str) -> Result<&str, ApiError> {
+        self.get(key)
+            .ok_or_else(|| ApiError::BadData(format!("missing parameter {:?}", key)))
+    }
+
+    pub fn time_range(&self) -> Result<TimeRange, ApiError> {
+        Ok(TimeRange {
+            start: self.time("start")?,
+            end: self.time("end")?,
+        })
+    }
+
+    /// Series selectors in `match[]` parameters.
+    pub fn selectors(&self) -> Result<Vec<Vec<LabelMatcher>>, ApiError> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == "match[]")
+            .map(|(_, selector)| {
+                parse_selector(selector).map_err(|err| {
+                    ApiError::BadData(format!("invalid parameter \"match[]\": {}", err))
+                })
+            })
+            .collect()
+    }
+}
+
+async fn query(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<Vec<(String, String)>>,
+    form: Option<web::Form<Vec<(String, String)>>>,
+) -> ApiResult {
+    let params = Params::new(query, form);
+    state.authorize(&req, &params.database(), Permission::Read)?;
+    let time = match params.time("time")? {
+        Some(time) => time,
+        None => chrono::Utc::now().timestamp_millis(),
+    };
+    let value = promql::instant_query(
+        state.storage.as_ref(),
+        &params.database(),
+        params.required("query")?,
+        time,
+        &state.config().read_options(&params.database()),
+    )
+    .await?;
+    success(value.to_json(time))
+}
+
+async fn query_range(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<Vec<(String, String)>>,
+    form: Option<web::Form<Vec<(String, String)>>>,
+) -> ApiResult {
+    let params = Params::new(query, form);
+    state.authorize(&req, &params.database(), Permission::Read)?;
+    let start = parse_time(params.required("start")?)?;
+    let end = parse_time(params.required("end")?)?;
+    let step = parse_duration(params.required("step")?)?;
+    if end < start {
+        return Err(ApiError::BadData(
+            "end timestamp must not be before start time".to_string(),
+        ));
+    }
+    if step <= 0 {
+        return Err(ApiError::BadData(
+            "zero or negative query resolution step widths are not accepted".to_string(),
+        ));
+    }
+    if (end - start) / step > MAX_POINTS_PER_SERIES {
+        return Err(ApiError::BadData(format!(
+            "exceeded maximum resolution of {} points per timeseries",
+            MAX_POINTS_PER_SERIES
+        )));
+    }
+    let value = promql::range_query(
+        state.storage.as_ref(),
+        &params.database(),
+        params.required("query")?,
+        start,
+        end,
+        step,
+        &state.config().read_options(&params.database()),
+    )
+    .await?;
+    success(value.to_json(end))
+}
+
+async fn labels(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<Vec<(String, String)>>,
+    form: Option<web::Form<Vec<(String, String)>>>,
+) -> ApiResult {
+    let params = Params::new(query, form);
+    state.authorize(&req, &params.database(), Permission::Read)?;
+    let names = discovery::label_names(
+        state.storage.as_ref(),
+        &params.database(),
+        &params.selectors()?,
+        params.time_range()?,
+    )
+    .await?;
+    success(names)
+}
+
+async fn label_values(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<Vec<(String, String)>>,
+) -> ApiResult {
+    let params = Params::new(query, None);
+    state.authorize(&req, &params.database(), Permission::Read)?;
+    let values = discovery::label_values(
+        state.storage.as_ref(),
+        &params.database(),
+        &name,
+        &params.selectors()?,
+        params.time_range()?,
+    )
+    .await?;
+    success(values)
+}
+
+async fn series(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<Vec<(String, String)>>,
+    form: Option<web::Form<Vec<(String, String)>>>,
+) -> ApiResult {
+    let params = Params::new(query, form);
+    state.authorize(&req, &params.database(), Permission::Read)?;
+    let selectors = params.selectors()?;
+    if selectors.is_empty() {
+        return Err(ApiError::BadData(
+            "no match[] parameter provided".to_string(),
+        ));
+    }
+    let series = discovery::series(
+        state.storage.as_ref(),
+        &params.database(),
+        &selectors,
+        params.time_range()?,
+    )
+    .await?;
+    let series: Vec<BTreeMap<_, _>> = series
+        .into_iter()
+        .map(|labels| {
+            labels
+                .into_iter()
+                .map(|label| (label.name, label.value))
+                .collect()
+        })
+        .collect();
+    success(series)
+}
+
+/// Latest samples of matched series for scraping by downstream Prometheus servers.
+async fn federate(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<Vec<(String, String)>>,
+) -> ApiResult {
+    let params = Params::new(query, None);
+    state.authorize(&req, &params.database(), Permission::Read)?;
+    let selectors = params.selectors()?;
+    if selectors.is_empty() {
+        return Err(ApiError::BadData(
+            "no match[] parameter provided".to_string(),
+        ));
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let series =
+        federate::latest(state.storage.as_ref(), &params.database(), &selectors, now).await?;
+    let format = Format::negotiate(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok()),
+    );
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(exposition::encode(&series, format)))
+}
+
+/// Register Prometheus HTTP API endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/api/v1/query")
+            .route(web::get().to(query))
+            .route(web::post().to(query)),
+    )
+    .service(
+        web::resource("/api/v1/query_range")
+            .route(web::get().to(query_range))
+            .route(web::post().to(query_range)),
+    )
+    .service(
+        web::resource("/api/v1/labels")
+            .route(web::get().to(labels))
+            .route(web::post().to(labels)),
+    )
+    .service(web::resource("/api/v1/label/{name}/values").route(web::get().to(label_values)))
+    .service(
+        web::resource("/api/v1/series")
+            .route(web::get().to(series))
+            .route(web::post().to(series)),
+    )
+    .service(web::resource("/federate").route(web::get().to(federate)));
+}
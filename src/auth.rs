@@ -0,0 +1,233 @@
+//! Basic and bearer token authentication, with per-credential database authorization.
+//!
+//! Credentials are read from a TOML file:
+//!
+//! ```toml
+//! [[credentials]]
+//! username = "prometheus"
+//! password = "secret"
+//! databases = ["prometheus"]
+//! write = true
+//!
+//! [[credentials]]
+//! token = "grafana-token"
+//! databases = ["*"]
+//! read = true
+//! ```
+use std::path::Path;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credential {
+    /// User name of basic authentication.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Bearer token.
+    pub token: Option<String>,
+    /// Allowed databases, `*` for all.
+    #[serde(default)]
+    pub databases: Vec<String>,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default)]
+    pub write: bool,
+}
+
+impl Credential {
+    fn name(&self) -> &str {
+        self.username.as_deref().unwrap_or("<token>")
+    }
+
+    fn allows(&self, database: &str, permission: Permission) -> bool {
+        let permitted = match permission {
+            Permission::Read => self.read,
+            Permission::Write => self.write,
+        };
+        permitted
+            && self
+                .databases
+                .iter()
+                .any(|allowed| allowed == "*" || allowed == database)
+    }
+}
+
+/// Whether a secret equals the expected one, compared on digests in constant time so that
+/// neither the time nor the length leaks it.
+fn secret_eq(expected: Option<&str>, actual: &str) -> bool {
+    let expected = match expected {
+        Some(expected) => Sha256::digest(expected.as_bytes()),
+        None => return false,
+    };
+    expected.ct_eq(&Sha256::digest(actual.as_bytes())).into()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    #[serde(default)]
+    pub credentials: Vec<Credential>,
+}
+
+impl Credentials {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("read credentials file {}", path.display()))?;
+        let credentials: Credentials = toml::from_str(&content)
+            .with_context(|| format!("parse credentials file {}", path.display()))?;
+        for credential in &credentials.credentials {
+            let basic = credential.username.is_some() && credential.password.is_some();
+            anyhow::ensure!(
+                basic != credential.token.is_some(),
+                "credential {} must have either username and password, or a token",
+                credential.name()
+            );
+        }
+        Ok(credentials)
+    }
+
+    /// Find the credential of the `Authorization` header value.
+    fn authenticate(&self, authorization: &str) -> Option<&Credential> {
+        let (scheme, value) = authorization.split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(base64::decode(value).ok()?).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            self.credentials.iter().find(|c| {
+                // both are compared, a wrong user name takes as long as a wrong password
+                secret_eq(c.username.as_deref(), username)
+                    & secret_eq(c.password.as_deref(), password)
+            })
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            self.credentials
+                .iter()
+                .find(|c| secret_eq(c.token.as_deref(), value))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("authentication required")]
+    Unauthorized,
+    #[error("{user} is not allowed to {permission:?} database {database}")]
+    Forbidden {
+        user: String,
+        database: String,
+        permission: Permission,
+    },
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let AuthError::Unauthorized = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"bailongma\""));
+        }
+        response.body(self.to_string())
+    }
+}
+
+impl AppState {
+    /// Check the request has a valid credential, `None` if no credentials file is
+    /// configured.
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Option<Credential>, AuthError> {
+        let config = self.config();
+        let credentials = match &config.credentials {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| credentials.authenticate(value))
+            .map(|credential| Some(credential.clone()))
+            .ok_or(AuthError::Unauthorized)
+    }
+
+    /// Check the request has a credential allowed to access the database, always passes
+    /// if no credentials file is configured.
+    pub fn authorize(
+        &self,
+        req: &HttpRequest,
+        database: &str,
+        permission: Permission,
+    ) -> Result<(), AuthError> {
+        let credential = match self.authenticate(req)? {
+            Some(credential) => credential,
+            None => return Ok(()),
+        };
+        if !credential.allows(database, permission) {
+            log::warn!(
+                "{} is not allowed to {:?} database {}",
+                credential.name(),
+                permission,
+                database
+            );
+            return Err(AuthError::Forbidden {
+                user: credential.name().to_string(),
+                database: database.to_string(),
+                permission,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_credentials() {
+    let credentials: Credentials = toml::from_str(
+        r#"
+        [[credentials]]
+        username = "prom"
+        password = "secret"
+        databases = ["prometheus"]
+        write = true
+
+        [[credentials]]
+        token = "t0ken"
+        databases = ["*"]
+        read = true
+        "#,
+    )
+    .unwrap();
+    let basic = format!("Basic {}", base64::encode("prom:secret"));
+    let writer = credentials.authenticate(&basic).unwrap();
+    assert!(writer.allows("prometheus", Permission::Write));
+    assert!(!writer.allows("prometheus", Permission::Read));
+    assert!(!writer.allows("other", Permission::Write));
+    assert!(credentials
+        .authenticate(&format!("Basic {}", base64::encode("prom:wrong")))
+        .is_none());
+    assert!(credentials
+        .authenticate(&format!("Basic {}", base64::encode("other:secret")))
+        .is_none());
+
+    let reader = credentials.authenticate("Bearer t0ken").unwrap();
+    assert!(reader.allows("other", Permission::Read));
+    assert!(!reader.allows("other", Permission::Write));
+    assert!(credentials.authenticate("Bearer nope").is_none());
+}
@@ -0,0 +1,862 @@
+//! Configuration from a TOML file, overridden by environment variables and command line
+//! flags, with hot reload of non-structural settings.
+//!
+//! ```toml
+//! [log]
+//! level = "info"
+//!
+//! [tdengine]
+//! connector = "rest"
+//! endpoints = ["td1:6030", "td2:6030", "td3:6030"]
+//!
+//! [tdengine.rest]
+//! url = "http://localhost:6041/rest/sqlt"
+//! max_connections = 100
+//!
+//! [auth]
+//! credentials_file = "/etc/bailongma/credentials.toml"
+//!
+//! [read]
+//! max_series = 100000
+//!
+//! [retry]
+//! max_retries = 10
+//! initial_backoff_ms = 100
+//!
+//! [opentsdb]
+//! telnet_listen = "0.0.0.0:4242"
+//!
+//! [graphite]
+//! listen = "0.0.0.0:2003"
+//! templates = ["servers.* .host.measurement.field*"]
+//!
+//! [statsd]
+//! listen = "0.0.0.0:8125"
+//! flush_interval = 10
+//!
+//! [scrape]
+//! interval = 15
+//!
+//! [[scrape.jobs]]
+//! name = "node"
+//! targets = ["localhost:9100"]
+//!
+//! [databases.prometheus]
+//! chunk_size = 300
+//! read_timeout = 30
+//! ```
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use bailongma::protocols::graphite::Templates;
+use bailongma::storage::{AdapterOptions, Connector, NativeOptions};
+use bailongma::ReadOptions;
+
+use crate::auth::{AuthError, Credentials};
+use crate::{AppState, Opts};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// One of off, error, warn, info, debug and trace.
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TDengineConfig {
+    /// One of native, rest and websocket.
+    pub connector: Connector,
+    pub host: String,
+    /// Port of the native connector.
+    pub port: u16,
+    /// Dnodes as `host[:port]` to fail over between, `host` and `port` if empty.
+    pub endpoints: Vec<String>,
+    pub user: String,
+    pub password: String,
+    /// Max connections of the native connector.
+    pub max_connections: u32,
+    pub rest: AdapterConfig,
+    pub websocket: AdapterConfig,
+}
+
+impl Default for TDengineConfig {
+    fn default() -> Self {
+        TDengineConfig {
+            connector: Connector::Native,
+            host: "localhost".to_string(),
+            port: 6030,
+            endpoints: Vec::new(),
+            user: "root".to_string(),
+            password: "taosdata".to_string(),
+            max_connections: 500,
+            rest: AdapterConfig::default(),
+            websocket: AdapterConfig::default(),
+        }
+    }
+}
+
+impl TDengineConfig {
+    /// Host and port of each endpoint.
+    pub fn endpoints(&self) -> Result<Vec<(String, u16)>> {
+        if self.endpoints.is_empty() {
+            return Ok(vec![(self.host.clone(), self.port)]);
+        }
+        self.endpoints
+            .iter()
+            .map(|endpoint| match endpoint.rsplit_once(':') {
+                Some((host, port)) => {
+                    let port = port
+                        .parse()
+                        .with_context(|| format!("invalid port of endpoint {}", endpoint))?;
+                    Ok((host.to_string(), port))
+                }
+                None => Ok((endpoint.clone(), self.port)),
+            })
+            .collect()
+    }
+
+    pub fn native_options(&self) -> Result<NativeOptions> {
+        Ok(NativeOptions {
+            endpoints: self.endpoints()?,
+            user: self.user.clone(),
+            password: self.password.clone(),
+            max_connections: self.max_connections,
+        })
+    }
+
+    /// Options of the REST or WebSocket connector, the URL defaults to taosAdapter on
+    /// each endpoint host.
+    pub fn adapter_options(&self, connector: Connector) -> Result<AdapterOptions> {
+        let (adapter, scheme, path) = match connector {
+            Connector::Websocket => (&self.websocket, "ws", "rest/ws"),
+            _ => (&self.rest, "http", "rest/sqlt"),
+        };
+        let urls = match &adapter.url {
+            Some(url) => vec![url.clone()],
+            None => self
+                .endpoints()?
+                .into_iter()
+                .map(|(host, _)| format!("{}://{}:6041/{}", scheme, host, path))
+                .collect(),
+        };
+        Ok(AdapterOptions {
+            urls,
+            user: self.user.clone(),
+            password: self.password.clone(),
+            token: adapter.token.clone(),
+            max_connections: adapter.max_connections,
+            timeout: Duration::from_secs(adapter.timeout),
+        })
+    }
+}
+
+/// `[tdengine.rest]` and `[tdengine.websocket]` sections.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdapterConfig {
+    /// taosAdapter endpoint, defaults to port 6041 of each TDengine endpoint.
+    pub url: Option<String>,
+    /// Token sent instead of user and password.
+    pub token: Option<String>,
+    /// Max concurrent requests, or WebSocket connections.
+    pub max_connections: u32,
+    /// Seconds a request may take, including connecting.
+    pub timeout: u64,
+}
+
+impl Default for AdapterConfig {
+    fn default() -> Self {
+        AdapterConfig {
+            url: None,
+            token: None,
+            max_connections: 100,
+            timeout: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub workers: usize,
+    /// Seconds to drain in-flight requests on shutdown.
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "0.0.0.0:10203".to_string(),
+            workers: 10,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteConfig {
+    /// Max values per insert statement.
+    pub chunk_size: usize,
+    /// Max process memory in GB.
+    pub max_memory: u64,
+    pub tag_type: String,
+}
+
+impl Default for WriteConfig {
+    fn default() -> Self {
+        WriteConfig {
+            chunk_size: 600,
+            max_memory: 50,
+            tag_type: "binary".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadConfig {
+    pub parallelism: usize,
+    /// Max series per query, 0 for unlimited.
+    pub max_series: usize,
+    /// Max samples per query, 0 for unlimited.
+    pub max_samples: usize,
+    /// Timeout in seconds, 0 for unlimited.
+    pub timeout: u64,
+}
+
+impl Default for ReadConfig {
+    fn default() -> Self {
+        ReadConfig {
+            parallelism: 8,
+            max_series: 100000,
+            max_samples: 50000000,
+            timeout: 120,
+        }
+    }
+}
+
+/// Retries of remote reads and writes on retryable errors.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Retries after the first attempt, 0 to fail right away.
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Growth of the backoff per retry.
+    pub multiplier: f64,
+    /// Fraction of each backoff randomly cut off, from 0 to 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 10,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 10000,
+            multiplier: 2.,
+            jitter: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, TLS is enabled if set.
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key, RSA or PKCS8.
+    pub key_file: Option<PathBuf>,
+    /// PEM CA certificates to verify clients with, client certificates are required if set.
+    pub client_ca_file: Option<PathBuf>,
+    /// Seconds between checks of certificate rotation, 0 to reload only on SIGHUP.
+    pub reload_interval: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert_file: None,
+            key_file: None,
+            client_ca_file: None,
+            reload_interval: 60,
+        }
+    }
+}
+
+/// OpenTSDB `/api/put` and telnet ingestion.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenTsdbConfig {
+    /// Database of telnet data points, and of `/api/put` without `db`.
+    pub database: String,
+    /// Address of the telnet listener, disabled if not set.
+    pub telnet_listen: Option<String>,
+}
+
+impl Default for OpenTsdbConfig {
+    fn default() -> Self {
+        OpenTsdbConfig {
+            database: "opentsdb".to_string(),
+            telnet_listen: None,
+        }
+    }
+}
+
+/// Graphite plaintext ingestion over TCP and UDP.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphiteConfig {
+    /// Address of the TCP and UDP listeners, disabled if not set.
+    pub listen: Option<String>,
+    pub database: String,
+    /// Templates splitting dotted paths into metric names and labels.
+    pub templates: Vec<String>,
+    /// Separator of the parts joined into a metric name.
+    pub separator: String,
+}
+
+impl Default for GraphiteConfig {
+    fn default() -> Self {
+        GraphiteConfig {
+            listen: None,
+            database: "graphite".to_string(),
+            templates: Vec::new(),
+            separator: "_".to_string(),
+        }
+    }
+}
+
+/// StatsD ingestion over UDP.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// Address of the UDP listener, disabled if not set.
+    pub listen: Option<String>,
+    pub database: String,
+    /// Seconds metrics are aggregated before they are written.
+    pub flush_interval: u64,
+    /// Quantiles of timers, histograms and distributions.
+    pub quantiles: Vec<f64>,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            listen: None,
+            database: "statsd".to_string(),
+            flush_interval: 10,
+            quantiles: vec![0.5, 0.9, 0.99],
+        }
+    }
+}
+
+/// A job of static scrape targets.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrapeJob {
+    /// `job` label of the scraped series.
+    pub name: String,
+    /// `host:port` of each target, its `instance` label.
+    pub targets: Vec<String>,
+    pub scheme: String,
+    pub metrics_path: String,
+    /// Seconds between scrapes, `[scrape] interval` if not set.
+    pub interval: Option<u64>,
+    /// Seconds before a scrape fails, `[scrape] timeout` if not set.
+    pub timeout: Option<u64>,
+    /// Extra labels of the scraped series.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Default for ScrapeJob {
+    fn default() -> Self {
+        ScrapeJob {
+            name: String::new(),
+            targets: Vec::new(),
+            scheme: "http".to_string(),
+            metrics_path: "/metrics".to_string(),
+            interval: None,
+            timeout: None,
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Scrapes of Prometheus exposition and OpenMetrics endpoints.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrapeConfig {
+    pub database: String,
+    /// Default seconds between scrapes.
+    pub interval: u64,
+    /// Default seconds before a scrape fails.
+    pub timeout: u64,
+    pub jobs: Vec<ScrapeJob>,
+}
+
+impl Default for ScrapeConfig {
+    fn default() -> Self {
+        ScrapeConfig {
+            database: "prometheus".to_string(),
+            interval: 60,
+            timeout: 10,
+            jobs: Vec::new(),
+        }
+    }
+}
+
+impl ScrapeConfig {
+    pub fn interval(&self, job: &ScrapeJob) -> Duration {
+        Duration::from_secs(job.interval.unwrap_or(self.interval))
+    }
+
+    pub fn timeout(&self, job: &ScrapeJob) -> Duration {
+        Duration::from_secs(job.timeout.unwrap_or(self.timeout))
+    }
+}
+
+/// OTLP/HTTP metrics ingestion.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// Database of `/v1/metrics` without `db`.
+    pub database: String,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            database: "otlp".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// TOML file of credentials, authentication is disabled if not set.
+    pub credentials_file: Option<PathBuf>,
+}
+
+/// Limits overridden in `[databases.<name>]` and `[tenants.<name>]` sections.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Overrides {
+    pub chunk_size: Option<usize>,
+    pub read_max_series: Option<usize>,
+    pub read_max_samples: Option<usize>,
+    pub read_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log: LogConfig,
+    pub tdengine: TDengineConfig,
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub write: WriteConfig,
+    pub read: ReadConfig,
+    pub retry: RetryConfig,
+    pub opentsdb: OpenTsdbConfig,
+    pub otlp: OtlpConfig,
+    pub graphite: GraphiteConfig,
+    pub statsd: StatsdConfig,
+    pub scrape: ScrapeConfig,
+    pub databases: BTreeMap<String, Overrides>,
+    /// Reserved for per-tenant limits.
+    pub tenants: BTreeMap<String, Overrides>,
+    /// Credentials loaded from `auth.credentials_file`.
+    #[serde(skip)]
+    pub credentials: Option<Arc<Credentials>>,
+}
+
+impl Config {
+    /// Load the config file if any, then apply environment and command line overrides.
+    pub fn load(opts: &Opts) -> Result<Self> {
+        let mut config = match &opts.config {
+            Some(path) => Self::from_file(path)?,
+            None => Config::default(),
+        };
+        opts.apply(&mut config);
+        config.validate()?;
+        if let Some(path) = &config.auth.credentials_file {
+            config.credentials = Some(Arc::new(Credentials::from_file(path)?));
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("parse config file {}", path.display()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.log_level()?;
+        anyhow::ensure!(
+            cfg!(feature = "native") || self.tdengine.connector != Connector::Native,
+            "native connector is not compiled in, use rest or websocket"
+        );
+        self.tdengine.endpoints()?;
+        anyhow::ensure!(
+            self.read.parallelism > 0,
+            "read parallelism must be positive"
+        );
+        anyhow::ensure!(
+            self.write.chunk_size > 0,
+            "write chunk size must be positive"
+        );
+        anyhow::ensure!(
+            self.tls.cert_file.is_some() == self.tls.key_file.is_some(),
+            "TLS certificate and key files must be set together"
+        );
+        anyhow::ensure!(
+            self.tls.client_ca_file.is_none() || self.tls.cert_file.is_some(),
+            "TLS client CA requires a server certificate"
+        );
+        anyhow::ensure!(
+            self.retry.multiplier >= 1.,
+            "retry multiplier must be at least 1"
+        );
+        anyhow::ensure!(
+            (0. ..=1.).contains(&self.retry.jitter),
+            "retry jitter must be between 0 and 1"
+        );
+        Templates::new(&self.graphite.templates, &self.graphite.separator)?;
+        anyhow::ensure!(
+            self.statsd.flush_interval > 0,
+            "StatsD flush interval must be positive"
+        );
+        anyhow::ensure!(
+            self.statsd.quantiles.iter().all(|q| (0. ..=1.).contains(q)),
+            "StatsD quantiles must be between 0 and 1"
+        );
+        for job in &self.scrape.jobs {
+            anyhow::ensure!(!job.name.is_empty(), "scrape job without a name");
+            let (interval, timeout) = (self.scrape.interval(job), self.scrape.timeout(job));
+            anyhow::ensure!(
+                interval > Duration::ZERO && timeout > Duration::ZERO && timeout <= interval,
+                "scrape timeout of {} must be positive and at most its interval",
+                job.name
+            );
+        }
+        for (name, overrides) in self.databases.iter().chain(&self.tenants) {
+            anyhow::ensure!(
+                overrides.chunk_size != Some(0),
+                "chunk size of {} must be positive",
+                name
+            );
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<log::LevelFilter> {
+        self.log
+            .level
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid log level {:?}", self.log.level))
+    }
+
+    /// Max process memory in KB, as sysinfo reports.
+    pub fn max_memory_kb(&self) -> u64 {
+        self.write.max_memory * 1024 * 1024
+    }
+
+    fn overrides(&self, database: &str) -> Option<&Overrides> {
+        self.databases.get(database)
+    }
+
+    pub fn chunk_size(&self, database: &str) -> usize {
+        self.overrides(database)
+            .and_then(|o| o.chunk_size)
+            .unwrap_or(self.write.chunk_size)
+    }
+
+    pub fn read_options(&self, database: &str) -> ReadOptions {
+        let overrides = self.overrides(database).cloned().unwrap_or_default();
+        let non_zero = |v: usize| Some(v).filter(|v| *v > 0);
+        ReadOptions {
+            parallelism: self.read.parallelism,
+            max_series: non_zero(overrides.read_max_series.unwrap_or(self.read.max_series)),
+            max_samples: non_zero(overrides.read_max_samples.unwrap_or(self.read.max_samples)),
+            timeout: Some(overrides.read_timeout.unwrap_or(self.read.timeout))
+                .filter(|v| *v > 0)
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Keep settings that only take effect at startup from `current`, returns names of
+    /// the sections whose changes are ignored.
+    fn keep_structural(&mut self, current: &Config) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        if self.tdengine != current.tdengine {
+            self.tdengine = current.tdengine.clone();
+            ignored.push("tdengine");
+        }
+        if self.server != current.server {
+            self.server = current.server.clone();
+            ignored.push("server");
+        }
+        if self.tls != current.tls {
+            self.tls = current.tls.clone();
+            ignored.push("tls");
+        }
+        if self.opentsdb != current.opentsdb {
+            self.opentsdb = current.opentsdb.clone();
+            ignored.push("opentsdb");
+        }
+        if self.graphite != current.graphite {
+            self.graphite = current.graphite.clone();
+            ignored.push("graphite");
+        }
+        if self.statsd != current.statsd {
+            self.statsd = current.statsd.clone();
+            ignored.push("statsd");
+        }
+        if self.scrape != current.scrape {
+            self.scrape = current.scrape.clone();
+            ignored.push("scrape");
+        }
+        ignored
+    }
+}
+
+impl Opts {
+    fn apply(&self, config: &mut Config) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        if let Some(level) = self.level {
+            config.log.level = level.to_string();
+        }
+        set(&mut config.tdengine.connector, &self.connector);
+        set(&mut config.tdengine.host, &self.host);
+        set(&mut config.tdengine.port, &self.port);
+        set(&mut config.tdengine.user, &self.user);
+        set(&mut config.tdengine.password, &self.password);
+        set(&mut config.tdengine.max_connections, &self.max_connections);
+        if !self.endpoints.is_empty() {
+            config.tdengine.endpoints = self.endpoints.clone();
+        }
+        if self.rest_url.is_some() {
+            config.tdengine.rest.url = self.rest_url.clone();
+        }
+        if self.rest_token.is_some() {
+            config.tdengine.rest.token = self.rest_token.clone();
+        }
+        if self.websocket_url.is_some() {
+            config.tdengine.websocket.url = self.websocket_url.clone();
+        }
+        if self.websocket_token.is_some() {
+            config.tdengine.websocket.token = self.websocket_token.clone();
+        }
+        set(&mut config.server.listen, &self.listen);
+        set(&mut config.server.workers, &self.workers);
+        set(&mut config.server.shutdown_timeout, &self.shutdown_timeout);
+        if self.tls_cert_file.is_some() {
+            config.tls.cert_file = self.tls_cert_file.clone();
+        }
+        if self.tls_key_file.is_some() {
+            config.tls.key_file = self.tls_key_file.clone();
+        }
+        if self.tls_client_ca_file.is_some() {
+            config.tls.client_ca_file = self.tls_client_ca_file.clone();
+        }
+        if self.credentials_file.is_some() {
+            config.auth.credentials_file = self.credentials_file.clone();
+        }
+        set(&mut config.write.chunk_size, &self.chunk_size);
+        set(&mut config.write.max_memory, &self.max_memory);
+        set(&mut config.write.tag_type, &self.tag_type);
+        set(&mut config.read.parallelism, &self.read_parallelism);
+        set(&mut config.read.max_series, &self.read_max_series);
+        set(&mut config.read.max_samples, &self.read_max_samples);
+        set(&mut config.read.timeout, &self.read_timeout);
+        set(&mut config.retry.max_retries, &self.max_retries);
+        set(
+            &mut config.retry.initial_backoff_ms,
+            &self.retry_initial_backoff_ms,
+        );
+        set(&mut config.retry.max_backoff_ms, &self.retry_max_backoff_ms);
+        if self.opentsdb_telnet_listen.is_some() {
+            config.opentsdb.telnet_listen = self.opentsdb_telnet_listen.clone();
+        }
+        if self.graphite_listen.is_some() {
+            config.graphite.listen = self.graphite_listen.clone();
+        }
+        if self.statsd_listen.is_some() {
+            config.statsd.listen = self.statsd_listen.clone();
+        }
+    }
+}
+
+impl AppState {
+    /// Current configuration.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Reload the config file and credentials, changes of TDengine connection and listener
+    /// are ignored.
+    pub fn reload(&self) -> Result<()> {
+        let current = self.config();
+        let mut config = Config::load(&self.opts)?;
+        for section in config.keep_structural(&current) {
+            log::warn!("changes in [{}] section require a restart", section);
+        }
+        if let Some(tls) = &self.tls {
+            tls.reload()?;
+        }
+        log::set_max_level(config.log_level()?);
+        *self.config.write().unwrap() = Arc::new(config);
+        log::info!("configuration reloaded");
+        Ok(())
+    }
+}
+
+/// Reload on SIGHUP.
+#[cfg(unix)]
+pub fn watch_signal(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    actix_web::rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                log::error!("listen to SIGHUP error: {}", err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading configuration");
+            if let Err(err) = state.reload() {
+                log::error!("reload configuration error: {:#}", err);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn watch_signal(_state: Arc<AppState>) {}
+
+/// Reload the configuration, any valid credential may if authentication is enabled.
+async fn reload(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AuthError> {
+    state.authenticate(&req)?;
+    Ok(match state.reload() {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:#}", err)),
+    })
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/-/reload").route(web::post().to(reload)));
+}
+
+#[test]
+fn test_config() {
+    let mut config: Config = toml::from_str(
+        r#"
+        [read]
+        max_series = 10
+
+        [databases.metrics]
+        chunk_size = 100
+        read_timeout = 0
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.tdengine, TDengineConfig::default());
+    assert_eq!(config.chunk_size("metrics"), 100);
+    assert_eq!(config.chunk_size("prometheus"), 600);
+    assert_eq!(config.read_options("metrics").timeout, None);
+    assert_eq!(config.read_options("metrics").max_series, Some(10));
+    assert_eq!(
+        config.read_options("prometheus").timeout,
+        Some(Duration::from_secs(120))
+    );
+    assert!(toml::from_str::<Config>("[read]\nmax_serie = 1").is_err());
+    let graphite: Config = toml::from_str("[graphite]\ntemplates = [\"a b c d\"]").unwrap();
+    assert!(graphite.validate().is_err());
+    let scrape: Config = toml::from_str(
+        r#"
+        [scrape]
+        interval = 15
+
+        [[scrape.jobs]]
+        name = "node"
+        targets = ["localhost:9100"]
+        timeout = 30
+        "#,
+    )
+    .unwrap();
+    assert_eq!(scrape.scrape.jobs[0].metrics_path, "/metrics");
+    assert!(scrape.validate().is_err());
+
+    let current = Config::default();
+    config.server.workers = 1;
+    config.log.level = "debug".to_string();
+    assert_eq!(config.keep_structural(&current), vec!["server"]);
+    assert_eq!(config.server, current.server);
+    assert_eq!(config.log.level, "debug");
+}
+
+#[test]
+fn test_connector_config() {
+    let config: Config = toml::from_str(
+        r#"
+        [tdengine]
+        connector = "websocket"
+        endpoints = ["td1", "td2:6031"]
+
+        [tdengine.rest]
+        url = "https://cloud.example.com/rest/sql"
+        token = "t0ken"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.tdengine.connector, Connector::Websocket);
+    assert_eq!(
+        config.tdengine.endpoints().unwrap(),
+        vec![("td1".to_string(), 6030), ("td2".to_string(), 6031)]
+    );
+    let websocket = config
+        .tdengine
+        .adapter_options(Connector::Websocket)
+        .unwrap();
+    assert_eq!(
+        websocket.urls,
+        vec!["ws://td1:6041/rest/ws", "ws://td2:6041/rest/ws"]
+    );
+    assert_eq!(websocket.token, None);
+    let rest = config.tdengine.adapter_options(Connector::Rest).unwrap();
+    assert_eq!(rest.urls, vec!["https://cloud.example.com/rest/sql"]);
+    assert_eq!(rest.token.as_deref(), Some("t0ken"));
+    assert_eq!(rest.max_connections, 100);
+    assert_eq!(rest.timeout, Duration::from_secs(60));
+    assert!(toml::from_str::<Config>("[tdengine]\nconnector = \"odbc\"").is_err());
+}
@@ -0,0 +1,60 @@
+//! Liveness and readiness probes at `/-/healthy` and `/-/ready`.
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+
+use crate::AppState;
+
+/// Max time to wait for a pooled connection in readiness checks.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn healthy() -> HttpResponse {
+    HttpResponse::Ok().body("Bailongma is Healthy.\n")
+}
+
+/// Check TDengine connectivity and backpressure, returns the reason if not ready.
+async fn check_ready(state: &AppState) -> Result<(), String> {
+    if state.shutdown.is_draining() {
+        return Err("shutting down".to_string());
+    }
+    state
+        .storage
+        .ping(READY_TIMEOUT)
+        .await
+        .map_err(|err| format!("TDengine is not serving: {}", err))?;
+
+    if let Some((memory, _)) = state.metrics.process_memory() {
+        let max_memory = state.config().max_memory_kb();
+        if memory > max_memory {
+            return Err(format!(
+                "backpressure engaged: memory {}KB exceeds limit {}KB",
+                memory, max_memory
+            ));
+        }
+    }
+    if let Some(pool) = state.storage.pool_state() {
+        if pool.idle_connections == 0 && pool.waiting > 0 {
+            return Err(format!(
+                "backpressure engaged: all {} TDengine connections are busy",
+                pool.connections
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn ready(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    match check_ready(&state).await {
+        Ok(()) => HttpResponse::Ok().body("Bailongma is Ready.\n"),
+        Err(reason) => {
+            log::warn!("not ready: {}", reason);
+            HttpResponse::ServiceUnavailable().body(format!("{}\n", reason))
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/-/healthy").route(web::get().to(healthy)))
+        .service(web::resource("/-/ready").route(web::get().to(ready)));
+}
@@ -0,0 +1,78 @@
+//! Graphite plaintext listeners, TCP and UDP on the same address.
+use std::sync::Arc;
+
+use bailongma::protocols::graphite::{self, Templates};
+
+use super::{Batch, Buffer, Reply, FLUSH_INTERVAL};
+use crate::AppState;
+
+/// Buffer a line, invalid lines are logged and skipped.
+fn push(buffer: &mut Buffer, templates: &Templates, line: &str) {
+    let now = chrono::Utc::now().timestamp_millis();
+    match graphite::parse_line(line, templates, now) {
+        Ok(Some(line)) => buffer
+            .set
+            .push(&line.name, line.labels, line.timestamp, line.value),
+        Ok(None) => {}
+        Err(err) => log::debug!("skip Graphite line: {}", err),
+    }
+}
+
+/// Lines of datagrams.
+struct Lines {
+    buffer: Buffer,
+    templates: Arc<Templates>,
+}
+
+impl Batch for Lines {
+    fn push(&mut self, payload: &str) {
+        self.buffer.bytes += payload.len();
+        for line in payload.lines() {
+            push(&mut self.buffer, &self.templates, line);
+        }
+    }
+
+    fn is_full(&self, state: &AppState) -> bool {
+        self.buffer.is_full(state)
+    }
+
+    fn take(&mut self) -> Buffer {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// Start the TCP and UDP listeners if configured.
+pub fn listen(state: &Arc<AppState>) -> anyhow::Result<()> {
+    let config = state.config();
+    let address = match &config.graphite.listen {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    let templates = Arc::new(Templates::new(
+        &config.graphite.templates,
+        &config.graphite.separator,
+    )?);
+
+    let socket = super::bind_udp("Graphite UDP", address)?;
+    let lines = Lines {
+        buffer: Buffer::default(),
+        templates: templates.clone(),
+    };
+    actix_web::rt::spawn(super::receive_udp(
+        state.clone(),
+        "Graphite",
+        socket,
+        config.graphite.database.clone(),
+        FLUSH_INTERVAL,
+        lines,
+    ));
+
+    super::serve_tcp(state, "Graphite TCP", address, move |state, stream| {
+        let templates = templates.clone();
+        let database = state.config().graphite.database.clone();
+        super::serve_lines(state, stream, database, move |buffer, line| {
+            push(buffer, &templates, line);
+            Reply::None
+        })
+    })
+}
@@ -0,0 +1,110 @@
+//! InfluxDB 1.x `/adapters/influxdb/write` and 2.x `/api/v2/write` endpoints.
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use serde_json::json;
+
+use bailongma::protocols::influxdb::{self, Precision};
+
+use crate::auth::Permission;
+use crate::AppState;
+
+#[derive(Debug, serde::Deserialize)]
+struct V1Options {
+    db: Option<String>,
+    precision: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct V2Options {
+    bucket: Option<String>,
+    precision: Option<String>,
+}
+
+/// Error bodies differ between the 1.x and 2.x APIs.
+#[derive(Debug, Clone, Copy)]
+enum Api {
+    V1,
+    V2,
+}
+
+impl Api {
+    fn error(self, status: StatusCode, message: impl Into<String>) -> HttpResponse {
+        let message = message.into();
+        let body = match self {
+            Api::V1 => json!({ "error": message }),
+            Api::V2 => json!({
+                "code": if status.is_client_error() { "invalid" } else { "internal error" },
+                "message": message,
+            }),
+        };
+        HttpResponse::build(status).json(body)
+    }
+}
+
+async fn write(
+    state: &AppState,
+    req: &HttpRequest,
+    api: Api,
+    database: Option<String>,
+    precision: Option<String>,
+    body: &[u8],
+) -> actix_web::Result<HttpResponse> {
+    let database = match database {
+        Some(database) if !database.is_empty() => database,
+        _ => return Ok(api.error(StatusCode::BAD_REQUEST, "database is required")),
+    };
+    state.authorize(req, &database, Permission::Write)?;
+    let precision = match precision.as_deref().map(str::parse).transpose() {
+        Ok(precision) => precision.unwrap_or(Precision::Nanoseconds),
+        Err(err) => return Ok(api.error(StatusCode::BAD_REQUEST, err.to_string())),
+    };
+    let lines = match std::str::from_utf8(body) {
+        Ok(lines) => lines,
+        Err(err) => return Ok(api.error(StatusCode::BAD_REQUEST, err.to_string())),
+    };
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let (set, errors) = influxdb::parse(lines, precision, now);
+    if let Err((status, message)) = super::write(state, &database, set, body.len()).await {
+        return Ok(api.error(status, message));
+    }
+    if let Some(err) = errors.first() {
+        // valid lines are written, as InfluxDB does
+        log::warn!("{} invalid lines of line protocol: {}", errors.len(), err);
+        let message = format!("partial write: {} ({} invalid lines)", err, errors.len());
+        return Ok(api.error(StatusCode::BAD_REQUEST, message));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn write_v1(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Query(options): web::Query<V1Options>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    write(&state, &req, Api::V1, options.db, options.precision, &body).await
+}
+
+async fn write_v2(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Query(options): web::Query<V2Options>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    write(
+        &state,
+        &req,
+        Api::V2,
+        options.bucket,
+        options.precision,
+        &body,
+    )
+    .await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/adapters/influxdb/write").route(web::post().to(write_v1)))
+        .service(web::resource("/api/v2/write").route(web::post().to(write_v2)));
+}
@@ -0,0 +1,298 @@
+//! Ingestion endpoints and listeners of protocols besides Prometheus remote write, their
+//! samples are converted into series and written like remote write requests.
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, web};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+use bailongma::protocols::SeriesSet;
+use bailongma::validation;
+
+use crate::AppState;
+
+mod graphite;
+mod influxdb;
+mod opentsdb;
+mod otlp;
+mod scrape;
+mod statsd;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    influxdb::configure(cfg);
+    opentsdb::configure(cfg);
+    otlp::configure(cfg);
+}
+
+/// Start the configured listeners and scrapes.
+pub fn listen(state: &Arc<AppState>) -> anyhow::Result<()> {
+    opentsdb::listen(state)?;
+    graphite::listen(state)?;
+    statsd::listen(state)?;
+    scrape::start(state)?;
+    Ok(())
+}
+
+/// Max time listeners buffer samples before they are written.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples received by a listener and not written yet.
+#[derive(Debug, Default)]
+struct Buffer {
+    set: SeriesSet,
+    /// Size of the payloads they were received in.
+    bytes: usize,
+}
+
+impl Buffer {
+    /// Whether the buffer should be written before it grows further.
+    fn is_full(&self, state: &AppState) -> bool {
+        self.set.len() >= state.config().write.chunk_size
+    }
+
+    async fn flush(&mut self, state: &AppState, database: &str) {
+        if self.set.is_empty() {
+            return;
+        }
+        let set = std::mem::take(&mut self.set);
+        let bytes = std::mem::take(&mut self.bytes);
+        if let Err((status, message)) = write(state, database, set, bytes).await {
+            log::error!("write buffered samples error ({}): {}", status, message);
+        }
+    }
+}
+
+/// Listen on a TCP address, serving each connection in a task until shutdown, when
+/// connections should flush and return.
+fn serve_tcp<F, S>(
+    state: &Arc<AppState>,
+    protocol: &'static str,
+    address: &str,
+    serve: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(Arc<AppState>, TcpStream) -> S + 'static,
+    S: Future<Output = std::io::Result<()>> + 'static,
+{
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    log::info!("{} listener, listen on {}", protocol, address);
+    let state = state.clone();
+    actix_web::rt::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = state.shutdown.draining() => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::error!("accept {} connection error: {}", protocol, err);
+                    continue;
+                }
+            };
+            let listening = state.shutdown.listening();
+            let connection = serve(state.clone(), stream);
+            actix_web::rt::spawn(async move {
+                if let Err(err) = connection.await {
+                    log::debug!("{} connection {} error: {}", protocol, peer, err);
+                }
+                drop(listening);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Max size of a UDP datagram.
+const MAX_DATAGRAM: usize = 65536;
+
+/// Bind a UDP socket to receive datagrams of a protocol.
+fn bind_udp(protocol: &str, address: &str) -> anyhow::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(address)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    log::info!("{} listener, listen on {}", protocol, address);
+    Ok(socket)
+}
+
+/// Samples received by a UDP listener, buffered or aggregated until taken to be written.
+trait Batch {
+    /// Add the lines of a datagram.
+    fn push(&mut self, payload: &str);
+
+    /// Whether the samples should be written before the next flush interval.
+    fn is_full(&self, _state: &AppState) -> bool {
+        false
+    }
+
+    /// Take the samples to write.
+    fn take(&mut self) -> Buffer;
+}
+
+/// Batches queued for a writer before new ones are dropped.
+const WRITER_BACKLOG: usize = 16;
+
+/// Writes the batches of a UDP listener in a task, so that receiving never waits for
+/// TDengine, datagrams would be dropped by the kernel meanwhile.
+struct Writer {
+    protocol: &'static str,
+    sender: mpsc::Sender<Buffer>,
+}
+
+impl Writer {
+    /// Spawn the task, it writes the queued batches and returns once the writer is
+    /// dropped, shutdown waits for it.
+    fn spawn(state: &Arc<AppState>, protocol: &'static str, database: String) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Buffer>(WRITER_BACKLOG);
+        let state = state.clone();
+        let listening = state.shutdown.listening();
+        actix_web::rt::spawn(async move {
+            while let Some(mut buffer) = receiver.recv().await {
+                buffer.flush(&state, &database).await;
+            }
+            drop(listening);
+        });
+        Writer { protocol, sender }
+    }
+
+    fn send(&self, buffer: Buffer) {
+        if buffer.set.is_empty() {
+            return;
+        }
+        if let Err(mpsc::error::TrySendError::Full(buffer)) = self.sender.try_send(buffer) {
+            log::warn!(
+                "{} writes are behind, drop {} series",
+                self.protocol,
+                buffer.set.len()
+            );
+        }
+    }
+}
+
+/// Receive datagrams until shutdown, adding them to a batch which is written every
+/// `interval`, when full, and on shutdown.
+async fn receive_udp<B: Batch>(
+    state: Arc<AppState>,
+    protocol: &'static str,
+    socket: UdpSocket,
+    database: String,
+    interval: Duration,
+    mut batch: B,
+) {
+    let _listening = state.shutdown.listening();
+    let writer = Writer::spawn(&state, protocol, database);
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        let len = tokio::select! {
+            received = socket.recv_from(&mut datagram) => match received {
+                Ok((len, _)) => len,
+                Err(err) => {
+                    log::error!("receive {} datagram error: {}", protocol, err);
+                    continue;
+                }
+            },
+            _ = interval.tick() => {
+                writer.send(batch.take());
+                continue;
+            }
+            _ = state.shutdown.draining() => break,
+        };
+        batch.push(&String::from_utf8_lossy(&datagram[..len]));
+        if batch.is_full(&state) {
+            writer.send(batch.take());
+        }
+    }
+    writer.send(batch.take());
+}
+
+/// What a TCP connection does after a line.
+enum Reply {
+    None,
+    Line(String),
+    Close,
+}
+
+/// Serve a line protocol connection until closed or shutdown, `handle` adds a line to
+/// the buffer, which is written every [FLUSH_INTERVAL], when full, and before returning.
+async fn serve_lines<F>(
+    state: Arc<AppState>,
+    stream: TcpStream,
+    database: String,
+    mut handle: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(&mut Buffer, &str) -> Reply,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut buffer = Buffer::default();
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = interval.tick() => {
+                buffer.flush(&state, &database).await;
+                continue;
+            }
+            _ = state.shutdown.draining() => break,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => break,
+        };
+        buffer.bytes += line.len() + 1;
+        match handle(&mut buffer, &line) {
+            Reply::None => {}
+            Reply::Line(reply) => writer.write_all(reply.as_bytes()).await?,
+            Reply::Close => break,
+        }
+        if buffer.is_full(&state) {
+            buffer.flush(&state, &database).await;
+        }
+    }
+    buffer.flush(&state, &database).await;
+    Ok(())
+}
+
+/// Validate and write converted series, `bytes` is the size of the payload they were
+/// received in. Invalid series are dropped, it fails if all of them are.
+async fn write(
+    state: &AppState,
+    database: &str,
+    set: SeriesSet,
+    bytes: usize,
+) -> Result<(), (StatusCode, String)> {
+    let (request, report) = validation::validate_write_request(set.into_write_request());
+    if report.rejected > 0 {
+        log::warn!(
+            "rejected {} of {} series: {:?}",
+            report.rejected,
+            report.accepted + report.rejected,
+            report.reasons
+        );
+        state.metrics.observe_rejections(&report);
+        if report.accepted == 0 {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "all {} series rejected: {:?}",
+                    report.rejected, report.reasons
+                ),
+            ));
+        }
+    }
+    let samples = request.timeseries.iter().map(|ts| ts.samples.len()).sum();
+    state
+        .metrics
+        .observe_write(database, bytes, request.timeseries.len(), samples);
+    if request.timeseries.is_empty() {
+        return Ok(());
+    }
+    crate::write_series(state, database, &request, None).await
+}
@@ -0,0 +1,118 @@
+//! OpenTSDB `/api/put` endpoint and telnet `put` listener.
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use serde_json::json;
+use tokio::net::TcpStream;
+
+use bailongma::protocols::opentsdb;
+use bailongma::protocols::SeriesSet;
+
+use super::{Buffer, Reply};
+use crate::auth::Permission;
+use crate::AppState;
+
+#[derive(Debug, serde::Deserialize)]
+struct PutOptions {
+    db: Option<String>,
+    /// Present to respond with the numbers of stored and failed data points.
+    summary: Option<String>,
+    /// Present to list the failed data points too.
+    details: Option<String>,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": {
+            "code": status.as_u16(),
+            "message": message.into(),
+        }
+    }))
+}
+
+async fn put(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Query(options): web::Query<PutOptions>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    let database = options
+        .db
+        .unwrap_or_else(|| state.config().opentsdb.database.clone());
+    state.authorize(&req, &database, Permission::Write)?;
+    let points = match opentsdb::parse_put(&body) {
+        Ok(points) => points,
+        Err(err) => return Ok(error(StatusCode::BAD_REQUEST, err.to_string())),
+    };
+    let total = points.len();
+    let mut set = SeriesSet::default();
+    let errors = opentsdb::convert(points, &mut set);
+    if let Err((status, message)) = super::write(&state, &database, set, body.len()).await {
+        return Ok(error(status, message));
+    }
+
+    let (success, failed) = (total - errors.len(), errors.len());
+    if !errors.is_empty() {
+        log::warn!(
+            "{} invalid OpenTSDB data points, first: {}",
+            failed,
+            errors[0].error
+        );
+    }
+    if options.details.is_some() {
+        Ok(HttpResponse::Ok().json(json!({
+            "success": success,
+            "failed": failed,
+            "errors": errors,
+        })))
+    } else if options.summary.is_some() {
+        Ok(HttpResponse::Ok().json(json!({
+            "success": success,
+            "failed": failed,
+        })))
+    } else if failed > 0 {
+        Ok(error(
+            StatusCode::BAD_REQUEST,
+            "One or more data points had errors, append \"details\" to the request to list them",
+        ))
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/api/put").route(web::post().to(put)));
+}
+
+/// Handle a telnet command.
+fn command(buffer: &mut Buffer, line: &str) -> Reply {
+    let line = line.trim();
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let reply = match command {
+        "" => None,
+        "put" => match opentsdb::parse_telnet_put(args) {
+            Ok(point) => opentsdb::convert(vec![point], &mut buffer.set)
+                .into_iter()
+                .next()
+                .map(|err| format!("put: {}\n", err.error)),
+            Err(err) => Some(format!("put: {}\n", err)),
+        },
+        "version" => Some(format!("bailongma {}\n", env!("CARGO_PKG_VERSION"))),
+        "exit" => return Reply::Close,
+        _ => Some(format!("unknown command: {}\n", command)),
+    };
+    reply.map_or(Reply::None, Reply::Line)
+}
+
+async fn serve(state: Arc<AppState>, stream: TcpStream) -> std::io::Result<()> {
+    let database = state.config().opentsdb.database.clone();
+    super::serve_lines(state, stream, database, command).await
+}
+
+/// Start the telnet listener if configured.
+pub fn listen(state: &Arc<AppState>) -> anyhow::Result<()> {
+    match &state.config().opentsdb.telnet_listen {
+        Some(address) => super::serve_tcp(state, "OpenTSDB telnet", address, serve),
+        None => Ok(()),
+    }
+}
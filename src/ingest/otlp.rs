@@ -0,0 +1,73 @@
+//! OTLP/HTTP metrics receiver at `/v1/metrics`, protobuf or JSON encoded.
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse};
+use prost::Message;
+
+use bailongma::protocols::otlp::{self, ExportMetricsServiceRequest, Status};
+
+use crate::auth::Permission;
+use crate::AppState;
+
+#[derive(Debug, serde::Deserialize)]
+struct MetricsOptions {
+    db: Option<String>,
+}
+
+/// Respond in the encoding of the request.
+fn respond(json: bool, status: StatusCode, message: Option<String>) -> HttpResponse {
+    let mut response = HttpResponse::build(status);
+    match (json, message) {
+        (true, None) => response.content_type("application/json").body("{}"),
+        (false, None) => response
+            .content_type("application/x-protobuf")
+            .body(otlp::ExportMetricsServiceResponse::default().encode_to_vec()),
+        (json, Some(message)) => {
+            let status = Status {
+                // gRPC INVALID_ARGUMENT or UNAVAILABLE
+                code: if status.is_client_error() { 3 } else { 14 },
+                message,
+            };
+            if json {
+                response.json(status)
+            } else {
+                response
+                    .content_type("application/x-protobuf")
+                    .body(status.encode_to_vec())
+            }
+        }
+    }
+}
+
+async fn metrics(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Query(options): web::Query<MetricsOptions>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    let database = options
+        .db
+        .unwrap_or_else(|| state.config().otlp.database.clone());
+    state.authorize(&req, &database, Permission::Write)?;
+    let json = req.content_type() == "application/json";
+    let request = if json {
+        serde_json::from_slice::<ExportMetricsServiceRequest>(&body).map_err(|err| err.to_string())
+    } else {
+        ExportMetricsServiceRequest::decode(body.as_ref()).map_err(|err| err.to_string())
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => return Ok(respond(json, StatusCode::BAD_REQUEST, Some(err))),
+    };
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let set = otlp::convert(&request, &state.delta_totals, now);
+    match super::write(&state, &database, set, body.len()).await {
+        Ok(()) => Ok(respond(json, StatusCode::OK, None)),
+        Err((status, message)) => Ok(respond(json, status, Some(message))),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/v1/metrics").route(web::post().to(metrics)));
+}
@@ -0,0 +1,107 @@
+//! Scrapes of static targets, each scraped in its own task every interval.
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+
+use bailongma::exposition::{self, Format};
+use bailongma::protocols::scrape::Target;
+
+use crate::config::ScrapeJob;
+use crate::AppState;
+
+/// OpenMetrics preferred over text exposition, as Prometheus asks.
+const ACCEPT_HEADER: &str =
+    "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+/// Fetch and parse a target, the size of the body is returned along with the samples.
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    timeout: Duration,
+) -> anyhow::Result<(Vec<exposition::ParsedSample>, usize)> {
+    let response = client
+        .get(url)
+        .header(ACCEPT, ACCEPT_HEADER)
+        .header(
+            "X-Prometheus-Scrape-Timeout-Seconds",
+            timeout.as_secs_f64().to_string(),
+        )
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let format = Format::from_content_type(
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let body = response.text().await?;
+    Ok((exposition::parse(&body, format)?, body.len()))
+}
+
+async fn scrape(state: Arc<AppState>, client: reqwest::Client, job: ScrapeJob, instance: String) {
+    let _listening = state.shutdown.listening();
+    let config = state.config();
+    let database = config.scrape.database.clone();
+    let timeout = config.scrape.timeout(&job);
+    let mut interval = tokio::time::interval(config.scrape.interval(&job));
+    let url = format!("{}://{}{}", job.scheme, instance, job.metrics_path);
+    let labels = job
+        .labels
+        .into_iter()
+        .chain(vec![
+            ("job".to_string(), job.name),
+            ("instance".to_string(), instance),
+        ])
+        .collect();
+    let mut target = Target::new(labels);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.draining() => break,
+        }
+        let start = Instant::now();
+        let now = chrono::Utc::now().timestamp_millis();
+        let (samples, bytes) = match fetch(&client, &url, timeout).await {
+            Ok((samples, bytes)) => (Some(samples), bytes),
+            Err(err) => {
+                log::warn!("scrape {} error: {:#}", url, err);
+                (None, 0)
+            }
+        };
+        let set = target.report(samples, start.elapsed(), now);
+        if let Err((status, message)) = super::write(&state, &database, set, bytes).await {
+            log::error!(
+                "write scraped samples of {} error ({}): {}",
+                url,
+                status,
+                message
+            );
+        }
+    }
+}
+
+/// Start scraping the configured targets.
+pub fn start(state: &Arc<AppState>) -> anyhow::Result<()> {
+    let config = state.config();
+    let client = reqwest::Client::builder().build()?;
+    for job in &config.scrape.jobs {
+        for instance in &job.targets {
+            log::info!(
+                "scrape {} target {} every {}s",
+                job.name,
+                instance,
+                config.scrape.interval(job).as_secs()
+            );
+            actix_web::rt::spawn(scrape(
+                state.clone(),
+                client.clone(),
+                job.clone(),
+                instance.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
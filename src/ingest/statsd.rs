@@ -0,0 +1,59 @@
+//! StatsD UDP listener, metrics are aggregated and written every flush interval.
+use std::sync::Arc;
+use std::time::Duration;
+
+use bailongma::protocols::statsd::{self, Aggregator};
+
+use super::{Batch, Buffer};
+use crate::AppState;
+
+/// Metrics of datagrams, aggregated until the flush interval.
+struct Aggregates {
+    aggregator: Aggregator,
+    bytes: usize,
+}
+
+impl Batch for Aggregates {
+    fn push(&mut self, payload: &str) {
+        self.bytes += payload.len();
+        for line in payload.lines() {
+            let added = match statsd::parse_line(line) {
+                Ok(Some(metric)) => self.aggregator.add(metric),
+                Ok(None) => Ok(()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = added {
+                log::debug!("skip StatsD line {:?}: {}", line, err);
+            }
+        }
+    }
+
+    /// The metrics updated since the last flush.
+    fn take(&mut self) -> Buffer {
+        Buffer {
+            set: self.aggregator.flush(chrono::Utc::now().timestamp_millis()),
+            bytes: std::mem::take(&mut self.bytes),
+        }
+    }
+}
+
+/// Start the UDP listener if configured.
+pub fn listen(state: &Arc<AppState>) -> anyhow::Result<()> {
+    if let Some(address) = &state.config().statsd.listen {
+        let config = state.config();
+        let socket = super::bind_udp("StatsD UDP", address)?;
+        let aggregates = Aggregates {
+            aggregator: Aggregator::new(&config.statsd.quantiles),
+            bytes: 0,
+        };
+        actix_web::rt::spawn(super::receive_udp(
+            state.clone(),
+            "StatsD",
+            socket,
+            config.statsd.database.clone(),
+            Duration::from_secs(config.statsd.flush_interval),
+            aggregates,
+        ));
+    }
+    Ok(())
+}
@@ -5,7 +5,10 @@ use log::{debug, error, info, warn};
 use thiserror::Error;
 
 mod prometheus;
+pub mod promql;
+pub mod protocols;
 mod protos;
+pub mod storage;
 mod utils;
 
 #[cfg(feature = "protoc")]
@@ -13,7 +16,7 @@ pub use protos::*;
 
 pub use prometheus::*;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "native"))]
 mod test;
 
 #[derive(Debug, Error)]
@@ -1,15 +1,17 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::BTreeMap,
     ops::Deref,
-    sync::{Arc, Mutex},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
     time::Duration,
 };
 
 use actix_web::{
+    http::StatusCode,
     middleware::Logger,
     post,
     web::{self, Bytes},
-    App, HttpResponse, HttpServer, Result as WebResult,
+    App, HttpRequest, HttpResponse, HttpServer, Result as WebResult,
 };
 use anyhow::Result;
 use clap::Parser;
@@ -18,14 +20,26 @@ use dashmap::{DashMap, DashSet};
 use log::*;
 use prost::Message;
 
-use libtaos::{self as taos, Taos, TaosCfgBuilder, TaosCode};
-
 // pub mod protos;
+mod api;
+mod auth;
+mod config;
+mod health;
+mod ingest;
+mod metrics;
+mod retry;
+mod shutdown;
+mod tls;
 pub mod utils;
 
+use bailongma::read_request::ResponseType;
+use bailongma::storage::{
+    Connector, ErrorKind, Memory, Row, Storage, StorageError, TDengine, STALE_COLUMN,
+};
 use bailongma::*;
-use taos::TaosError;
-use utils::md5sum;
+use config::Config;
+use metrics::timed;
+use utils::{md5sum, tag_name_escape};
 
 //use protos::WriteRequest;
 fn table_name_escape(name: &str) -> String {
@@ -41,122 +55,91 @@ fn table_name_escape(name: &str) -> String {
         s
     }
 }
-fn tag_name_escape(name: &str) -> String {
-    name.replace(":", "_")
-        .replace(".", "_")
-        .replace("-", "_")
-        .to_lowercase()
-}
-
-fn tag_value_escape(value: &str) -> String {
-    value.replace("\"", "\\\"")
-}
 
 async fn handle_stable_schema<'prom>(
-    _state: &AppState,
-    taos: &Taos,
+    state: &AppState,
     database: &str,
     timeseries: &'prom TimeSeries,
 ) -> Result<()> {
     use itertools::Itertools;
     debug!("handle stable start");
-    let (name, labels): (_, Vec<_>) = timeseries
+    let storage = state.storage.as_ref();
+    let (name, labels): (Vec<_>, Vec<_>) = timeseries
         .labels
         .iter()
         .partition(|label| label.name == "__name__");
 
-    // label __name__ should exist.
-    assert!(name.len() == 1);
-
-    // get metrics name
-    let metrics_name = &name[0].value;
+    // get metrics name, validated to be the only __name__ label
+    let metrics_name = match name.as_slice() {
+        [name] => &name.value,
+        _ => anyhow::bail!("series without a single __name__ label"),
+    };
     let stable_name = table_name_escape(metrics_name);
+    let tags = labels
+        .iter()
+        .map(|label| format!("t_{}", tag_name_escape(&label.name)))
+        .collect_vec();
 
-    let mut schema = taos
-        .describe(&format!("{}.{}", database, &stable_name))
-        .await;
-    trace!("schema: {:?}", &schema);
-    //let _ = state.create_table_lock.lock().unwrap();
-    if let Err(taos::Error::RawTaosError(taos::TaosError { code, ref err })) = schema {
-        //let _ = state.create_table_lock.lock().unwrap();
-        match code {
-            taos::TaosCode::MndInvalidTableName => {
-                // create super table
-                let sql = format!(
-                    "create stable if not exists {}.{} (ts timestamp, value double) tags (taghash binary({}), {})",
-                    database,
-                    stable_name,
-                    34, // taghash length
-                    labels
-                        .iter()
-                        .map(|label| { format!("t_{} binary({})", tag_name_escape(&label.name), 128) })  // TODO: default binary length is 128 
-                        .join(", ")
-                );
-                trace!("exec sql: {}", &sql);
-                taos.exec(&sql).await?;
-                schema = taos
-                    .describe(&format!("{}.{}", database, &stable_name))
-                    .await;
-            }
-            taos::TaosCode::MndDbNotSelected => {
-                // create database
-                let sql = format!("create database if not exists {}", database);
-                dbg!(taos.exec(&sql).await?);
-                // create super table
-                let sql = format!(
-                    "create stable if not exists {}.{} (ts timestamp, value double) tags (taghash binary({}), {})",
-                    database,
-                    stable_name,
-                    34, // taghash length
-                    labels
-                        .iter()
-                        .map(|label| { format!("t_{} binary({})", tag_name_escape(&label.name), 128) })  // TODO: default binary length is 128 
-                        .join(", ")
-                );
-                trace!("exec sql: {}", &sql);
-                taos.exec(&sql).await?;
-                schema = taos
-                    .describe(&format!("{}.{}", database, &stable_name))
-                    .await;
-            }
-            _ => {
-                error!("error: TaosError {{ code: {}, err: {} }}", code, err);
-                return Err(taos::Error::RawTaosError(TaosError {
-                    code,
-                    err: err.clone(),
-                })
-                .into());
-            }
+    let schema = match storage.describe(database, &stable_name).await {
+        Ok(Some(schema)) => schema,
+        Ok(None) => {
+            // create super table
+            timed(
+                &state.metrics.ddl_duration,
+                storage.create_stable(database, &stable_name, &tags),
+            )
+            .await?;
+            storage
+                .describe(database, &stable_name)
+                .await?
+                .ok_or_else(|| {
+                    anyhow::anyhow!("super table {}.{} not created", database, stable_name)
+                })?
         }
-    }
+        Err(StorageError::DatabaseNotFound(_)) => {
+            // create database and super table
+            timed(
+                &state.metrics.ddl_duration,
+                storage.create_database(database),
+            )
+            .await?;
+            timed(
+                &state.metrics.ddl_duration,
+                storage.create_stable(database, &stable_name, &tags),
+            )
+            .await?;
+            storage
+                .describe(database, &stable_name)
+                .await?
+                .ok_or_else(|| {
+                    anyhow::anyhow!("super table {}.{} not created", database, stable_name)
+                })?
+        }
+        Err(err) => {
+            error!("describe {}.{} error: {}", database, stable_name, err);
+            return Err(err.into());
+        }
+    };
+    trace!("schema: {:?}", &schema);
 
-    let schema = schema.unwrap();
-    use std::iter::FromIterator;
-    let fields = BTreeSet::from_iter(schema.names().into_iter());
+    if !schema.columns.iter().any(|column| column == STALE_COLUMN) {
+        trace!("add stale column for stable {}", stable_name);
+        timed(
+            &state.metrics.ddl_duration,
+            storage.add_stale_column(database, &stable_name),
+        )
+        .await?;
+    }
 
     let mut tagmap = BTreeMap::new();
-    for label in &labels {
-        if !fields.contains(&format!("t_{}", tag_name_escape(&label.name))) {
-            let sql = format!(
-                "alter stable {}.{} add tag t_{} binary({})",
-                database,
-                stable_name,
-                tag_name_escape(&label.name),
-                128
-            );
-            trace!("add tag {} for stable {}: {}", label.name, stable_name, sql);
-            let res = taos.exec(&sql).await;
-            if let Err(err) = res {
-                match &err {
-                    taos::Error::RawTaosError(TaosError { code, err }) => match code {
-                        TaosCode::MndFieldAlreayExist => {}
-                        _ => {
-                            anyhow::bail!(err.clone());
-                        }
-                    },
-                    _ => {}
-                }
-            }
+    for (label, tag) in labels.iter().zip(&tags) {
+        if !schema.tags.contains(tag) {
+            trace!("add tag {} for stable {}", label.name, stable_name);
+            timed(
+                &state.metrics.ddl_duration,
+                storage.add_tag(database, &stable_name, tag),
+            )
+            .await?;
         }
         tagmap.insert(&label.name, &label.value);
     }
@@ -167,64 +150,36 @@ async fn handle_stable_schema<'prom>(
     let taghash = md5sum(tagmap.values().join("").as_bytes());
 
     // create sub table;
-    // FIXME: It's better to keep a table exist set.
-    let sql = format!(
-        "create table if not exists {}.{} using {}.{} (taghash,{}) tags(\"{}\",{})",
-        database,
-        table_name,
-        database,
-        stable_name,
-        tagmap
-            .keys()
-            .map(|v| format!("t_{}", tag_name_escape(&v)))
-            .join(","),
-        taghash,
-        tagmap
-            .values()
-            .map(|value| {
-                if value.len() < 127 {
-                    format!("\"{}\"", tag_value_escape(value))
-                } else {
-                    format!("\"{}\"", tag_value_escape(&value[0..127]))
-                }
-            })
-            .join(",")
-    );
+    let table_tags = std::iter::once(("taghash".to_string(), taghash))
+        .chain(
+            tagmap
+                .iter()
+                .map(|(name, value)| (format!("t_{}", tag_name_escape(name)), value.to_string())),
+        )
+        .collect_vec();
     debug!("created table {}.{}", database, table_name);
-    trace!("create table with sql: {}", sql);
-    if let Err(taos::Error::RawTaosError(TaosError { code, err })) = taos.exec(&sql).await {
-        if err.contains("tag value too long") {
-            error!("tag value too long: {}", sql);
-        } else {
-            anyhow::bail!(err.clone());
+    match timed(
+        &state.metrics.ddl_duration,
+        storage.create_table(database, &table_name, &stable_name, &table_tags),
+    )
+    .await
+    {
+        Err(StorageError::TagValueTooLong) => {
+            error!("tag value too long: {}.{}", database, table_name);
         }
+        res => res?,
     }
     debug!("handle stable done");
     Ok(())
 }
-async fn handle_table_schema(
+async fn handle_table_schema<'prom>(
     state: &AppState,
-    taos: &Taos,
     database: &str,
-    req: &WriteRequest,
+    timeseries: impl IntoIterator<Item = &'prom TimeSeries>,
 ) -> Result<()> {
-    use futures::stream::{iter, StreamExt};
-    let stream = iter(req.timeseries.iter());
-    let res = stream
-        .then(|ts| async move { handle_stable_schema(state, taos, database, ts).await })
-        .collect::<Vec<_>>()
-        .await;
-    for i in res {
-        let _ = i?;
+    for ts in timeseries {
+        handle_stable_schema(state, database, ts).await?;
     }
-    // let stream = iter(req.timeseries.iter().map(|ts| {
-    // handle_stable_schema(state, taos, database, ts)
-    // }));
-    // let vec: Vec<_> = TryStreamExt::try_collect(stream).await;
-    // for ts in &req.timeseries {
-    //     // handle stable
-    //     handle_stable_schema(state, taos, database, ts).await?;
-    // }
     debug!("handle table schema done");
     Ok(())
 }
@@ -235,56 +190,89 @@ async fn write_tdengine_from_prometheus(
 ) -> Result<()> {
     use itertools::Itertools;
     debug!("Write tdengine from prometheus write request");
-    let taos = state.pool.get()?;
-    let taos = taos.deref();
-    // build insert sql
-    let chunks = req
+    let storage = state.storage.as_ref();
+    let tables = req
         .timeseries
         .iter()
         .map(|ts| {
-            let (name, labels): (_, Vec<_>) =
+            let (name, labels): (Vec<_>, Vec<_>) =
                 ts.labels.iter().partition(|label| label.name == "__name__");
-            // label __name__ should exist.
-            assert!(name.len() == 1);
-
-            // get metrics name
-            let metrics_name = &name[0].value;
+            // get metrics name, validated to be the only __name__ label
+            let metrics_name = match name.as_slice() {
+                [name] => &name.value,
+                _ => anyhow::bail!("series without a single __name__ label"),
+            };
             let tag_values = labels.iter().map(|label| &label.value).join("");
             let table_name = format!("{}{}", metrics_name, tag_values);
-            let table_name = format!("{}.md5_{}", database, md5sum(table_name.as_bytes()));
-            ts.samples.iter().map(move |sample| match sample.value {
-                Some(value) if value.is_nan() => {
-                    format!(" {} values ({}, NULL)", table_name, sample.timestamp)
-                }
-                None => format!(" {} values ({}, NULL)", table_name, sample.timestamp),
-                Some(value) => format!(" {} values ({}, {})", table_name, sample.timestamp, value),
+            Ok(format!("md5_{}", md5sum(table_name.as_bytes())))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let cached = tables
+        .iter()
+        .filter(|table| state.tables.exist(database, table))
+        .count();
+    state.metrics.schema_cache_hits.inc_by(cached as u64);
+    state
+        .metrics
+        .schema_cache_misses
+        .inc_by((tables.len() - cached) as u64);
+
+    // build insert rows of series indexes
+    let chunks = req
+        .timeseries
+        .iter()
+        .zip(&tables)
+        .enumerate()
+        .map(|(index, (ts, table))| {
+            ts.samples.iter().map(move |sample| {
+                let row = Row {
+                    table,
+                    timestamp: sample.timestamp,
+                    value: sample.value,
+                };
+                (index, row)
             })
         })
         .flatten()
-        .chunks(state.opts.chunk_size)
+        .chunks(state.config().chunk_size(database))
         .into_iter()
-        .map(|mut chunk| chunk.join(""))
+        .map(|chunk| chunk.collect_vec())
         .collect_vec();
 
     for chunk in chunks {
-        let sql = format!("insert into {}", chunk);
-        debug!("chunk sql length is {}", sql.len());
-
-        if let Err(err) = taos.query(&sql).await {
-            match err {
-                taos::Error::RawTaosError(err) => match err.code {
-                    TaosCode::MndDbNotSelected | TaosCode::MndInvalidTableName => {
-                        handle_table_schema(&state, taos, database, &req).await?;
-                        taos.query(&sql).await?;
-                    }
-                    code => {
-                        warn!("insert into tdengine error: [{}]{}", code, err);
-                    }
-                },
-                err => {
-                    error!("error with query [{}]: {}", sql.len(), err);
+        let (series, chunk): (Vec<_>, Vec<_>) = chunk.into_iter().unzip();
+        match timed(
+            &state.metrics.insert_duration,
+            storage.insert(database, &chunk),
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(StorageError::DatabaseNotFound(_)) | Err(StorageError::TableNotFound(_)) => {
+                let cached = |index: &usize| state.tables.exist(database, &tables[*index]);
+                if series.iter().all(cached) {
+                    // cached tables were dropped since
+                    state.tables.forget(database);
                 }
+                // series of NaN values too, their super tables may lack the stale column
+                let uncached = series
+                    .iter()
+                    .zip(&chunk)
+                    .filter(|(index, row)| !cached(index) || row.value.map_or(false, f64::is_nan))
+                    .map(|(index, _)| index)
+                    .dedup()
+                    .map(|index| &req.timeseries[*index]);
+                handle_table_schema(state, database, uncached).await?;
+                timed(
+                    &state.metrics.insert_duration,
+                    storage.insert(database, &chunk),
+                )
+                .await?;
             }
+            Err(err) => return Err(err.into()),
+        }
+        for index in series.iter().dedup() {
+            state.tables.add_table(database, &tables[*index]);
         }
     }
 
@@ -294,6 +282,7 @@ async fn write_tdengine_from_prometheus(
 #[post("/adapters/prometheus/write")]
 async fn prometheus(
     state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
     web::Query(options): web::Query<PrometheusOptions>,
     bytes: Bytes,
 ) -> WebResult<HttpResponse> {
@@ -302,49 +291,131 @@ async fn prometheus(
     info!("recieved {} bytes from prometheus", bytes.len());
     let database = options.database;
     let database = database.unwrap_or("prometheus".to_string());
+    state.authorize(&req, &database, auth::Permission::Write)?;
 
     let mut decoder = snap::raw::Decoder::new();
     let decompressed = decoder
         .decompress_vec(bytes)
         .map_err(|_| actix_web::error::ErrorNotAcceptable("bad snappy stream"))?;
 
-    let write_request = WriteRequest::decode(&mut decompressed.as_ref()).map_err(|prost_err| {
-        // decompressed.len();
-        let err = "bad prometheus write request: deserializing error";
-        error!(
-            "{}, protolens: {}, raw error: {:?}",
-            err,
-            decompressed.len(),
-            prost_err
-        );
-        actix_web::error::ErrorNotAcceptable(err)
-    })?;
+    let (write_request, report) =
+        validation::decode_write_request(&decompressed).map_err(|prost_err| {
+            // decompressed.len();
+            let err = "bad prometheus write request: deserializing error";
+            error!(
+                "{}, protolens: {}, raw error: {:?}",
+                err,
+                decompressed.len(),
+                prost_err
+            );
+            actix_web::error::ErrorNotAcceptable(err)
+        })?;
     drop(decompressed); // drop decompressed data, it'll not be used after
+    state.metrics.observe_write(
+        &database,
+        bytes.len(),
+        write_request.timeseries.len(),
+        write_request
+            .timeseries
+            .iter()
+            .map(|ts| ts.samples.len())
+            .sum(),
+    );
+
+    if report.rejected > 0 {
+        warn!(
+            "rejected {} of {} series: {:?}",
+            report.rejected,
+            report.accepted + report.rejected,
+            report.reasons
+        );
+        state.metrics.observe_rejections(&report);
+        if report.accepted == 0 {
+            return Ok(HttpResponse::BadRequest().json(&report));
+        }
+    }
 
     // std::fs::write(format!("prom-failed-{}.json", md5sum(bytes)), serde_json::to_string(&write_request).unwrap())?;
 
-    // write tdengine, retry max 10 times if error.
-    for _i in 0..10i32 {
-        let res = write_tdengine_from_prometheus(&state, &database, &write_request).await;
-        if let Err(err) = res {
-            warn!(
-                "write tdengine error : {}\nbacktraces: {}",
-                // i,
-                err,
-                err.backtrace()
-            );
-            tokio::time::sleep(Duration::from_millis(100)).await;
-        } else {
-            return Ok(HttpResponse::Ok().finish());
-        }
+    match write_series(&state, &database, &write_request, Some(bytes)).await {
+        Ok(()) if report.rejected > 0 => Ok(HttpResponse::Ok().json(&report)),
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err((status, message)) => Ok(HttpResponse::build(status).body(message)),
     }
+}
 
-    // if not success, write data and save it to persistent storage.
-    error!("failed with retries, the data will be lost");
-    std::fs::write(format!("prom-failed-write-{}.snappy", md5sum(bytes)), bytes)?;
-    // });
-    // body is loaded, now we can deserialize
-    Ok(HttpResponse::InternalServerError().finish())
+/// Write series to TDengine, retrying retryable errors with backoff until the shutdown
+/// deadline. Series that could not be written but may be later are spooled, as the
+/// snappy payload if given. Returns the status and message of a failed write.
+async fn write_series(
+    state: &AppState,
+    database: &str,
+    write_request: &WriteRequest,
+    payload: Option<&[u8]>,
+) -> std::result::Result<(), (StatusCode, String)> {
+    let mut backoff = state.config().retry.backoff();
+    let written = state
+        .shutdown
+        .until_deadline(async {
+            loop {
+                let err = match write_tdengine_from_prometheus(state, database, write_request).await
+                {
+                    Ok(()) => return Ok(()),
+                    Err(err) => err,
+                };
+                let kind = retry::write_error_kind(&err);
+                if kind == ErrorKind::BadData {
+                    return Err((kind, err));
+                }
+                match backoff.next() {
+                    Some(delay) => {
+                        warn!("write tdengine error, retry in {:?}: {:#}", delay, err);
+                        state.metrics.write_retries.inc();
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => return Err((kind, err)),
+                }
+            }
+        })
+        .await;
+    let (status, message) = match written {
+        Some(Ok(())) => return Ok(()),
+        Some(Err((ErrorKind::BadData, err))) => {
+            // retrying will not help, let the sender drop the samples
+            warn!("write tdengine rejected the data: {:#}", err);
+            state.metrics.write_failures.inc();
+            return Err((StatusCode::BAD_REQUEST, err.to_string()));
+        }
+        Some(Err((kind, err))) => {
+            error!("write tdengine failed with retries: {:#}", err);
+            state.metrics.write_failures.inc();
+            (retry::write_status(kind), err.to_string())
+        }
+        None => {
+            warn!("shutdown deadline reached while writing, spool the data");
+            (StatusCode::SERVICE_UNAVAILABLE, "shutting down".to_string())
+        }
+    };
+
+    // if not success, write data and save it to persistent storage, Prometheus will
+    // also send it again on 5xx.
+    let encoded;
+    let payload = match payload {
+        Some(payload) => payload,
+        None => {
+            encoded = snap::raw::Encoder::new()
+                .compress_vec(&write_request.encode_to_vec())
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            &encoded
+        }
+    };
+    let spooled = format!("prom-failed-write-{}.snappy", md5sum(payload));
+    if let Err(err) = std::fs::write(&spooled, payload) {
+        error!("spool {} error: {}", spooled, err);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+    state.metrics.spooled_payloads.inc();
+    Err((status, message))
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -354,6 +425,7 @@ struct PrometheusOptions {
 #[post("/adapters/prometheus/read")]
 async fn prometheus_read_handler(
     state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
     web::Query(options): web::Query<PrometheusOptions>,
     bytes: Bytes,
 ) -> WebResult<HttpResponse> {
@@ -363,6 +435,7 @@ async fn prometheus_read_handler(
 
     let database = options.database;
     let database = database.unwrap_or("prometheus".to_string());
+    state.authorize(&req, &database, auth::Permission::Read)?;
     // let database = database.to_string();
 
     let mut decoder = snap::raw::Decoder::new();
@@ -374,79 +447,235 @@ async fn prometheus_read_handler(
         actix_web::error::ErrorNotAcceptable("bad prometheus read request: deserializing error")
     })?;
     drop(decompressed); // drop decompressed data, it'll not be used after
-    let taos = state.pool.get().expect("get connection from pool");
-    let taos = taos.deref();
-    for _i in 0..10i32 {
-        let res = prometheus_read(taos, &database, &read_request).await;
-        if let Err(err) = res {
-            warn!("read tdengine error : {}", err,);
-            tokio::time::sleep(Duration::from_millis(100)).await;
-        } else {
-            let res = res.unwrap();
-            let mut buf: Vec<u8> = vec![];
-            let _ = res.encode(&mut buf).map_err(|_| {
-                actix_web::error::ErrorNotAcceptable("failed encode protobuf message")
-            })?;
-            let mut encoder = snap::raw::Encoder::new();
-            let compressed = encoder.compress_vec(&buf).map_err(|_| {
-                actix_web::error::ErrorNotAcceptable("failed to compress with snappy method")
-            })?;
-            return Ok(HttpResponse::Ok().body(compressed));
+
+    let response_type = read_response_type(&read_request)?;
+    debug!("remote read response type: {:?}", response_type);
+    if response_type == ResponseType::StreamedXorChunks {
+        return Ok(prometheus_read_streamed(
+            state.get_ref().clone(),
+            database,
+            read_request,
+        ));
+    }
+
+    let read_options = state.config().read_options(&database);
+    let mut backoff = state.config().retry.backoff();
+    let err = loop {
+        let res = prometheus_read(
+            state.storage.as_ref(),
+            &database,
+            &read_request,
+            &read_options,
+        )
+        .await;
+        let err = match res {
+            Ok(res) => {
+                let mut buf: Vec<u8> = vec![];
+                let _ = res.encode(&mut buf).map_err(|_| {
+                    actix_web::error::ErrorNotAcceptable("failed encode protobuf message")
+                })?;
+                let mut encoder = snap::raw::Encoder::new();
+                let compressed = encoder.compress_vec(&buf).map_err(|_| {
+                    actix_web::error::ErrorNotAcceptable("failed to compress with snappy method")
+                })?;
+                return Ok(HttpResponse::Ok().body(compressed));
+            }
+            Err(err) => err,
+        };
+        if !err.is_retryable() {
+            warn!("read tdengine aborted: {}", err);
+            break err;
+        }
+        match backoff.next() {
+            Some(delay) => {
+                warn!("read tdengine error, retry in {:?}: {}", delay, err);
+                tokio::time::sleep(delay).await;
+            }
+            None => {
+                error!("read tdengine failed with retries: {}", err);
+                break err;
+            }
         }
+    };
+    Ok(HttpResponse::build(retry::read_status(&err)).body(err.to_string()))
+}
+
+/// Negotiate response type, first implemented one in `accepted_response_types` wins.
+fn read_response_type(read_request: &ReadRequest) -> WebResult<ResponseType> {
+    if read_request.accepted_response_types.is_empty() {
+        return Ok(ResponseType::Samples);
     }
+    read_request
+        .accepted_response_types
+        .iter()
+        .find_map(|t| ResponseType::from_i32(*t))
+        .ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(format!(
+                "none of accepted response types {:?} is implemented",
+                read_request.accepted_response_types
+            ))
+        })
+}
 
-    // if not success, write data and save it to persistent storage.
-    error!("failed with retries, the data will be lost");
-    std::fs::write(format!("prom-failed-read-{}.snappy", md5sum(bytes)), bytes)?;
-    // });
-    // body is loaded, now we can deserialize
-    Ok(HttpResponse::InternalServerError().finish())
+/// Stream series with `STREAMED_XOR_CHUNKS` response type as they are read.
+fn prometheus_read_streamed(
+    state: Arc<AppState>,
+    database: String,
+    read_request: ReadRequest,
+) -> HttpResponse {
+    use futures::{channel::mpsc, future, SinkExt};
+    let (mut tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+    actix_web::rt::spawn(async move {
+        let sink = tx.clone().with(|(index, series): (usize, TimeSeries)| {
+            future::ok::<_, mpsc::SendError>(Ok(Bytes::from(chunked::encode_series_frames(
+                index, series,
+            ))))
+        });
+        let read_options = state.config().read_options(&database);
+        let res = prometheus_read_into(
+            state.storage.as_ref(),
+            &database,
+            &read_request,
+            &read_options,
+            sink,
+        )
+        .await;
+        if let Err(err) = res {
+            error!("streamed read tdengine error: {}", err);
+            let _ = tx
+                .send(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    err.to_string(),
+                )))
+                .await;
+        }
+    });
+    HttpResponse::Ok()
+        .content_type(chunked::STREAMED_CONTENT_TYPE)
+        .streaming(rx)
 }
 
 /// TDengine adapter for prometheus.
+///
+/// Settings are read from the config file if any, overridden by environment variables,
+/// then by command line flags.
 #[derive(Debug, Clone, Parser)]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 #[clap(version, author)]
-struct Opts {
-    /// Debug level
-    #[clap(short, long, default_value = "info")]
-    level: log::LevelFilter,
-    /// TDengine host IP or hostname.
-    #[clap(short, long, default_value = "localhost")]
-    host: String,
-    /// TDengine server port
-    #[clap(short, long, default_value = "6030")]
-    port: u16,
-    /// TDengine user
-    #[clap(short, long, default_value = "root")]
-    user: String,
-    /// TDengine password
-    #[clap(short = 'P', long, default_value = "taosdata")]
-    password: String,
-    /// Listen to an specific ip and port
-    #[clap(short = 'L', long, default_value = "0.0.0.0:10203")]
-    listen: String,
-    /// Thread works for web request
-    #[clap(short, long, default_value = "10")]
-    workers: usize,
-    /// Sql chunk size.
+pub struct Opts {
+    /// TOML config file, reloaded on SIGHUP or `POST /-/reload`.
+    #[clap(short = 'f', long, env = "BLM_CONFIG")]
+    config: Option<PathBuf>,
+    /// Debug level [default: info]
+    #[clap(short, long, env = "BLM_LEVEL")]
+    level: Option<log::LevelFilter>,
+    /// How to connect to TDengine, one of native, rest and websocket [default: native]
+    #[clap(long, env = "BLM_CONNECTOR")]
+    connector: Option<Connector>,
+    /// TDengine host IP or hostname [default: localhost]
+    #[clap(short, long, env = "BLM_HOST")]
+    host: Option<String>,
+    /// TDengine server port of the native connector [default: 6030]
+    #[clap(short, long, env = "BLM_PORT")]
+    port: Option<u16>,
+    /// TDengine dnodes as host[:port] to fail over between, separated by commas [default: <host>:<port>]
+    #[clap(long, env = "BLM_ENDPOINTS", use_delimiter = true)]
+    endpoints: Vec<String>,
+    /// TDengine user [default: root]
+    #[clap(short, long, env = "BLM_USER")]
+    user: Option<String>,
+    /// TDengine password [default: taosdata]
+    #[clap(short = 'P', long, env = "BLM_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// taosAdapter REST endpoint [default: http://<host>:6041/rest/sqlt]
+    #[clap(long, env = "BLM_REST_URL")]
+    rest_url: Option<String>,
+    /// taosAdapter REST token, sent instead of user and password
+    #[clap(long, env = "BLM_REST_TOKEN", hide_env_values = true)]
+    rest_token: Option<String>,
+    /// taosAdapter WebSocket endpoint [default: ws://<host>:6041/rest/ws]
+    #[clap(long, env = "BLM_WEBSOCKET_URL")]
+    websocket_url: Option<String>,
+    /// taosAdapter WebSocket token
+    #[clap(long, env = "BLM_WEBSOCKET_TOKEN", hide_env_values = true)]
+    websocket_token: Option<String>,
+    /// Listen to an specific ip and port [default: 0.0.0.0:10203]
+    #[clap(short = 'L', long, env = "BLM_LISTEN")]
+    listen: Option<String>,
+    /// Thread works for web request [default: 10]
+    #[clap(short, long, env = "BLM_WORKERS")]
+    workers: Option<usize>,
+    /// Seconds to drain in-flight requests on SIGTERM, unfinished writes are spooled [default: 30]
+    #[clap(long, env = "BLM_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+    /// TLS certificate chain in PEM, serve HTTPS if set
+    #[clap(long, env = "BLM_TLS_CERT_FILE")]
+    tls_cert_file: Option<PathBuf>,
+    /// TLS private key in PEM
+    #[clap(long, env = "BLM_TLS_KEY_FILE")]
+    tls_key_file: Option<PathBuf>,
+    /// CA certificates in PEM to verify client certificates with, enables mutual TLS
+    #[clap(long, env = "BLM_TLS_CLIENT_CA_FILE")]
+    tls_client_ca_file: Option<PathBuf>,
+    /// Credentials file in TOML, enables basic and bearer token authentication
+    #[clap(long, env = "BLM_CREDENTIALS_FILE")]
+    credentials_file: Option<PathBuf>,
+    /// Sql chunk size [default: 600]
     ///
     /// The larger your table column size is, the small chunk should be setted.
-    #[clap(short, long, default_value = "600")]
-    chunk_size: usize,
-    /// Max TDengine connections
+    #[clap(short, long, env = "BLM_CHUNK_SIZE")]
+    chunk_size: Option<usize>,
+    /// Max TDengine connections of the native connector [default: 500]
     ///
     ///   - in concurrent cases, use max as 50000
     ///   - for common use, set it as 5000
-    #[clap(short = 'C', long, default_value = "500")]
-    max_connections: u32,
-    /// Max memroy, unit: GB
-    #[clap(short = 'M', long, default_value = "50")]
-    max_memory: u64,
-
-    /// Tag data type
-    #[clap(short = 't', long, default_value = "binary")]
-    tag_type: String,
+    #[clap(short = 'C', long, env = "BLM_MAX_CONNECTIONS")]
+    max_connections: Option<u32>,
+    /// Max memroy, unit: GB [default: 50]
+    #[clap(short = 'M', long, env = "BLM_MAX_MEMORY")]
+    max_memory: Option<u64>,
+
+    /// Tag data type [default: binary]
+    #[clap(short = 't', long, env = "BLM_TAG_TYPE")]
+    tag_type: Option<String>,
+    /// Keep written samples in memory instead of TDengine, for trying out the adapter
+    #[clap(long, env = "BLM_DRY_RUN")]
+    dry_run: bool,
+
+    /// Max remote read queries and super table scans running concurrently [default: 8]
+    ///
+    /// Each of them holds a TDengine connection, keep it well below max connections.
+    #[clap(long, env = "BLM_READ_PARALLELISM")]
+    read_parallelism: Option<usize>,
+    /// Max series per remote read query, 0 for unlimited [default: 100000]
+    #[clap(long, env = "BLM_READ_MAX_SERIES")]
+    read_max_series: Option<usize>,
+    /// Max samples per remote read query, 0 for unlimited [default: 50000000]
+    #[clap(long, env = "BLM_READ_MAX_SAMPLES")]
+    read_max_samples: Option<usize>,
+    /// Remote read timeout in seconds, 0 for unlimited [default: 120]
+    #[clap(long, env = "BLM_READ_TIMEOUT")]
+    read_timeout: Option<u64>,
+
+    /// Retries of remote reads and writes on retryable errors [default: 10]
+    #[clap(long, env = "BLM_MAX_RETRIES")]
+    max_retries: Option<u32>,
+    /// Backoff before the first retry in milliseconds, doubled per retry [default: 100]
+    #[clap(long, env = "BLM_RETRY_INITIAL_BACKOFF_MS")]
+    retry_initial_backoff_ms: Option<u64>,
+    /// Max backoff between retries in milliseconds [default: 10000]
+    #[clap(long, env = "BLM_RETRY_MAX_BACKOFF_MS")]
+    retry_max_backoff_ms: Option<u64>,
+
+    /// Address of the OpenTSDB telnet listener, e.g. 0.0.0.0:4242
+    #[clap(long, env = "BLM_OPENTSDB_TELNET_LISTEN")]
+    opentsdb_telnet_listen: Option<String>,
+    /// Address of the Graphite plaintext TCP and UDP listeners, e.g. 0.0.0.0:2003
+    #[clap(long, env = "BLM_GRAPHITE_LISTEN")]
+    graphite_listen: Option<String>,
+    /// Address of the StatsD UDP listener, e.g. 0.0.0.0:8125
+    #[clap(long, env = "BLM_STATSD_LISTEN")]
+    statsd_listen: Option<String>,
 }
 
 #[derive(Debug, Default)]
@@ -461,96 +690,294 @@ impl Tables {
     }
 }
 
-type StableHandler = DashMap<String, (DashSet<String>, DashMap<String, Tables>)>;
+/// Tables known to exist per database, a schema cache of the write path.
 #[derive(Debug, Default)]
-pub struct DatabasesHandler(
-    DashMap<String, DashMap<String, (DashSet<String>, DashMap<String, Tables>)>>,
-);
+pub struct DatabasesHandler(DashMap<String, Tables>);
 
 impl DatabasesHandler {
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub fn assert_database<'a>(
-        &'a self,
-        database: &str,
-    ) -> dashmap::mapref::one::Ref<String, StableHandler> {
-        match self.0.get(database) {
-            Some(st) => st,
-            None => {
-                self.0.insert(database.to_string(), Default::default());
-                self.0.get(database).unwrap()
-            }
-        }
+    pub fn exist(&self, database: &str, table: &str) -> bool {
+        matches!(self.0.get(database), Some(tables) if tables.exist(table))
+    }
+
+    pub fn add_table(&self, database: &str, table: impl Into<String>) {
+        self.0
+            .entry(database.to_string())
+            .or_default()
+            .add_table(table);
+    }
+
+    /// Forget the tables of a database, some may have been dropped.
+    pub fn forget(&self, database: &str) {
+        self.0.remove(database);
     }
 }
 #[derive(Debug)]
 pub struct AppState {
+    /// Command line and environment overrides, applied again on reload.
     opts: Opts,
-    pool: taos::TaosPool,
+    config: RwLock<Arc<Config>>,
+    /// TDengine, or in-memory samples in a dry run.
+    storage: Arc<dyn Storage>,
     create_table_lock: Mutex<i32>,
     tables: DatabasesHandler,
-    max_memory: u64,
+    metrics: metrics::Metrics,
+    /// Certificate resolver if TLS is enabled.
+    tls: Option<Arc<tls::CertResolver>>,
+    shutdown: shutdown::Shutdown,
+    /// Running totals of OTLP delta sums and histograms.
+    delta_totals: bailongma::protocols::otlp::DeltaTotals,
+}
+
+/// Connect to TDengine with the configured connector, connections are opened lazily so
+/// that the adapter starts even if TDengine is down, readiness reports it instead.
+fn connect(config: &config::TDengineConfig) -> Result<TDengine> {
+    match config.connector {
+        #[cfg(feature = "native")]
+        Connector::Native => Ok(TDengine::native(&config.native_options()?)),
+        #[cfg(not(feature = "native"))]
+        Connector::Native => anyhow::bail!("native connector is not compiled in"),
+        Connector::Rest => Ok(TDengine::rest(&config.adapter_options(Connector::Rest)?)?),
+        Connector::Websocket => Ok(TDengine::websocket(
+            &config.adapter_options(Connector::Websocket)?,
+        )),
+    }
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
+    let config = Config::load(&opts)?;
 
-    std::env::set_var(
-        "RUST_LOG",
-        format!(
-            "actix_web=info,bailongma={level},main={level}",
-            level = opts.level.to_string()
-        ),
-    );
+    // log level is capped by `log::set_max_level`, so that it could be changed on reload
+    std::env::set_var("RUST_LOG", "actix_web=info,bailongma=trace,main=trace");
     // fern::Dispatch::new()
     //         .level(opts.level)
     //         .chain(std::io::stdout())
     //         .chain(fern::log_file("output.log")?)
     //         .apply()?;
     env_logger::init();
+    log::set_max_level(config.log_level()?);
     //dbg!(&opts);
     //let create_table_lock = Arc::new(Mutex::new(0));
-    let taos_cfg = TaosCfgBuilder::default()
-        .ip(&opts.host)
-        .user(&opts.user)
-        .pass(&opts.password)
-        .db("log")
-        .port(opts.port)
-        .build()
-        .expect("ToasCfg builder error");
-    let taos_pool = r2d2::Pool::builder()
-        .max_size(opts.max_connections)
-        .test_on_check_out(false)
-        .connection_timeout(Duration::from_secs(500))
-        .max_lifetime(Some(Duration::from_secs(600)))
-        .idle_timeout(Some(Duration::from_secs(300)))
-        .build(taos_cfg)?;
-    let workers = opts.workers;
-    let listen = opts.listen.clone();
-
-    let max_memory = opts.max_memory;
+    let storage: Arc<dyn Storage> = if opts.dry_run {
+        warn!("dry run, written samples are kept in memory and lost on exit");
+        Arc::new(Memory::default())
+    } else {
+        info!(
+            "connect to TDengine with {} connector",
+            config.tdengine.connector
+        );
+        Arc::new(connect(&config.tdengine)?)
+    };
+    let workers = config.server.workers;
+    let listen = config.server.listen.clone();
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout);
+    let tls = match (&config.tls.cert_file, &config.tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let resolver = Arc::new(tls::CertResolver::new(cert_file, key_file)?);
+            let server_config = tls::server_config(&config.tls, resolver.clone())?;
+            if config.tls.reload_interval > 0 {
+                tls::watch(
+                    resolver.clone(),
+                    Duration::from_secs(config.tls.reload_interval),
+                );
+            }
+            Some((resolver, server_config))
+        }
+        _ => None,
+    };
+
     let state = Arc::new(AppState {
         opts,
-        pool: taos_pool.clone(),
+        config: RwLock::new(Arc::new(config)),
+        storage: storage.clone(),
         create_table_lock: Default::default(),
         tables: Default::default(),
-        max_memory: max_memory * 1024 * 1024,
+        metrics: Default::default(),
+        tls: tls.as_ref().map(|(resolver, _)| resolver.clone()),
+        shutdown: Default::default(),
+        delta_totals: Default::default(),
     });
+    config::watch_signal(state.clone());
+    ingest::listen(&state)?;
+    let app_state = state.clone();
     let server = HttpServer::new(move || {
         App::new()
-            .data(state.clone())
+            .data(app_state.clone())
             .wrap(Logger::default())
             .service(prometheus)
             .service(prometheus_read_handler)
+            .configure(api::configure)
+            .configure(ingest::configure)
+            .configure(metrics::configure)
+            .configure(health::configure)
+            .configure(config::configure)
     })
     .workers(workers)
-    .bind(&listen)?
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    // signals are handled to drain in-flight writes before stopping
+    .disable_signals();
+    let server = match tls {
+        Some((_, server_config)) => {
+            info!("start server with TLS, listen on {}", listen);
+            server.bind_rustls(&listen, server_config)?
+        }
+        None => {
+            info!("start server, listen on {}", listen);
+            server.bind(&listen)?
+        }
+    }
     .run();
-    info!("start server, listen on {}", listen);
+    let handle = server.handle();
+    shutdown::watch_signal(state.clone(), shutdown_timeout, async move {
+        handle.stop(true).await
+    });
 
     server.await?;
+    info!("server stopped, waiting for listeners to flush");
+    if state
+        .shutdown
+        .until_deadline(state.shutdown.listeners_stopped())
+        .await
+        .is_none()
+    {
+        warn!("shutdown deadline reached while listeners were flushing");
+    }
+    info!("closing storage connections");
+    if let Err(err) = storage.close().await {
+        error!("close storage error: {}", err);
+    }
+    log::logger().flush();
     Ok(())
 }
+
+#[cfg(test)]
+fn memory_state() -> AppState {
+    AppState {
+        opts: Opts::parse_from(["bailongma"]),
+        config: RwLock::new(Arc::new(Config::default())),
+        storage: Arc::new(Memory::default()),
+        create_table_lock: Default::default(),
+        tables: Default::default(),
+        metrics: Default::default(),
+        tls: None,
+        shutdown: Default::default(),
+        delta_totals: Default::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_staleness_round_trip() {
+    use bailongma::exposition::ParsedSample;
+    use bailongma::promql::{self, Value};
+    use bailongma::protocols::scrape::Target;
+
+    let state = memory_state();
+    let mut target = Target::new(vec![("job".to_string(), "node".to_string())]);
+    let sample = ParsedSample {
+        name: "a".to_string(),
+        labels: Vec::new(),
+        value: 1.,
+        timestamp: None,
+    };
+    for (samples, now) in [(vec![sample], 1000), (Vec::new(), 2000)] {
+        let set = target.report(Some(samples), Duration::ZERO, now);
+        write_series(&state, "prometheus", &set.into_write_request(), None)
+            .await
+            .unwrap();
+    }
+
+    let options = ReadOptions::default();
+    let query_at = |query, time| {
+        promql::instant_query(state.storage.as_ref(), "prometheus", query, time, &options)
+    };
+    match query_at("a", 1500).await.unwrap() {
+        Value::Vector(samples) => assert_eq!(samples.len(), 1),
+        value => panic!("unexpected {:?}", value),
+    }
+    // ended by the staleness marker, though within the lookback delta
+    assert_eq!(
+        query_at("a", 2500).await.unwrap(),
+        Value::Vector(Vec::new())
+    );
+    match query_at("count_over_time(a[5s])", 2500).await.unwrap() {
+        Value::Vector(samples) => assert_eq!(samples[0].value, 1.),
+        value => panic!("unexpected {:?}", value),
+    }
+
+    // remote read returns the marker
+    let matcher = LabelMatcher {
+        r#type: label_matcher::Type::Eq as i32,
+        name: "__name__".to_string(),
+        value: "a".to_string(),
+    };
+    let req = ReadRequest {
+        queries: vec![Query {
+            start_timestamp_ms: 0,
+            end_timestamp_ms: 2000,
+            matchers: vec![matcher],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let res = prometheus_read(state.storage.as_ref(), "prometheus", &req, &options)
+        .await
+        .unwrap();
+    let samples = &res.results[0].timeseries[0].samples;
+    assert_eq!(samples[0].value, Some(1.));
+    assert!(is_stale(samples[1].value.unwrap()));
+}
+
+#[tokio::test]
+async fn test_nan_round_trip() {
+    use bailongma::exposition::ParsedSample;
+    use bailongma::promql::{self, Value};
+    use bailongma::protocols::scrape::Target;
+
+    let state = memory_state();
+    let mut target = Target::new(vec![("job".to_string(), "node".to_string())]);
+    let sample = ParsedSample {
+        name: "a".to_string(),
+        labels: Vec::new(),
+        value: f64::NAN,
+        timestamp: None,
+    };
+    let set = target.report(Some(vec![sample]), Duration::ZERO, 1000);
+    write_series(&state, "prometheus", &set.into_write_request(), None)
+        .await
+        .unwrap();
+
+    // a plain NaN does not end the series
+    let options = ReadOptions::default();
+    match promql::instant_query(state.storage.as_ref(), "prometheus", "a", 1500, &options)
+        .await
+        .unwrap()
+    {
+        Value::Vector(samples) => assert!(samples[0].value.is_nan()),
+        value => panic!("unexpected {:?}", value),
+    }
+
+    let matcher = LabelMatcher {
+        r#type: label_matcher::Type::Eq as i32,
+        name: "__name__".to_string(),
+        value: "a".to_string(),
+    };
+    let req = ReadRequest {
+        queries: vec![Query {
+            start_timestamp_ms: 0,
+            end_timestamp_ms: 2000,
+            matchers: vec![matcher],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let res = prometheus_read(state.storage.as_ref(), "prometheus", &req, &options)
+        .await
+        .unwrap();
+    let value = res.results[0].timeseries[0].samples[0].value.unwrap();
+    assert!(value.is_nan() && !is_stale(value));
+}
@@ -0,0 +1,311 @@
+//! Self-monitoring metrics of the adapter, exposed at `/metrics`.
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, HttpResponse};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use sysinfo::{ProcessExt, SystemExt};
+
+use bailongma::validation::ValidationReport;
+
+use crate::AppState;
+
+/// Max values of the `database` label, which comes from requests, further databases are
+/// counted as `other`.
+const MAX_DATABASES: usize = 64;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Remote write requests received per database.
+    pub requests: IntCounterVec,
+    /// Compressed bytes received per database.
+    pub bytes: IntCounterVec,
+    /// Series received per database.
+    pub series: IntCounterVec,
+    /// Samples received per database.
+    pub samples: IntCounterVec,
+    /// Latency of insert statements.
+    pub insert_duration: Histogram,
+    /// Latency of create and alter statements.
+    pub ddl_duration: Histogram,
+    /// Write attempts retried after an error.
+    pub write_retries: IntCounter,
+    /// Write requests failed after all retries.
+    pub write_failures: IntCounter,
+    /// Failed payloads spooled to local files.
+    pub spooled_payloads: IntCounter,
+    /// Series rejected by validation per reason.
+    pub rejected_series: IntCounterVec,
+    /// Written series whose table was in the schema cache.
+    pub schema_cache_hits: IntCounter,
+    /// Written series whose table was not in the schema cache.
+    pub schema_cache_misses: IntCounter,
+    pool_waiting: IntGauge,
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    endpoint_up: IntGaugeVec,
+    endpoint_failures: IntCounterVec,
+    memory: IntGauge,
+    virtual_memory: IntGauge,
+    sys: Mutex<sysinfo::System>,
+    /// Values of the `database` label so far.
+    databases: Mutex<HashSet<String>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("bailongma".to_string()), None)
+            .expect("metrics prefix should be valid");
+        let counter_vec = |name: &str, help: &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["database"])
+                .expect("metric options should be valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric should be registered once");
+            counter
+        };
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).expect("metric options should be valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric should be registered once");
+            counter
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGauge::new(name, help).expect("metric options should be valid");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("metric should be registered once");
+            gauge
+        };
+        let endpoint_gauge = |name: &str, help: &str| {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), &["endpoint"])
+                .expect("metric options should be valid");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("metric should be registered once");
+            gauge
+        };
+        let endpoint_counter = |name: &str, help: &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["endpoint"])
+                .expect("metric options should be valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric should be registered once");
+            counter
+        };
+        let reason_counter = |name: &str, help: &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["reason"])
+                .expect("metric options should be valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric should be registered once");
+            counter
+        };
+        let histogram = |name: &str, help: &str| {
+            let histogram = Histogram::with_opts(HistogramOpts::new(name, help))
+                .expect("metric options should be valid");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("metric should be registered once");
+            histogram
+        };
+        Metrics {
+            requests: counter_vec("write_requests_total", "Remote write requests received."),
+            bytes: counter_vec(
+                "write_bytes_total",
+                "Compressed bytes of remote write requests received.",
+            ),
+            series: counter_vec(
+                "write_series_total",
+                "Series of remote write requests received.",
+            ),
+            samples: counter_vec(
+                "write_samples_total",
+                "Samples of remote write requests received.",
+            ),
+            insert_duration: histogram(
+                "insert_duration_seconds",
+                "Latency of TDengine insert statements.",
+            ),
+            ddl_duration: histogram(
+                "ddl_duration_seconds",
+                "Latency of TDengine create and alter statements.",
+            ),
+            write_retries: counter(
+                "write_retries_total",
+                "Write attempts retried after an error.",
+            ),
+            write_failures: counter(
+                "write_failures_total",
+                "Write requests failed after all retries.",
+            ),
+            spooled_payloads: counter(
+                "spooled_payloads_total",
+                "Failed write payloads spooled to local files.",
+            ),
+            rejected_series: reason_counter(
+                "write_rejected_series_total",
+                "Written series rejected by validation.",
+            ),
+            schema_cache_hits: counter(
+                "schema_cache_hits_total",
+                "Written series whose table was in the schema cache.",
+            ),
+            schema_cache_misses: counter(
+                "schema_cache_misses_total",
+                "Written series whose table was not in the schema cache.",
+            ),
+            pool_waiting: gauge(
+                "pool_waiting_connections",
+                "Callers waiting for a pooled TDengine connection.",
+            ),
+            pool_connections: gauge("pool_connections", "TDengine connections in the pool."),
+            pool_idle_connections: gauge(
+                "pool_idle_connections",
+                "Idle TDengine connections in the pool.",
+            ),
+            endpoint_up: endpoint_gauge(
+                "endpoint_up",
+                "Whether a TDengine endpoint is up, 0 after a failure until it is reached again.",
+            ),
+            endpoint_failures: endpoint_counter(
+                "endpoint_failures_total",
+                "Failed connections and requests per TDengine endpoint.",
+            ),
+            memory: gauge(
+                "process_memory_bytes",
+                "Resident memory of the adapter process.",
+            ),
+            virtual_memory: gauge(
+                "process_virtual_memory_bytes",
+                "Virtual memory of the adapter process.",
+            ),
+            sys: Mutex::new(sysinfo::System::new()),
+            databases: Mutex::new(HashSet::new()),
+            registry,
+        }
+    }
+
+    /// Record a received remote write request.
+    pub fn observe_write(&self, database: &str, bytes: usize, series: usize, samples: usize) {
+        let database = self.database_label(database);
+        let database = database.as_str();
+        self.requests.with_label_values(&[database]).inc();
+        self.bytes
+            .with_label_values(&[database])
+            .inc_by(bytes as u64);
+        self.series
+            .with_label_values(&[database])
+            .inc_by(series as u64);
+        self.samples
+            .with_label_values(&[database])
+            .inc_by(samples as u64);
+    }
+
+    /// Record the series rejected by validation per reason.
+    pub fn observe_rejections(&self, report: &ValidationReport) {
+        for (reason, count) in &report.reasons {
+            self.rejected_series
+                .with_label_values(&[*reason])
+                .inc_by(*count as u64);
+        }
+    }
+
+    /// Label value of a database, up to [MAX_DATABASES] distinct ones.
+    fn database_label(&self, database: &str) -> String {
+        let mut databases = self.databases.lock().unwrap();
+        if databases.contains(database) {
+            return database.to_string();
+        }
+        if databases.len() < MAX_DATABASES {
+            databases.insert(database.to_string());
+            return database.to_string();
+        }
+        "other".to_string()
+    }
+
+    /// Refresh gauges sampled at scrape time.
+    fn refresh(&self, state: &AppState) {
+        if let Some(pool) = state.storage.pool_state() {
+            self.pool_connections.set(pool.connections as i64);
+            self.pool_idle_connections.set(pool.idle_connections as i64);
+            self.pool_waiting.set(pool.waiting as i64);
+        }
+        for endpoint in state.storage.endpoints() {
+            let labels = [endpoint.address.as_str()];
+            self.endpoint_up
+                .with_label_values(&labels)
+                .set(endpoint.up as i64);
+            let failures = self.endpoint_failures.with_label_values(&labels);
+            failures.inc_by(endpoint.failures.saturating_sub(failures.get()));
+        }
+
+        if let Some((memory, virtual_memory)) = self.process_memory() {
+            self.memory.set(memory as i64 * 1024);
+            self.virtual_memory.set(virtual_memory as i64 * 1024);
+        }
+    }
+
+    /// Resident and virtual memory of the process in KB, as sysinfo reports.
+    pub fn process_memory(&self) -> Option<(u64, u64)> {
+        let pid = std::process::id() as _;
+        let mut sys = self.sys.lock().unwrap();
+        if !sys.refresh_process(pid) {
+            return None;
+        }
+        sys.process(pid)
+            .map(|ps| (ps.memory(), ps.virtual_memory()))
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Await a future, observing its latency in the histogram.
+pub async fn timed<F: Future>(histogram: &Histogram, future: F) -> F::Output {
+    let _timer = histogram.start_timer();
+    future.await
+}
+
+async fn metrics(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    state.metrics.refresh(&state);
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(err) = encoder.encode(&state.metrics.registry.gather(), &mut buf) {
+        log::error!("encode metrics error: {}", err);
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buf)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/metrics").route(web::get().to(metrics)));
+}
+
+#[test]
+fn test_database_label() {
+    let metrics = Metrics::new();
+    for i in 0..MAX_DATABASES {
+        let database = format!("db{}", i);
+        assert_eq!(metrics.database_label(&database), database);
+    }
+    assert_eq!(metrics.database_label("db0"), "db0");
+    assert_eq!(metrics.database_label("random"), "other");
+}
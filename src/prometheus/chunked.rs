@@ -0,0 +1,407 @@
+//! Gorilla/XOR chunk encoding and framing for `STREAMED_XOR_CHUNKS` remote read responses.
+//!
+//! The encoding follows Prometheus `tsdb/chunkenc/xor.go`, the framing follows
+//! `storage/remote/chunked.go`.
+use prost::Message;
+
+use crate::prometheus::types::*;
+
+/// Content type of streamed remote read responses.
+pub const STREAMED_CONTENT_TYPE: &str =
+    "application/x-streamed-protobuf; proto=prometheus.ChunkedReadResponse";
+
+/// Prometheus cuts chunks at 120 samples.
+pub const MAX_SAMPLES_PER_CHUNK: usize = 120;
+
+/// Max bytes of chunks in one frame, same as Prometheus default.
+pub const MAX_BYTES_IN_FRAME: usize = 1024 * 1024;
+
+#[derive(Debug)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits available for writing in the last byte.
+    count: u8,
+}
+
+impl BitWriter {
+    fn with_header(header: usize) -> Self {
+        BitWriter {
+            bytes: vec![0; header],
+            count: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.count == 0 {
+            self.bytes.push(0);
+            self.count = 8;
+        }
+        if bit {
+            let i = self.bytes.len() - 1;
+            self.bytes[i] |= 1 << (self.count - 1);
+        }
+        self.count -= 1;
+    }
+
+    /// Write the lowest `nbits` bits of `value`, most significant bit first.
+    fn write_bits(&mut self, value: u64, nbits: u8) {
+        for i in (0..nbits).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.write_bits(byte as u64, 8);
+    }
+}
+
+fn put_uvarint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut uvalue = (value as u64) << 1;
+    if value < 0 {
+        uvalue = !uvalue;
+    }
+    put_uvarint(buf, uvalue)
+}
+
+fn bit_range(value: i64, nbits: u8) -> bool {
+    -((1 << (nbits - 1)) - 1) <= value && value <= 1 << (nbits - 1)
+}
+
+/// XOR chunk appender.
+#[derive(Debug)]
+pub struct XorChunk {
+    writer: BitWriter,
+    num: u16,
+    min_time: i64,
+    t: i64,
+    t_delta: u64,
+    v: f64,
+    leading: u8,
+    trailing: u8,
+}
+
+impl Default for XorChunk {
+    fn default() -> Self {
+        XorChunk {
+            writer: BitWriter::with_header(2),
+            num: 0,
+            min_time: 0,
+            t: 0,
+            t_delta: 0,
+            v: 0.,
+            leading: 0xff,
+            trailing: 0,
+        }
+    }
+}
+
+impl XorChunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.num as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num == 0
+    }
+
+    pub fn append(&mut self, t: i64, v: f64) {
+        let mut t_delta = 0;
+        match self.num {
+            0 => {
+                let mut buf = Vec::with_capacity(10);
+                put_varint(&mut buf, t);
+                for byte in buf {
+                    self.writer.write_byte(byte);
+                }
+                self.writer.write_bits(v.to_bits(), 64);
+                self.min_time = t;
+            }
+            1 => {
+                t_delta = (t - self.t) as u64;
+                let mut buf = Vec::with_capacity(10);
+                put_uvarint(&mut buf, t_delta);
+                for byte in buf {
+                    self.writer.write_byte(byte);
+                }
+                self.write_value_delta(v);
+            }
+            _ => {
+                t_delta = (t - self.t) as u64;
+                let dod = t_delta.wrapping_sub(self.t_delta) as i64;
+                if dod == 0 {
+                    self.writer.write_bit(false);
+                } else if bit_range(dod, 14) {
+                    self.writer.write_bits(0b10, 2);
+                    self.writer.write_bits(dod as u64, 14);
+                } else if bit_range(dod, 17) {
+                    self.writer.write_bits(0b110, 3);
+                    self.writer.write_bits(dod as u64, 17);
+                } else if bit_range(dod, 20) {
+                    self.writer.write_bits(0b1110, 4);
+                    self.writer.write_bits(dod as u64, 20);
+                } else {
+                    self.writer.write_bits(0b1111, 4);
+                    self.writer.write_bits(dod as u64, 64);
+                }
+                self.write_value_delta(v);
+            }
+        }
+        self.t = t;
+        self.v = v;
+        self.t_delta = t_delta;
+        self.num += 1;
+    }
+
+    fn write_value_delta(&mut self, v: f64) {
+        let delta = v.to_bits() ^ self.v.to_bits();
+        if delta == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        self.writer.write_bit(true);
+
+        let mut leading = delta.leading_zeros() as u8;
+        let trailing = delta.trailing_zeros() as u8;
+        // Clamp number of leading zeros to avoid overflow when encoding.
+        if leading >= 32 {
+            leading = 31;
+        }
+
+        if self.leading != 0xff && leading >= self.leading && trailing >= self.trailing {
+            self.writer.write_bit(false);
+            self.writer
+                .write_bits(delta >> self.trailing, 64 - self.leading - self.trailing);
+        } else {
+            self.leading = leading;
+            self.trailing = trailing;
+            self.writer.write_bit(true);
+            self.writer.write_bits(leading as u64, 5);
+            // 64 significant bits overflows to 0 in 6 bits, the reader handles it.
+            let sigbits = 64 - leading - trailing;
+            self.writer.write_bits(sigbits as u64, 6);
+            self.writer.write_bits(delta >> trailing, sigbits);
+        }
+    }
+
+    /// Finish the chunk into a protobuf [Chunk].
+    pub fn finish(self) -> Chunk {
+        let XorChunk {
+            writer,
+            num,
+            min_time,
+            t,
+            ..
+        } = self;
+        let mut data = writer.bytes;
+        data[..2].copy_from_slice(&num.to_be_bytes());
+        Chunk {
+            min_time_ms: min_time,
+            max_time_ms: t,
+            r#type: chunk::Encoding::Xor as i32,
+            data,
+        }
+    }
+}
+
+/// Encode samples into XOR chunks, at most [MAX_SAMPLES_PER_CHUNK] samples in each.
+///
+/// Samples without value are encoded as NaN.
+pub fn encode_chunks(samples: &[Sample]) -> Vec<Chunk> {
+    samples
+        .chunks(MAX_SAMPLES_PER_CHUNK)
+        .map(|samples| {
+            let mut chunk = XorChunk::new();
+            for sample in samples {
+                chunk.append(sample.timestamp, sample.value.unwrap_or(f64::NAN));
+            }
+            chunk.finish()
+        })
+        .collect()
+}
+
+/// Frame a message: uvarint size, big endian CRC32 Castagnoli checksum, then message bytes.
+pub fn encode_frame(message: &ChunkedReadResponse) -> Vec<u8> {
+    let bytes = message.encode_to_vec();
+    let mut frame = Vec::with_capacity(bytes.len() + 14);
+    put_uvarint(&mut frame, bytes.len() as u64);
+    frame.extend_from_slice(&crc32c::crc32c(&bytes).to_be_bytes());
+    frame.extend_from_slice(&bytes);
+    frame
+}
+
+/// Encode a series into one or more frames, split by [MAX_BYTES_IN_FRAME].
+pub fn encode_series_frames(query_index: usize, series: TimeSeries) -> Vec<u8> {
+    let TimeSeries { labels, samples } = series;
+    let mut frames = Vec::new();
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    for chunk in encode_chunks(&samples) {
+        size += chunk.data.len();
+        chunks.push(chunk);
+        if size >= MAX_BYTES_IN_FRAME {
+            frames.extend(encode_frame(&ChunkedReadResponse {
+                chunked_series: vec![ChunkedSeries {
+                    labels: labels.clone(),
+                    chunks: std::mem::take(&mut chunks),
+                }],
+                query_index: query_index as i64,
+            }));
+            size = 0;
+        }
+    }
+    if !chunks.is_empty() {
+        frames.extend(encode_frame(&ChunkedReadResponse {
+            chunked_series: vec![ChunkedSeries { labels, chunks }],
+            query_index: query_index as i64,
+        }));
+    }
+    frames
+}
+
+#[cfg(test)]
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+#[cfg(test)]
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> bool {
+        let bit = self.bytes[self.pos / 8] >> (7 - self.pos % 8) & 1 == 1;
+        self.pos += 1;
+        bit
+    }
+    fn read_bits(&mut self, nbits: u8) -> u64 {
+        (0..nbits).fold(0, |v, _| v << 1 | self.read_bit() as u64)
+    }
+    fn read_uvarint(&mut self) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = self.read_bits(8);
+            value |= (byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+}
+
+/// Decode a XOR chunk, a port of Prometheus `xorIterator` for tests.
+#[cfg(test)]
+fn decode_chunk(chunk: &Chunk) -> Vec<(i64, f64)> {
+    let num = u16::from_be_bytes([chunk.data[0], chunk.data[1]]);
+    let mut reader = BitReader {
+        bytes: &chunk.data[2..],
+        pos: 0,
+    };
+    let mut samples = Vec::new();
+    let (mut t, mut v, mut t_delta) = (0i64, 0u64, 0u64);
+    let (mut leading, mut trailing) = (0u8, 0u8);
+    for i in 0..num {
+        match i {
+            0 => {
+                let ut = reader.read_uvarint();
+                t = (ut >> 1) as i64 ^ -((ut & 1) as i64);
+                v = reader.read_bits(64);
+            }
+            _ => {
+                if i == 1 {
+                    t_delta = reader.read_uvarint();
+                } else {
+                    let sz = match (0..4).take_while(|_| reader.read_bit()).count() {
+                        0 => 0,
+                        1 => 14,
+                        2 => 17,
+                        3 => 20,
+                        _ => 64,
+                    };
+                    let mut dod = reader.read_bits(sz) as i64;
+                    if sz != 0 && sz != 64 && dod > 1 << (sz - 1) {
+                        dod -= 1 << sz;
+                    }
+                    t_delta = (t_delta as i64 + dod) as u64;
+                }
+                t += t_delta as i64;
+                if reader.read_bit() {
+                    if reader.read_bit() {
+                        leading = reader.read_bits(5) as u8;
+                        let mut sigbits = reader.read_bits(6) as u8;
+                        if sigbits == 0 {
+                            sigbits = 64;
+                        }
+                        trailing = 64 - leading - sigbits;
+                    }
+                    let sigbits = 64 - leading - trailing;
+                    v ^= reader.read_bits(sigbits) << trailing;
+                }
+            }
+        }
+        samples.push((t, f64::from_bits(v)));
+    }
+    samples
+}
+
+#[test]
+fn test_xor_chunk_roundtrip() {
+    let mut samples = Vec::new();
+    let mut ts = 1621511073000i64;
+    for i in 0..300i64 {
+        // irregular intervals to cover all delta-of-delta buckets
+        ts += match i % 5 {
+            0 => 15000,
+            1 => 15001,
+            2 => 8000,
+            3 => 100_000,
+            _ => 3_000_000_000,
+        };
+        let value = match i % 4 {
+            0 => Some(i as f64 * 1.5),
+            1 => Some(-(i as f64) / 3.),
+            2 => None,
+            _ => Some(1e300),
+        };
+        samples.push(Sample {
+            timestamp: ts,
+            value,
+        });
+    }
+    let chunks = encode_chunks(&samples);
+    assert_eq!(chunks.len(), 3);
+    let decoded: Vec<_> = chunks.iter().flat_map(decode_chunk).collect();
+    assert_eq!(decoded.len(), samples.len());
+    for ((t, v), sample) in decoded.into_iter().zip(&samples) {
+        assert_eq!(t, sample.timestamp);
+        match sample.value {
+            Some(value) => assert_eq!(v, value),
+            None => assert!(v.is_nan()),
+        }
+    }
+    assert_eq!(chunks[0].min_time_ms, samples[0].timestamp);
+    assert_eq!(chunks[0].max_time_ms, samples[119].timestamp);
+}
+
+#[test]
+fn test_encode_frame() {
+    let message = ChunkedReadResponse {
+        chunked_series: vec![],
+        query_index: 1,
+    };
+    let bytes = message.encode_to_vec();
+    let frame = encode_frame(&message);
+    assert_eq!(frame[0] as usize, bytes.len());
+    assert_eq!(&frame[1..5], &crc32c::crc32c(&bytes).to_be_bytes());
+    assert_eq!(&frame[5..], &bytes[..]);
+}
@@ -0,0 +1,143 @@
+//! Label and series discovery from TDengine metadata, backing the Prometheus HTTP API
+//! `/api/v1/labels`, `/api/v1/label/<name>/values` and `/api/v1/series`.
+use std::collections::{BTreeSet, HashSet};
+
+use crate::prometheus::reader::*;
+use crate::prometheus::types::*;
+use crate::storage::Storage;
+
+type Result<T> = std::result::Result<T, PrometheusReaderError>;
+
+/// Optional time range in milliseconds, series without samples in it are excluded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeRange {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+impl TimeRange {
+    pub(crate) fn condition(&self) -> Option<String> {
+        match (self.start, self.end) {
+            (None, None) => None,
+            (Some(start), None) => Some(format!("ts >= {}", start)),
+            (None, Some(end)) => Some(format!("ts <= {}", end)),
+            (Some(start), Some(end)) => Some(format!("ts >= {} AND ts <= {}", start, end)),
+        }
+    }
+}
+
+/// Series matched by any of the selectors, deduplicated.
+pub async fn series(
+    storage: &dyn Storage,
+    database: &str,
+    selectors: &[Vec<LabelMatcher>],
+    range: TimeRange,
+) -> Result<Vec<Vec<Label>>> {
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for matchers in selectors {
+        let plan = query_to_plan(&Query {
+            matchers: matchers.clone(),
+            ..Default::default()
+        })?;
+        for stable in
+            metric_filter_to_tables(storage, database, plan.metric_filter.as_ref()).await?
+        {
+            let series = storage
+                .resolve_series(database, &stable, &plan, None)
+                .await?;
+            let series = storage
+                .series_in_range(database, &stable, series, range)
+                .await?;
+            for Series { table, labels } in series {
+                if seen.insert(table) {
+                    result.push(labels);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Super table names in the database.
+async fn stables(storage: &dyn Storage, database: &str) -> Result<Vec<String>> {
+    metric_filter_to_tables(storage, database, None).await
+}
+
+/// All series if there's no selector.
+fn or_all(selectors: &[Vec<LabelMatcher>]) -> &[Vec<LabelMatcher>] {
+    const ALL: &[Vec<LabelMatcher>] = &[Vec::new()];
+    if selectors.is_empty() {
+        ALL
+    } else {
+        selectors
+    }
+}
+
+/// Label names, from super table tags if there's neither a selector nor a time range.
+pub async fn label_names(
+    storage: &dyn Storage,
+    database: &str,
+    selectors: &[Vec<LabelMatcher>],
+    range: TimeRange,
+) -> Result<Vec<String>> {
+    let mut names = BTreeSet::new();
+    if selectors.is_empty() && range.condition().is_none() {
+        for stable in stables(storage, database).await? {
+            let tags = stable_tags(storage, database, &stable).await?;
+            names.extend(
+                tags.into_iter()
+                    .flatten()
+                    .filter(|tag| tag != "taghash")
+                    .map(|tag| tag.replacen("t_", "", 1)),
+            );
+            names.insert("__name__".to_string());
+        }
+    } else {
+        for labels in series(storage, database, or_all(selectors), range).await? {
+            names.extend(labels.into_iter().map(|label| label.name));
+        }
+    }
+    Ok(names.into_iter().collect())
+}
+
+/// Values of a label, from distinct tag values if there's neither a selector nor a time
+/// range.
+pub async fn label_values(
+    storage: &dyn Storage,
+    database: &str,
+    name: &str,
+    selectors: &[Vec<LabelMatcher>],
+    range: TimeRange,
+) -> Result<Vec<String>> {
+    let mut values = BTreeSet::new();
+    if !selectors.is_empty() || range.condition().is_some() {
+        for labels in series(storage, database, or_all(selectors), range).await? {
+            values.extend(
+                labels
+                    .into_iter()
+                    .filter(|label| label.name == name)
+                    .map(|label| label.value),
+            );
+        }
+        return Ok(values.into_iter().collect());
+    }
+
+    let stables = stables(storage, database).await?;
+    if name == "__name__" {
+        return Ok(stables);
+    }
+    for stable in stables {
+        let column = stable_tags(storage, database, &stable)
+            .await?
+            .into_iter()
+            .flatten()
+            .find(|tag| tag != "taghash" && tag.replacen("t_", "", 1) == name);
+        let column = match column {
+            Some(column) => column,
+            None => continue,
+        };
+        values.extend(storage.tag_values(database, &stable, &column).await?);
+    }
+    Ok(values.into_iter().collect())
+}
@@ -0,0 +1,341 @@
+//! Prometheus text exposition and OpenMetrics text encoding and parsing of samples.
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::prometheus::types::*;
+
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    OpenMetrics,
+}
+
+impl Format {
+    /// Negotiate the format from an `Accept` header, OpenMetrics only if explicitly accepted.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Format::OpenMetrics,
+            _ => Format::Text,
+        }
+    }
+
+    /// Format of a scraped response by its `Content-Type`, text exposition if unknown.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.starts_with("application/openmetrics-text") => {
+                Format::OpenMetrics
+            }
+            _ => Format::Text,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => TEXT_CONTENT_TYPE,
+            Format::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+}
+
+/// Format a sample value as Prometheus does.
+pub fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Encode the latest sample of each series, series of the same metric must be adjacent to
+/// share a `# TYPE` line.
+pub fn encode(series: &[TimeSeries], format: Format) -> String {
+    let mut out = String::new();
+    let mut last_metric = None;
+    for ts in series {
+        let sample = match ts.samples.last() {
+            Some(Sample {
+                value: Some(value),
+                timestamp,
+            }) => (*value, *timestamp),
+            _ => continue,
+        };
+        let name = ts
+            .labels
+            .iter()
+            .find(|label| label.name == "__name__")
+            .map_or("", |label| label.value.as_str());
+        if last_metric != Some(name) {
+            let r#type = match format {
+                Format::Text => "untyped",
+                Format::OpenMetrics => "unknown",
+            };
+            let _ = writeln!(out, "# TYPE {} {}", name, r#type);
+            last_metric = Some(name);
+        }
+        out.push_str(name);
+        let mut labels = ts.labels.iter().filter(|label| label.name != "__name__");
+        if let Some(first) = labels.next() {
+            let _ = write!(
+                out,
+                "{{{}=\"{}\"",
+                first.name,
+                escape_label_value(&first.value)
+            );
+            for label in labels {
+                let _ = write!(
+                    out,
+                    ",{}=\"{}\"",
+                    label.name,
+                    escape_label_value(&label.value)
+                );
+            }
+            out.push('}');
+        }
+        let (value, timestamp) = sample;
+        let _ = match format {
+            Format::Text => writeln!(out, " {} {}", format_value(value), timestamp),
+            Format::OpenMetrics => {
+                writeln!(out, " {} {}", format_value(value), timestamp as f64 / 1000.)
+            }
+        };
+    }
+    if format == Format::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+/// A sample parsed from text exposition or OpenMetrics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedSample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// Timestamp in milliseconds, if exposed.
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("invalid line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+fn parse_value(value: &str) -> Result<f64, String> {
+    match value {
+        "NaN" => Ok(f64::NAN),
+        "+Inf" | "Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        _ => value
+            .parse()
+            .map_err(|_| format!("invalid value {:?}", value)),
+    }
+}
+
+/// Parse labels after the opening brace into `labels`, returns the rest of the line.
+fn parse_labels<'a>(
+    mut rest: &'a str,
+    labels: &mut Vec<(String, String)>,
+) -> Result<&'a str, String> {
+    loop {
+        rest = rest.trim_start();
+        if let Some(rest) = rest.strip_prefix('}') {
+            return Ok(rest);
+        }
+        let (name, value) = rest
+            .split_once('=')
+            .ok_or_else(|| "missing label value".to_string())?;
+        let value = value
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| format!("label value of {} is not quoted", name.trim()))?;
+        let mut unescaped = String::new();
+        let mut end = None;
+        let mut chars = value.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => unescaped.push('\n'),
+                    Some((_, c)) => unescaped.push(c),
+                    None => break,
+                },
+                '"' => {
+                    end = Some(index);
+                    break;
+                }
+                c => unescaped.push(c),
+            }
+        }
+        let end = end.ok_or_else(|| format!("unterminated label value of {}", name.trim()))?;
+        labels.push((name.trim().to_string(), unescaped));
+        rest = value[end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+}
+
+fn parse_sample(line: &str, format: Format) -> Result<ParsedSample, String> {
+    let end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .unwrap_or(line.len());
+    let name = &line[..end];
+    if name.is_empty() {
+        return Err("missing metric name".to_string());
+    }
+    let mut labels = Vec::new();
+    let rest = match line[end..].strip_prefix('{') {
+        Some(rest) => parse_labels(rest, &mut labels)?,
+        None => &line[end..],
+    };
+    // OpenMetrics exemplars follow the sample after ` # `
+    let mut fields = rest.split_whitespace().take_while(|field| *field != "#");
+    let value = parse_value(fields.next().ok_or_else(|| "missing value".to_string())?)?;
+    let timestamp = match fields.next() {
+        None => None,
+        Some(timestamp) => {
+            let invalid = || format!("invalid timestamp {:?}", timestamp);
+            Some(match format {
+                Format::Text => timestamp.parse().map_err(|_| invalid())?,
+                Format::OpenMetrics => {
+                    let seconds: f64 = timestamp.parse().map_err(|_| invalid())?;
+                    (seconds * 1000.) as i64
+                }
+            })
+        }
+    };
+    if fields.next().is_some() {
+        return Err("unexpected fields after the timestamp".to_string());
+    }
+    Ok(ParsedSample {
+        name: name.to_string(),
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// Parse a scraped body, comments and metadata lines are skipped. The first invalid line
+/// fails the whole body, as a failed scrape.
+pub fn parse(text: &str, format: Format) -> Result<Vec<ParsedSample>, ParseError> {
+    let mut samples = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if format == Format::OpenMetrics && line == "# EOF" {
+            break;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let sample = parse_sample(line, format).map_err(|message| ParseError {
+            line: index + 1,
+            message,
+        })?;
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+#[test]
+fn test_parse() {
+    let samples = parse(
+        "# HELP http_requests_total Requests.\n\
+         # TYPE http_requests_total counter\n\
+         http_requests_total{method=\"post\",path=\"/a\\\"b\\\\c\\nd\",} 1027 1395066363000\n\
+         \n\
+         up 1\n\
+         temperature{room=\"a b\"} -Inf\n",
+        Format::Text,
+    )
+    .unwrap();
+    assert_eq!(
+        samples[0],
+        ParsedSample {
+            name: "http_requests_total".to_string(),
+            labels: vec![
+                ("method".to_string(), "post".to_string()),
+                ("path".to_string(), "/a\"b\\c\nd".to_string()),
+            ],
+            value: 1027.,
+            timestamp: Some(1395066363000),
+        }
+    );
+    assert_eq!(samples[1].labels, vec![]);
+    assert_eq!(samples[1].timestamp, None);
+    assert_eq!(samples[2].value, f64::NEG_INFINITY);
+
+    let samples = parse(
+        "foo_total 17 1520879607.789 # {trace_id=\"KOO5S4vxi0o\"} 0.67\n# EOF\nbar 1\n",
+        Format::OpenMetrics,
+    )
+    .unwrap();
+    assert_eq!(samples.len(), 1);
+    assert_eq!(samples[0].timestamp, Some(1520879607789));
+
+    assert_eq!(
+        parse("up\n", Format::Text).unwrap_err().to_string(),
+        "invalid line 1: missing value"
+    );
+    assert!(parse("up{job=\"a} 1\n", Format::Text).is_err());
+    assert!(parse("up{job=a} 1\n", Format::Text).is_err());
+    assert!(parse("up 1 2 3\n", Format::Text).is_err());
+    assert_eq!(
+        Format::from_content_type(Some("application/openmetrics-text; version=1.0.0")),
+        Format::OpenMetrics
+    );
+}
+
+#[test]
+fn test_encode() {
+    let series = vec![
+        TimeSeries {
+            labels: vec![
+                Label {
+                    name: "__name__".to_string(),
+                    value: "up".to_string(),
+                },
+                Label {
+                    name: "job".to_string(),
+                    value: "a\"b".to_string(),
+                },
+            ],
+            samples: vec![Sample {
+                value: Some(1.),
+                timestamp: 1500,
+            }],
+        },
+        TimeSeries {
+            labels: vec![Label {
+                name: "__name__".to_string(),
+                value: "up".to_string(),
+            }],
+            samples: vec![Sample {
+                value: Some(f64::INFINITY),
+                timestamp: 2000,
+            }],
+        },
+    ];
+    assert_eq!(
+        encode(&series, Format::Text),
+        "# TYPE up untyped\nup{job=\"a\\\"b\"} 1 1500\nup +Inf 2000\n"
+    );
+    assert_eq!(
+        encode(&series, Format::OpenMetrics),
+        "# TYPE up unknown\nup{job=\"a\\\"b\"} 1 1.5\nup +Inf 2\n# EOF\n"
+    );
+}
@@ -0,0 +1,55 @@
+//! Latest samples of series matched by selectors, backing the `/federate` endpoint.
+use std::collections::HashSet;
+
+use crate::prometheus::is_stale;
+use crate::prometheus::reader::*;
+use crate::prometheus::types::*;
+use crate::promql::LOOKBACK_DELTA_MS;
+use crate::storage::Storage;
+
+type Result<T> = std::result::Result<T, PrometheusReaderError>;
+
+/// Latest sample of each series matched by any of the selectors, grouped by metric name.
+///
+/// As Prometheus, series without samples in the lookback delta before `now` in
+/// milliseconds, or ended by a staleness marker, are dropped.
+pub async fn latest(
+    storage: &dyn Storage,
+    database: &str,
+    selectors: &[Vec<LabelMatcher>],
+    now: i64,
+) -> Result<Vec<TimeSeries>> {
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for matchers in selectors {
+        let plan = query_to_plan(&Query {
+            matchers: matchers.clone(),
+            ..Default::default()
+        })?;
+        for stable in
+            metric_filter_to_tables(storage, database, plan.metric_filter.as_ref()).await?
+        {
+            let series = storage
+                .resolve_series(database, &stable, &plan, None)
+                .await?
+                .into_iter()
+                .filter(|s| seen.insert(s.table.clone()))
+                .collect();
+            let latest = storage.last_rows(database, &stable, series).await?;
+            result.extend(latest.into_iter().filter(|ts| {
+                ts.samples.iter().any(|sample| {
+                    sample.timestamp > now - LOOKBACK_DELTA_MS
+                        && !sample.value.map_or(false, is_stale)
+                })
+            }));
+        }
+    }
+    let metric_name = |ts: &TimeSeries| {
+        ts.labels
+            .iter()
+            .find(|label| label.name == "__name__")
+            .map(|label| label.value.clone())
+    };
+    result.sort_by_cached_key(metric_name);
+    Ok(result)
+}
@@ -1,6 +1,28 @@
+pub mod chunked;
+pub mod discovery;
+pub mod exposition;
+pub mod federate;
 mod reader;
+pub mod selector;
 pub mod types;
+pub mod validation;
 mod writer;
 
 pub use reader::read as prometheus_read;
+pub use reader::read_into as prometheus_read_into;
+pub(crate) use reader::TABLES_PER_QUERY;
+pub use reader::{
+    LabelCondition, LabelFilter, MetricFilter, PrometheusReaderError, QueryPlan, ReadOptions,
+    Series,
+};
 pub use types::*;
+
+/// Bits of the staleness marker, a NaN Prometheus tells apart from other NaNs.
+///
+/// Storage backends keep it apart from other NaNs, so both read back as written.
+pub const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+
+/// Whether a value is a staleness marker.
+pub fn is_stale(value: f64) -> bool {
+    value.to_bits() == STALE_NAN
+}
@@ -1,19 +1,21 @@
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use crate::prometheus::types::*;
-use crate::utils::tag_value_escape;
+use crate::utils::{tag_name_escape, tag_value_escape};
 
 use thiserror::Error;
 
-use libtaos::field::TaosQueryData;
-use libtaos::Taos;
+use crate::storage::{ErrorKind, Storage, StorageError};
 
+use futures::{Sink, SinkExt};
 use regex::Regex;
 
 #[derive(Error, Debug)]
 pub enum PrometheusReaderError {
-    #[error("TDengine connection error")]
-    TaosError(#[from] libtaos::Error),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
     #[error("Regex pattern error: {0}")]
     RegexError(#[from] regex::Error),
     #[error("unknown table name in query")]
@@ -25,6 +27,14 @@ pub enum PrometheusReaderError {
     },
     #[error("unsupported matcher type for metrics __name__: {0}")]
     UnsupportedMatcherTypeForMetrics(String),
+    #[error("response stream closed")]
+    StreamClosed,
+    #[error("query {query} matched more than {limit} series, use a more selective matcher")]
+    SeriesLimit { query: usize, limit: usize },
+    #[error("query {query} selected more than {limit} samples, use a shorter time range")]
+    SamplesLimit { query: usize, limit: usize },
+    #[error("remote read timed out after {0:?}")]
+    Timeout(Duration),
     // #[error(transparent)]
     // Other(#[from] anyhow::Error),
 }
@@ -33,6 +43,21 @@ use PrometheusReaderError::*;
 
 type Result<T> = std::result::Result<T, PrometheusReaderError>;
 
+impl PrometheusReaderError {
+    /// If the error is caused by query guardrails, retrying will not help.
+    pub fn is_guardrail(&self) -> bool {
+        matches!(self, SeriesLimit { .. } | SamplesLimit { .. } | Timeout(_))
+    }
+
+    /// If the error is transient, e.g. TDengine is unreachable, so that retrying may help.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Storage(err) => err.kind() != ErrorKind::BadData,
+            _ => false,
+        }
+    }
+}
+
 pub enum LabelFilter {
     Re(Regex),
     Nre(Regex),
@@ -59,17 +84,129 @@ impl ToString for MetricFilter {
 }
 pub type LabelFilters = BTreeMap<String, LabelFilter>;
 
-/// Return a tuple:
-/// 1. metric filter
-/// 2. condition sql string
-/// 3. regex label filters
-pub fn query_to_sql(query: &Query) -> Result<(MetricFilter, String, LabelFilters)> {
+/// Equality label matcher, pushed down to TDengine as tag condition.
+#[derive(Debug)]
+pub enum LabelCondition {
+    Eq(String),
+    Neq(String),
+}
+
+/// Plan of a remote read query, split into the two phases of [read]:
+///
+/// 1. resolve the matched series with tag-only conditions,
+/// 2. fetch `ts`/`value` columns of the matched child tables in the time range.
+#[derive(Debug)]
+pub struct QueryPlan {
+    /// Metric filter for `__name__`, resolved to super tables, `None` for all super tables.
+    pub metric_filter: Option<MetricFilter>,
+    /// Equality conditions by escaped tag name, pushed down to TDengine.
+    pub conditions: Vec<(String, LabelCondition)>,
+    /// Regex label filters by escaped tag name, applied once per series.
+    pub filters: LabelFilters,
+    pub start_timestamp_ms: i64,
+    pub end_timestamp_ms: i64,
+}
+
+impl QueryPlan {
+    /// Time range condition for sample fetching.
+    pub fn time_condition(&self) -> String {
+        format!(
+            "ts >= {} AND ts <= {}",
+            self.start_timestamp_ms, self.end_timestamp_ms
+        )
+    }
+
+    /// Tag conditions for a super table with the tag columns, or `None` if no series
+    /// of the super table could match.
+    ///
+    /// Per Prometheus semantics, a label not set in a series equals to an empty label,
+    /// so both missing tag columns and NULL tag values are treated as empty.
+    pub fn tag_conditions<S: AsRef<str>>(&self, tags: &[S]) -> Option<Vec<String>> {
+        let column = |name: &str| {
+            tags.iter()
+                .map(|tag| tag.as_ref())
+                .find(|tag| tag.replacen("t_", "", 1) == name)
+        };
+        let mut conditions = Vec::new();
+        for (name, condition) in &self.conditions {
+            match (condition, column(name)) {
+                (LabelCondition::Eq(value), None) => {
+                    if !value.is_empty() {
+                        return None;
+                    }
+                }
+                (LabelCondition::Eq(value), Some(column)) => {
+                    if value.is_empty() {
+                        // From the PromQL docs: "Label matchers that match
+                        // empty label values also select all time series that
+                        // do not have the specific label set at all."
+                        conditions.push(format!(
+                            "({column} = '' or {column} is null)",
+                            column = column
+                        ));
+                    } else {
+                        conditions.push(format!("{} = \"{}\"", column, value));
+                    }
+                }
+                (LabelCondition::Neq(value), None) => {
+                    if value.is_empty() {
+                        return None;
+                    }
+                }
+                (LabelCondition::Neq(value), Some(column)) => {
+                    if value.is_empty() {
+                        conditions.push(format!(
+                            "({column} != '' and {column} is not null)",
+                            column = column
+                        ));
+                    } else {
+                        conditions.push(format!(
+                            "({column} != \"{value}\" or {column} is null)",
+                            column = column,
+                            value = value
+                        ));
+                    }
+                }
+            }
+        }
+        for (name, filter) in &self.filters {
+            if column(name).is_none() && !filter.is_match("") {
+                return None;
+            }
+        }
+        Some(conditions)
+    }
+}
+
+impl std::fmt::Debug for LabelFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LabelFilter::Re(r) => write!(f, "=~{}", r),
+            LabelFilter::Nre(r) => write!(f, "!~{}", r),
+        }
+    }
+}
+
+impl LabelFilter {
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            LabelFilter::Re(pattern) => pattern.is_match(value),
+            LabelFilter::Nre(pattern) => !pattern.is_match(value),
+        }
+    }
+}
+
+/// Label regular expressions are fully anchored, as in Prometheus.
+fn anchored(pattern: &str) -> Result<Regex> {
+    Ok(Regex::new(&format!("^(?:{})$", pattern))?)
+}
+
+pub fn query_to_plan(query: &Query) -> Result<QueryPlan> {
     let mut metric_filter = None;
-    let mut matchers = Vec::new();
+    let mut conditions = Vec::new();
     let mut filters = LabelFilters::new();
     for matcher in &query.matchers {
         log::trace!("{:?}", matcher);
-        let escaped_name = tag_value_escape(&matcher.name);
         let value = tag_value_escape(&matcher.value);
         match matcher.name.as_str() {
             "__name__" => {
@@ -87,77 +224,87 @@ pub fn query_to_sql(query: &Query) -> Result<(MetricFilter, String, LabelFilters
                         metric_filter = Some(MetricFilter::Neq(value.clone()));
                     }
                     label_matcher::Type::Re => {
-                        metric_filter = Some(MetricFilter::Re(Regex::new(&value)?));
+                        metric_filter = Some(MetricFilter::Re(anchored(&value)?));
                     }
                     label_matcher::Type::Nre => {
-                        metric_filter = Some(MetricFilter::Nre(Regex::new(&value)?));
+                        metric_filter = Some(MetricFilter::Nre(anchored(&value)?));
                     }
                 }
             }
             name => {
+                let name = tag_name_escape(name);
                 match matcher.r#type() {
                     label_matcher::Type::Eq => {
-                        if value.is_empty() {
-                            // From the PromQL docs: "Label matchers that match
-                            // empty label values also select all time series that
-                            // do not have the specific label set at all."
-                            matchers.push(format!(
-                                "(t_{name} = '' or t_{name} is null)",
-                                name = escaped_name
-                            ));
-                        } else {
-                            matchers.push(format!("t_{} = \"{}\"", escaped_name, value));
-                        }
+                        conditions.push((name, LabelCondition::Eq(value)));
                     }
                     label_matcher::Type::Neq => {
-                        matchers.push(format!("t_{} != \"{}\"", escaped_name, value));
+                        conditions.push((name, LabelCondition::Neq(value)));
                     }
                     label_matcher::Type::Re => {
-                        filters.insert(name.to_string(), LabelFilter::Re(Regex::new(&value)?));
+                        filters.insert(name, LabelFilter::Re(anchored(&value)?));
                     }
                     label_matcher::Type::Nre => {
-                        filters.insert(name.to_string(), LabelFilter::Nre(Regex::new(&value)?));
+                        filters.insert(name, LabelFilter::Nre(anchored(&value)?));
                     }
                 }
             }
         }
     }
-    if metric_filter.is_none() {
-        return Err(NoneTableName);
-    }
-    let metric_filter = metric_filter.unwrap();
     log::debug!(
         "start time: {}, end time: {}, metric fiter: {:?}",
         query.start_timestamp_ms,
         query.end_timestamp_ms,
         metric_filter
     );
-    matchers.push(format!("ts >= {}", query.start_timestamp_ms));
-    matchers.push(format!("ts <= {}", query.end_timestamp_ms));
-    let sql = format!("WHERE {} ORDER BY ts", matchers.join(" AND "));
-    Ok((metric_filter, sql, filters))
+    Ok(QueryPlan {
+        metric_filter,
+        conditions,
+        filters,
+        start_timestamp_ms: query.start_timestamp_ms,
+        end_timestamp_ms: query.end_timestamp_ms,
+    })
 }
 
-async fn metric_filter_to_tables(
-    taos: &Taos,
+/// Return a tuple:
+/// 1. metric filter
+/// 2. condition sql string
+/// 3. regex label filters
+pub fn query_to_sql(query: &Query) -> Result<(MetricFilter, String, LabelFilters)> {
+    let plan = query_to_plan(query)?;
+    // all tags in the query are assumed to exist
+    let tags: Vec<_> = plan
+        .conditions
+        .iter()
+        .map(|(name, _)| name)
+        .chain(plan.filters.keys())
+        .map(|name| format!("t_{}", name))
+        .collect();
+    let mut conditions = plan.tag_conditions(tags.as_slice()).unwrap_or_default();
+    conditions.push(format!("ts >= {}", plan.start_timestamp_ms));
+    conditions.push(format!("ts <= {}", plan.end_timestamp_ms));
+    let sql = format!("WHERE {} ORDER BY ts", conditions.join(" AND "));
+    let metric_filter = plan.metric_filter.ok_or(NoneTableName)?;
+    Ok((metric_filter, sql, plan.filters))
+}
+
+/// Super tables matched by metric filter, all super tables if there's no filter.
+pub(crate) async fn metric_filter_to_tables(
+    storage: &dyn Storage,
     database: &str,
-    filter: &MetricFilter,
+    filter: Option<&MetricFilter>,
 ) -> Result<Vec<String>> {
     let mut names = Vec::new();
-    use itertools::Itertools;
     use MetricFilter::*;
 
-    if let Eq(name) = filter {
+    if let Some(Eq(name)) = filter {
         names.push(name.to_string());
         return Ok(names);
     }
-    taos.use_database(database).await?;
-    let TaosQueryData { rows, .. } = taos.query("show stables").await?;
-    let metrics = rows
-        .into_iter()
-        .filter_map(|a| a.into_iter().next())
-        .map(|field| format!("{}", field))
-        .collect_vec();
+    let metrics = storage.stables(database).await?;
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Ok(metrics),
+    };
     match filter {
         Neq(name) => {
             names = metrics
@@ -215,107 +362,317 @@ fn test_query_to_sql() {
     assert_eq!(sql, "WHERE t_mode = \"system\" AND t_monitor = \"example\" AND ts >= 1621511013040 AND ts <= 1621511073040 ORDER BY ts")
 }
 
-pub async fn read(taos: &Taos, database: &str, req: &ReadRequest) -> Result<ReadResponse> {
-    let ReadRequest { queries, .. } = &req;
-    // let mut results = Vec::new();
-    type Map = linked_hash_map::LinkedHashMap<Vec<Label>, Vec<Sample>>;
-    let mut results = Vec::new();
-    for query in queries {
-        let (metric_filter, cond, filters) = query_to_sql(query)?;
-        log::debug!("condition: {}", cond);
-        let mut timeseries = Vec::new();
-
-        for table_name in metric_filter_to_tables(taos, database, &metric_filter).await? {
-            let mut results_map = Map::default();
-            let sql = format!("select * from {}.{} {}", database, table_name, cond);
-            log::debug!("sql: {}", sql);
-            let TaosQueryData { column_meta, rows } = taos.query(&sql).await?;
-
-            // call regex filters
-            for row in rows {
-                //log::trace!("{:?}", row);
-                if !filters.is_empty()
-                    && !row.iter().zip(&column_meta).all(|(field, meta)| {
-                        if let Some(filter) = filters.get(&meta.name.as_str().replacen("t_", "", 1))
-                        {
-                            match filter {
-                                LabelFilter::Re(pattern) => {
-                                    field.as_string().map_or(false, |v| pattern.is_match(&v))
-                                }
-                                LabelFilter::Nre(pattern) => {
-                                    field.as_string().map_or(false, |v| !pattern.is_match(&v))
-                                }
-                            }
-                        } else {
-                            true
-                        }
-                    })
-                {
-                    continue;
-                }
+#[test]
+fn test_query_to_plan() {
+    let data = r#"
+         {
+          "start_timestamp_ms": 1621511013040,
+          "end_timestamp_ms": 1621511073040,
+          "matchers": [
+           { "name": "__name__", "value": "node_cpu_seconds_total" },
+           { "name": "mode", "value": "system" },
+           { "name": "cpu", "type": 2, "value": "0|1" }
+          ]
+         }"#;
+    let query: Query = serde_json::from_str(data).unwrap();
+    let plan = query_to_plan(&query).unwrap();
+    assert_eq!(
+        plan.tag_conditions(&["taghash", "t_mode", "t_cpu"])
+            .unwrap(),
+        vec!["t_mode = \"system\""]
+    );
+    // Missing `mode` tag never equals to `system`.
+    assert!(plan.tag_conditions(&["taghash", "t_cpu"]).is_none());
+    // Missing `cpu` tag is empty, which does not match `0|1`.
+    assert!(plan.tag_conditions(&["taghash", "t_mode"]).is_none());
+    assert_eq!(
+        plan.time_condition(),
+        "ts >= 1621511013040 AND ts <= 1621511073040"
+    );
+    assert!(plan.filters["cpu"].is_match("1"));
+    assert!(!plan.filters["cpu"].is_match("2"));
+    assert!(!plan.filters["cpu"].is_match("10"));
+}
 
-                let mut labels = Vec::new();
-                let mut sample = Sample::default();
-                labels.push(Label {
-                    name: "__name__".to_string(),
-                    value: table_name.to_string(),
-                });
-                for (field, meta) in row.into_iter().zip(&column_meta) {
-                    match meta.name.as_str() {
-                        "ts" => {
-                            sample.timestamp =
-                                field.as_raw_timestamp().expect("should be timestamp");
-                        }
-                        "value" => {
-                            sample.value = field.as_double().copied();
-                        }
-                        "taghash" => {}
-                        name => {
-                            if let Some(value) = field.as_string() {
-                                let label = Label {
-                                    name: name.replacen("t_", "", 1),
-                                    value: value.to_string(),
-                                };
-                                labels.push(label);
-                            }
-                        }
-                    }
-                }
+#[test]
+fn test_query_without_metric_name() {
+    let data = r#"
+         {
+          "start_timestamp_ms": 1621511013040,
+          "end_timestamp_ms": 1621511073040,
+          "matchers": [
+           { "name": "job", "value": "api" },
+           { "name": "env", "type": 1, "value": "dev" },
+           { "name": "zone", "value": "" }
+          ]
+         }"#;
+    let query: Query = serde_json::from_str(data).unwrap();
+    let plan = query_to_plan(&query).unwrap();
+    assert!(plan.metric_filter.is_none());
+    assert_eq!(
+        plan.tag_conditions(&["t_job", "t_env", "t_zone"]).unwrap(),
+        vec![
+            "t_job = \"api\"",
+            "(t_env != \"dev\" or t_env is null)",
+            "(t_zone = '' or t_zone is null)"
+        ]
+    );
+    // Missing `env` and `zone` tags are empty, which matches both `!= dev` and `= ""`.
+    assert_eq!(
+        plan.tag_conditions(&["t_job"]).unwrap(),
+        vec!["t_job = \"api\""]
+    );
+    assert!(plan.tag_conditions(&["t_env", "t_zone"]).is_none());
+}
 
-                if let Some(entry) = results_map.get_mut(&labels) {
-                    entry.push(sample);
-                } else {
-                    results_map.insert(labels, vec![sample]);
-                }
-            }
-            timeseries.extend(
-                results_map
-                    .into_iter()
-                    .map(|(labels, samples)| TimeSeries { labels, samples }),
-            );
+/// Number of child tables fetched in one sample query.
+pub(crate) const TABLES_PER_QUERY: usize = 100;
+
+/// A series resolved from super table tags.
+#[derive(Debug, Clone)]
+pub struct Series {
+    /// Child table name.
+    pub table: String,
+    /// Labels with `__name__`, sorted by name.
+    pub labels: Vec<Label>,
+}
+
+/// Tag column names of a super table, `None` if the super table does not exist.
+pub async fn stable_tags(
+    storage: &dyn Storage,
+    database: &str,
+    stable: &str,
+) -> Result<Option<Vec<String>>> {
+    Ok(storage
+        .describe(database, stable)
+        .await?
+        .map(|schema| schema.tags))
+}
+
+/// Options of remote read execution.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Max queries and super table scans running concurrently, each holds a pooled connection.
+    pub parallelism: usize,
+    /// Max series per query, `None` for unlimited.
+    pub max_series: Option<usize>,
+    /// Max samples per query, `None` for unlimited.
+    pub max_samples: Option<usize>,
+    /// Wall-clock timeout of a remote read request, `None` for unlimited.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            parallelism: 8,
+            max_series: None,
+            max_samples: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Series and samples counters of a query, shared by its concurrent scans.
+#[derive(Debug, Default)]
+struct QueryUsage {
+    series: AtomicUsize,
+    samples: AtomicUsize,
+}
+
+impl QueryUsage {
+    fn add_series(&self, query: usize, n: usize, options: &ReadOptions) -> Result<()> {
+        let total = self.series.fetch_add(n, Ordering::Relaxed) + n;
+        match options.max_series {
+            Some(limit) if total > limit => Err(SeriesLimit { query, limit }),
+            _ => Ok(()),
         }
-        results.push(QueryResult { timeseries });
     }
 
-    for result in results.iter() {
-        log::debug!("total series: {}", result.timeseries.len());
-        for ts in &result.timeseries {
-            use itertools::Itertools;
-            let labels = ts
-                .labels
-                .iter()
-                .map(|label| format!("{}={}", label.name, label.value))
-                .join(",");
-            log::debug!("labels: {}", labels);
+    fn add_samples(&self, query: usize, n: usize, options: &ReadOptions) -> Result<()> {
+        let total = self.samples.fetch_add(n, Ordering::Relaxed) + n;
+        match options.max_samples {
+            Some(limit) if total > limit => Err(SamplesLimit { query, limit }),
+            _ => Ok(()),
         }
     }
 
+    /// Series left before the limit, plus one to tell an exceeded limit apart.
+    fn series_left(&self, options: &ReadOptions) -> Option<usize> {
+        let used = self.series.load(Ordering::Relaxed);
+        options.max_series.map(|max| max.saturating_sub(used) + 1)
+    }
+
+    /// Samples left before the limit, plus one to tell an exceeded limit apart.
+    fn samples_left(&self, options: &ReadOptions) -> Option<usize> {
+        let used = self.samples.load(Ordering::Relaxed);
+        options.max_samples.map(|max| max.saturating_sub(used) + 1)
+    }
+}
+
+/// Resolve and fetch all series of a super table, storage queries are limited to what is
+/// left of the limits of the query.
+async fn read_stable(
+    storage: &dyn Storage,
+    database: &str,
+    stable: &str,
+    plan: &QueryPlan,
+    (query, usage): (usize, &QueryUsage),
+    options: &ReadOptions,
+) -> Result<Vec<TimeSeries>> {
+    use itertools::Itertools;
+    let series = storage
+        .resolve_series(database, stable, plan, usage.series_left(options))
+        .await?;
+    log::debug!(
+        "resolved {} series in {}.{}",
+        series.len(),
+        database,
+        stable
+    );
+    usage.add_series(query, series.len(), options)?;
+    let mut timeseries = Vec::with_capacity(series.len());
+    let batches = series
+        .into_iter()
+        .chunks(TABLES_PER_QUERY)
+        .into_iter()
+        .map(|batch| batch.collect_vec())
+        .collect_vec();
+    for batch in batches {
+        let limit = usage.samples_left(options);
+        let batch = storage
+            .fetch_samples(database, stable, batch, plan, limit)
+            .await?;
+        let samples = batch.iter().map(|ts| ts.samples.len()).sum();
+        usage.add_samples(query, samples, options)?;
+        timeseries.extend(batch);
+    }
+    Ok(timeseries)
+}
+
+/// Read series of each query into a sink, as `(query index, series)` pairs in query order.
+///
+/// Queries and super table scans run concurrently up to [ReadOptions::parallelism], while
+/// series are sent in the original query order as soon as they are fetched, which lets
+/// callers stream responses.
+///
+/// Reading stops with an error once a query exceeds the series or samples limit, or
+/// the whole request exceeds the timeout.
+pub async fn read_into<S>(
+    storage: &dyn Storage,
+    database: &str,
+    req: &ReadRequest,
+    options: &ReadOptions,
+    sink: S,
+) -> Result<()>
+where
+    S: Sink<(usize, TimeSeries)>,
+{
+    match options.timeout {
+        Some(timeout) => tokio::time::timeout(
+            timeout,
+            read_into_sink(storage, database, req, options, sink),
+        )
+        .await
+        .map_err(|_| Timeout(timeout))?,
+        None => read_into_sink(storage, database, req, options, sink).await,
+    }
+}
+
+async fn read_into_sink<S>(
+    storage: &dyn Storage,
+    database: &str,
+    req: &ReadRequest,
+    options: &ReadOptions,
+    sink: S,
+) -> Result<()>
+where
+    S: Sink<(usize, TimeSeries)>,
+{
+    use futures::stream::{self, StreamExt};
+    use itertools::Itertools;
+    futures::pin_mut!(sink);
+    let plans = req
+        .queries
+        .iter()
+        .map(query_to_plan)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut scans = Vec::new();
+    for (index, plan) in plans.iter().enumerate() {
+        log::debug!("plan of query {}: {:?}", index, plan);
+        for stable in
+            metric_filter_to_tables(storage, database, plan.metric_filter.as_ref()).await?
+        {
+            scans.push((index, plan, stable));
+        }
+    }
+    log::debug!(
+        "{} super table scans for {} queries",
+        scans.len(),
+        plans.len()
+    );
+
+    let usages: Vec<QueryUsage> = plans.iter().map(|_| QueryUsage::default()).collect();
+    let usages = &usages;
+    let mut results = stream::iter(scans)
+        .map(|(index, plan, stable)| async move {
+            read_stable(
+                storage,
+                database,
+                &stable,
+                plan,
+                (index, &usages[index]),
+                options,
+            )
+            .await
+            .map(|timeseries| (index, timeseries))
+        })
+        .buffered(options.parallelism.max(1));
+
+    let mut total = 0;
+    while let Some(res) = results.next().await {
+        let (index, timeseries) = res?;
+        for ts in timeseries {
+            log::trace!(
+                "labels: {}",
+                ts.labels
+                    .iter()
+                    .map(|label| format!("{}={}", label.name, label.value))
+                    .join(",")
+            );
+            total += 1;
+            sink.as_mut()
+                .send((index, ts))
+                .await
+                .map_err(|_| StreamClosed)?;
+        }
+    }
+    log::debug!("total series: {}", total);
+    Ok(())
+}
+
+pub async fn read(
+    storage: &dyn Storage,
+    database: &str,
+    req: &ReadRequest,
+    options: &ReadOptions,
+) -> Result<ReadResponse> {
+    let mut series: Vec<(usize, TimeSeries)> = Vec::new();
+    read_into(storage, database, req, options, &mut series).await?;
+    let mut results = vec![QueryResult::default(); req.queries.len()];
+    for (index, ts) in series {
+        results[index].timeseries.push(ts);
+    }
     Ok(ReadResponse { results })
 }
 
+#[cfg(feature = "native")]
 #[tokio::test]
 async fn test_read_request() {
     let taos = crate::test::taos().unwrap();
+    let storage = crate::storage::TDengine::native(&crate::test::native_options());
+    let options = ReadOptions::default();
     taos.exec("drop database if exists prom_read_0xabc")
         .await
         .unwrap();
@@ -379,7 +736,9 @@ async fn test_read_request() {
        }"#;
 
     let req: ReadRequest = serde_json::from_str(data).unwrap();
-    let res = read(&taos, "prom_read_0xabc", &req).await.unwrap();
+    let res = read(&storage, "prom_read_0xabc", &req, &options)
+        .await
+        .unwrap();
     println!("{:?}", res);
     assert_eq!(res.results[0].timeseries.len(), 2);
 
@@ -410,7 +769,9 @@ async fn test_read_request() {
        }"#;
 
     let req: ReadRequest = serde_json::from_str(data).unwrap();
-    let res = read(&taos, "prom_read_0xabc", &req).await.unwrap();
+    let res = read(&storage, "prom_read_0xabc", &req, &options)
+        .await
+        .unwrap();
     println!("{:?}", res);
     assert_eq!(res.results[0].timeseries.len(), 2);
 
@@ -441,7 +802,9 @@ async fn test_read_request() {
        }"#;
 
     let req: ReadRequest = serde_json::from_str(data).unwrap();
-    let res = read(&taos, "prom_read_0xabc", &req).await.unwrap();
+    let res = read(&storage, "prom_read_0xabc", &req, &options)
+        .await
+        .unwrap();
     println!("{:?}", res);
     assert_eq!(res.results[0].timeseries.len(), 1);
 
@@ -472,7 +835,9 @@ async fn test_read_request() {
        }"#;
 
     let req: ReadRequest = serde_json::from_str(data).unwrap();
-    let res = read(&taos, "prom_read_0xabc", &req).await.unwrap();
+    let res = read(&storage, "prom_read_0xabc", &req, &options)
+        .await
+        .unwrap();
     println!("{:?}", res);
     assert_eq!(res.results[0].timeseries.len(), 1);
 
@@ -486,7 +851,7 @@ async fn test_read_request() {
             {
              "name": "__name__",
              "type": 2,
-             "value": "tb"
+             "value": "stb.*"
             }
           ],
           "hints": {
@@ -499,8 +864,87 @@ async fn test_read_request() {
        }"#;
 
     let req: ReadRequest = serde_json::from_str(data).unwrap();
-    let res = read(&taos, "prom_read_0xabc", &req).await.unwrap();
+    let res = read(&storage, "prom_read_0xabc", &req, &options)
+        .await
+        .unwrap();
     println!("{:?}", res);
     assert_eq!(res.results[0].timeseries.len(), 5);
     taos.exec("drop database prom_read_0xabc").await.unwrap();
 }
+
+#[tokio::test]
+async fn test_read_limits() {
+    use crate::storage::{Memory, Row};
+
+    let storage = Memory::default();
+    storage.create_database("prom").await.unwrap();
+    storage
+        .create_stable("prom", "up", &["t_job".to_string()])
+        .await
+        .unwrap();
+    let mut rows = Vec::new();
+    for table in ["md5_a", "md5_b", "md5_c"] {
+        let tags = [
+            ("taghash".to_string(), table.to_string()),
+            ("t_job".to_string(), table.to_string()),
+        ];
+        storage
+            .create_table("prom", table, "up", &tags)
+            .await
+            .unwrap();
+        rows.extend((1..=3).map(|i| Row {
+            table,
+            timestamp: i * 1000,
+            value: Some(1.),
+        }));
+    }
+    storage.insert("prom", &rows).await.unwrap();
+    let req = ReadRequest {
+        queries: vec![Query {
+            start_timestamp_ms: 0,
+            end_timestamp_ms: 3000,
+            matchers: vec![LabelMatcher {
+                r#type: label_matcher::Type::Eq as i32,
+                name: "__name__".to_string(),
+                value: "up".to_string(),
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let options = ReadOptions {
+        max_series: Some(3),
+        max_samples: Some(9),
+        ..Default::default()
+    };
+    let res = read(&storage, "prom", &req, &options).await.unwrap();
+    assert_eq!(res.results[0].timeseries.len(), 3);
+    let options = ReadOptions {
+        max_series: Some(2),
+        ..Default::default()
+    };
+    assert!(matches!(
+        read(&storage, "prom", &req, &options).await,
+        Err(SeriesLimit { query: 0, limit: 2 })
+    ));
+    let options = ReadOptions {
+        max_samples: Some(8),
+        ..Default::default()
+    };
+    assert!(matches!(
+        read(&storage, "prom", &req, &options).await,
+        Err(SamplesLimit { query: 0, limit: 8 })
+    ));
+
+    // a sink that is never read, e.g. a stalled client
+    let (sink, _receiver) = futures::channel::mpsc::channel(0);
+    let options = ReadOptions {
+        timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    assert!(matches!(
+        read_into(&storage, "prom", &req, &options, sink).await,
+        Err(Timeout(_))
+    ));
+}
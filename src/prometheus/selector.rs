@@ -0,0 +1,240 @@
+//! Parser of PromQL series selectors, as used by `match[]` parameters of the HTTP API,
+//! e.g. `up{job="api", instance=~"10\\..*"}`.
+use thiserror::Error;
+
+use crate::prometheus::types::*;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SelectorError {
+    #[error("unexpected end of selector")]
+    UnexpectedEnd,
+    #[error("unexpected character {0:?} at position {1}")]
+    UnexpectedChar(char, usize),
+    #[error("invalid escape sequence in string at position {0}")]
+    InvalidEscape(usize),
+    #[error("vector selector must contain at least one non-empty matcher")]
+    EmptySelector,
+}
+
+type Result<T> = std::result::Result<T, SelectorError>;
+
+pub fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':'
+}
+
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+/// Parse a quoted string literal at the start of `input`, return the unquoted string
+/// and the consumed length in bytes.
+///
+/// Double and single quoted strings support Go escape sequences, backtick quoted
+/// strings are raw.
+pub fn parse_string(input: &str, offset: usize) -> Result<(String, usize)> {
+    let mut chars = input.char_indices();
+    let quote = match chars.next() {
+        Some((_, c @ '"')) | Some((_, c @ '\'')) | Some((_, c @ '`')) => c,
+        Some((i, c)) => return Err(SelectorError::UnexpectedChar(c, offset + i)),
+        None => return Err(SelectorError::UnexpectedEnd),
+    };
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((value, i + 1)),
+            '\\' if quote != '`' => {
+                let (_, escaped) = chars.next().ok_or(SelectorError::UnexpectedEnd)?;
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    'a' => value.push('\u{07}'),
+                    'b' => value.push('\u{08}'),
+                    'f' => value.push('\u{0c}'),
+                    'v' => value.push('\u{0b}'),
+                    '\\' | '"' | '\'' => value.push(escaped),
+                    'x' | 'u' | 'U' => {
+                        let len = match escaped {
+                            'x' => 2,
+                            'u' => 4,
+                            _ => 8,
+                        };
+                        let hex: String = (0..len)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == len)
+                            .and_then(std::char::from_u32)
+                            .ok_or(SelectorError::InvalidEscape(offset + i))?;
+                        value.push(c);
+                    }
+                    _ => return Err(SelectorError::InvalidEscape(offset + i)),
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    Err(SelectorError::UnexpectedEnd)
+}
+
+struct Cursor<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn expect_char(&mut self) -> Result<char> {
+        let c = self.peek().ok_or(SelectorError::UnexpectedEnd)?;
+        self.pos += c.len_utf8();
+        Ok(c)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(c) if is_name_start(c) => {}
+            Some(c) => return Err(SelectorError::UnexpectedChar(c, self.pos)),
+            None => return Err(SelectorError::UnexpectedEnd),
+        }
+        let len = self
+            .rest()
+            .find(|c: char| !is_name_char(c))
+            .unwrap_or_else(|| self.rest().len());
+        let name = self.rest()[..len].to_string();
+        self.pos += len;
+        Ok(name)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let (value, len) = parse_string(self.rest(), self.pos)?;
+        self.pos += len;
+        Ok(value)
+    }
+
+    fn match_type(&mut self) -> Result<label_matcher::Type> {
+        let pos = self.pos;
+        let t = match (self.expect_char()?, self.peek()) {
+            ('=', Some('~')) => label_matcher::Type::Re,
+            ('=', _) => return Ok(label_matcher::Type::Eq),
+            ('!', Some('=')) => label_matcher::Type::Neq,
+            ('!', Some('~')) => label_matcher::Type::Nre,
+            (c, _) => return Err(SelectorError::UnexpectedChar(c, pos)),
+        };
+        self.pos += 1;
+        Ok(t)
+    }
+}
+
+/// Parse label matchers in braces, the cursor is right after `{`.
+fn parse_matchers(cursor: &mut Cursor) -> Result<Vec<LabelMatcher>> {
+    let mut matchers = Vec::new();
+    loop {
+        cursor.skip_whitespace();
+        if cursor.peek() == Some('}') {
+            cursor.pos += 1;
+            return Ok(matchers);
+        }
+        let name = cursor.name()?;
+        cursor.skip_whitespace();
+        let r#type = cursor.match_type()?;
+        cursor.skip_whitespace();
+        let value = cursor.string()?;
+        matchers.push(LabelMatcher {
+            r#type: r#type as i32,
+            name,
+            value,
+        });
+        cursor.skip_whitespace();
+        match cursor.expect_char()? {
+            ',' => continue,
+            '}' => return Ok(matchers),
+            c => return Err(SelectorError::UnexpectedChar(c, cursor.pos - c.len_utf8())),
+        }
+    }
+}
+
+/// If all matchers match empty labels, such a selector would select all series.
+pub fn is_empty_selector(matchers: &[LabelMatcher]) -> bool {
+    let matches_empty = |m: &LabelMatcher| match m.r#type() {
+        label_matcher::Type::Eq => m.value.is_empty(),
+        label_matcher::Type::Neq => !m.value.is_empty(),
+        label_matcher::Type::Re => {
+            regex::Regex::new(&format!("^(?:{})$", m.value)).map_or(false, |re| re.is_match(""))
+        }
+        label_matcher::Type::Nre => {
+            regex::Regex::new(&format!("^(?:{})$", m.value)).map_or(false, |re| !re.is_match(""))
+        }
+    };
+    matchers.iter().all(matches_empty)
+}
+
+/// Parse a series selector into label matchers, the metric name becomes a `__name__` matcher.
+pub fn parse_selector(input: &str) -> Result<Vec<LabelMatcher>> {
+    let mut cursor = Cursor { input, pos: 0 };
+    let mut matchers = Vec::new();
+    cursor.skip_whitespace();
+    if cursor.peek().map_or(false, is_name_start) {
+        matchers.push(LabelMatcher {
+            r#type: label_matcher::Type::Eq as i32,
+            name: "__name__".to_string(),
+            value: cursor.name()?,
+        });
+        cursor.skip_whitespace();
+    }
+    if cursor.peek() == Some('{') {
+        cursor.pos += 1;
+        matchers.extend(parse_matchers(&mut cursor)?);
+        cursor.skip_whitespace();
+    }
+    if let Some(c) = cursor.peek() {
+        return Err(SelectorError::UnexpectedChar(c, cursor.pos));
+    }
+    if is_empty_selector(&matchers) {
+        return Err(SelectorError::EmptySelector);
+    }
+    Ok(matchers)
+}
+
+#[test]
+fn test_parse_selector() {
+    let matchers = parse_selector(r#"up{job="api", instance=~"10\\..*",env!="",}"#).unwrap();
+    let matchers: Vec<_> = matchers
+        .iter()
+        .map(|m| (m.name.as_str(), m.r#type(), m.value.as_str()))
+        .collect();
+    assert_eq!(
+        matchers,
+        vec![
+            ("__name__", label_matcher::Type::Eq, "up"),
+            ("job", label_matcher::Type::Eq, "api"),
+            ("instance", label_matcher::Type::Re, "10\\..*"),
+            ("env", label_matcher::Type::Neq, ""),
+        ]
+    );
+
+    let matchers = parse_selector(r#"{__name__=~'node_.*', mode!~`idle|iowait`}"#).unwrap();
+    assert_eq!(matchers.len(), 2);
+    assert_eq!(matchers[1].r#type(), label_matcher::Type::Nre);
+    assert_eq!(matchers[1].value, "idle|iowait");
+
+    assert_eq!(
+        parse_selector(r#"{job=""}"#),
+        Err(SelectorError::EmptySelector)
+    );
+    assert!(parse_selector(r#"up{job="api""#).is_err());
+    assert!(parse_selector(r#"up{job="api"} offset"#).is_err());
+}
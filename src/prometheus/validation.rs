@@ -0,0 +1,237 @@
+//! Per-series validation of remote write requests, and of series converted from the
+//! other ingestion protocols.
+//!
+//! Requests are decoded with label names and values as bytes, so that a series with
+//! invalid UTF-8 is rejected alone instead of failing the whole request. Series without
+//! a single `__name__` label, with an empty or invalid metric name, or with duplicate or
+//! empty label names, are rejected too, the other series of the request are written.
+//! Label names are duplicate if they are stored in the same tag column, e.g. `Foo` and
+//! `foo`, or `a.b` and `a_b`.
+use std::collections::{BTreeMap, HashSet};
+
+use prost::Message;
+use serde::Serialize;
+
+use crate::prometheus::types::*;
+use crate::utils::tag_name_escape;
+
+/// Remote write request as received, before validation.
+#[derive(Clone, PartialEq, Message)]
+pub struct RawWriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<RawTimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RawTimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<RawLabel>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RawLabel {
+    #[prost(bytes = "vec", tag = "1")]
+    pub name: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+/// Reason for rejecting a series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    MissingMetricName,
+    DuplicateMetricName,
+    EmptyMetricName,
+    InvalidMetricName,
+    DuplicateLabelName,
+    EmptyLabelName,
+    InvalidUtf8,
+}
+
+impl Rejection {
+    /// Reason reported in responses and as the `reason` metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::MissingMetricName => "missing_metric_name",
+            Rejection::DuplicateMetricName => "duplicate_metric_name",
+            Rejection::EmptyMetricName => "empty_metric_name",
+            Rejection::InvalidMetricName => "invalid_metric_name",
+            Rejection::DuplicateLabelName => "duplicate_label_name",
+            Rejection::EmptyLabelName => "empty_label_name",
+            Rejection::InvalidUtf8 => "invalid_utf8",
+        }
+    }
+}
+
+/// Summary of a validated request, returned in the response body.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// Rejected series per reason.
+    pub reasons: BTreeMap<&'static str, usize>,
+}
+
+impl ValidationReport {
+    fn reject(&mut self, rejection: Rejection) {
+        self.rejected += 1;
+        *self.reasons.entry(rejection.as_str()).or_default() += 1;
+    }
+}
+
+/// Whether a metric name matches `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Validate the labels of a series.
+pub fn validate_series(series: RawTimeSeries) -> Result<TimeSeries, Rejection> {
+    let mut names = HashSet::with_capacity(series.labels.len());
+    let mut labels = Vec::with_capacity(series.labels.len());
+    let mut metric_names = 0;
+    for label in series.labels {
+        if label.name.is_empty() {
+            return Err(Rejection::EmptyLabelName);
+        }
+        let name = String::from_utf8(label.name).map_err(|_| Rejection::InvalidUtf8)?;
+        let value = String::from_utf8(label.value).map_err(|_| Rejection::InvalidUtf8)?;
+        if name == "__name__" {
+            if value.is_empty() {
+                return Err(Rejection::EmptyMetricName);
+            }
+            if !is_metric_name(&value) {
+                return Err(Rejection::InvalidMetricName);
+            }
+            metric_names += 1;
+        } else if !names.insert(tag_name_escape(&name)) {
+            return Err(Rejection::DuplicateLabelName);
+        }
+        labels.push(Label { name, value });
+    }
+    match metric_names {
+        0 => Err(Rejection::MissingMetricName),
+        1 => Ok(TimeSeries {
+            labels,
+            samples: series.samples,
+        }),
+        _ => Err(Rejection::DuplicateMetricName),
+    }
+}
+
+/// Keep the valid series only.
+fn validate(series: impl IntoIterator<Item = RawTimeSeries>) -> (WriteRequest, ValidationReport) {
+    let mut report = ValidationReport::default();
+    let mut request = WriteRequest::default();
+    for series in series {
+        match validate_series(series) {
+            Ok(series) => {
+                report.accepted += 1;
+                request.timeseries.push(series);
+            }
+            Err(rejection) => report.reject(rejection),
+        }
+    }
+    (request, report)
+}
+
+/// Decode a remote write request, keeping the valid series only.
+pub fn decode_write_request(
+    buf: &[u8],
+) -> Result<(WriteRequest, ValidationReport), prost::DecodeError> {
+    let raw = RawWriteRequest::decode(buf)?;
+    Ok(validate(raw.timeseries))
+}
+
+/// Validate series converted from other protocols, keeping the valid series only.
+pub fn validate_write_request(request: WriteRequest) -> (WriteRequest, ValidationReport) {
+    validate(request.timeseries.into_iter().map(|ts| {
+        RawTimeSeries {
+            labels: ts
+                .labels
+                .into_iter()
+                .map(|label| RawLabel {
+                    name: label.name.into_bytes(),
+                    value: label.value.into_bytes(),
+                })
+                .collect(),
+            samples: ts.samples,
+        }
+    }))
+}
+
+#[test]
+fn test_decode_write_request() {
+    let label = |name: &[u8], value: &[u8]| RawLabel {
+        name: name.to_vec(),
+        value: value.to_vec(),
+    };
+    let series = |labels: Vec<RawLabel>| RawTimeSeries {
+        labels,
+        samples: vec![Sample {
+            value: Some(1.),
+            timestamp: 1000,
+        }],
+    };
+    let raw = RawWriteRequest {
+        timeseries: vec![
+            series(vec![label(b"__name__", b"up"), label(b"job", b"node")]),
+            series(vec![label(b"job", b"node")]),
+            series(vec![label(b"__name__", b"up"), label(b"__name__", b"down")]),
+            series(vec![
+                label(b"__name__", b"up"),
+                label(b"a", b"1"),
+                label(b"a", b"2"),
+            ]),
+            series(vec![label(b"__name__", b"up"), label(b"", b"1")]),
+            series(vec![label(b"__name__", b"up"), label(b"job", b"\xff")]),
+            series(vec![label(b"__name__", b""), label(b"job", b"node")]),
+            series(vec![label(b"__name__", b"0up")]),
+            series(vec![label(b"__name__", b"node.up")]),
+            series(vec![label(b"__name__", b"job:up_total")]),
+            series(vec![
+                label(b"__name__", b"up"),
+                label(b"Job", b"1"),
+                label(b"job", b"2"),
+            ]),
+            series(vec![
+                label(b"__name__", b"up"),
+                label(b"a.b", b"1"),
+                label(b"a_b", b"2"),
+            ]),
+        ],
+    };
+    let (request, report) = decode_write_request(&raw.encode_to_vec()).unwrap();
+    assert_eq!(request.timeseries.len(), 2);
+    assert_eq!(request.timeseries[0].labels[1].value, "node");
+    assert_eq!(request.timeseries[1].labels[0].value, "job:up_total");
+    assert_eq!(report.accepted, 2);
+    assert_eq!(report.rejected, 10);
+    assert_eq!(
+        report.reasons.into_iter().collect::<Vec<_>>(),
+        vec![
+            ("duplicate_label_name", 3),
+            ("duplicate_metric_name", 1),
+            ("empty_label_name", 1),
+            ("empty_metric_name", 1),
+            ("invalid_metric_name", 2),
+            ("invalid_utf8", 1),
+            ("missing_metric_name", 1),
+        ]
+    );
+    // series converted from other protocols
+    let mut converted = request.clone();
+    let mut duplicate = converted.timeseries[0].clone();
+    duplicate.labels.push(Label {
+        name: "Job".to_string(),
+        value: "node".to_string(),
+    });
+    converted.timeseries.push(duplicate);
+    let (converted, report) = validate_write_request(converted);
+    assert_eq!(converted, request);
+    assert_eq!(report.accepted, 2);
+    assert_eq!(report.reasons.get("duplicate_label_name"), Some(&1));
+}
@@ -0,0 +1,225 @@
+use crate::prometheus::types::LabelMatcher;
+
+/// Instant vector selector, e.g. `http_requests_total{job="api"} offset 5m`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSelector {
+    /// Label matchers, including `__name__` if the metric name is given.
+    pub matchers: Vec<LabelMatcher>,
+    /// Offset in milliseconds.
+    pub offset: i64,
+    /// Index of the selector in the expression, used to look up fetched series.
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Atan2,
+    Eql,
+    Neq,
+    Gtr,
+    Lss,
+    Gte,
+    Lte,
+    And,
+    Or,
+    Unless,
+}
+
+impl BinaryOp {
+    pub fn from_token(token: &str) -> Option<Self> {
+        use BinaryOp::*;
+        Some(match token {
+            "+" => Add,
+            "-" => Sub,
+            "*" => Mul,
+            "/" => Div,
+            "%" => Mod,
+            "^" => Pow,
+            "atan2" => Atan2,
+            "==" => Eql,
+            "!=" => Neq,
+            ">" => Gtr,
+            "<" => Lss,
+            ">=" => Gte,
+            "<=" => Lte,
+            "and" => And,
+            "or" => Or,
+            "unless" => Unless,
+            _ => return None,
+        })
+    }
+
+    /// Binding power, higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        use BinaryOp::*;
+        match self {
+            Or => 1,
+            And | Unless => 2,
+            Eql | Neq | Gtr | Lss | Gte | Lte => 3,
+            Add | Sub => 4,
+            Mul | Div | Mod | Atan2 => 5,
+            Pow => 6,
+        }
+    }
+
+    pub fn is_right_associative(self) -> bool {
+        self == BinaryOp::Pow
+    }
+
+    pub fn is_comparison(self) -> bool {
+        use BinaryOp::*;
+        matches!(self, Eql | Neq | Gtr | Lss | Gte | Lte)
+    }
+
+    pub fn is_set(self) -> bool {
+        use BinaryOp::*;
+        matches!(self, And | Or | Unless)
+    }
+}
+
+/// Vector matching cardinality.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cardinality {
+    OneToOne,
+    /// `group_left(labels)`, labels are copied from the "one" side.
+    ManyToOne(Vec<String>),
+    /// `group_right(labels)`, labels are copied from the "one" side.
+    OneToMany(Vec<String>),
+}
+
+/// Modifiers of binary operations: `bool`, `on`/`ignoring` and `group_left`/`group_right`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryModifier {
+    pub return_bool: bool,
+    /// `Some((true, labels))` for `on(labels)`, `Some((false, labels))` for `ignoring(labels)`.
+    pub matching: Option<(bool, Vec<String>)>,
+    pub cardinality: Cardinality,
+}
+
+impl Default for BinaryModifier {
+    fn default() -> Self {
+        BinaryModifier {
+            return_bool: false,
+            matching: None,
+            cardinality: Cardinality::OneToOne,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Group,
+    Stddev,
+    Stdvar,
+    Topk,
+    Bottomk,
+    Quantile,
+}
+
+impl AggregateOp {
+    pub fn from_name(name: &str) -> Option<Self> {
+        use AggregateOp::*;
+        Some(match name {
+            "sum" => Sum,
+            "avg" => Avg,
+            "min" => Min,
+            "max" => Max,
+            "count" => Count,
+            "group" => Group,
+            "stddev" => Stddev,
+            "stdvar" => Stdvar,
+            "topk" => Topk,
+            "bottomk" => Bottomk,
+            "quantile" => Quantile,
+            _ => return None,
+        })
+    }
+
+    pub fn has_param(self) -> bool {
+        use AggregateOp::*;
+        matches!(self, Topk | Bottomk | Quantile)
+    }
+}
+
+/// Grouping of aggregations, `by` or `without` some labels.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Grouping {
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+impl Default for Grouping {
+    fn default() -> Self {
+        Grouping::By(Vec::new())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    String(String),
+    VectorSelector(VectorSelector),
+    /// Range vector selector with range in milliseconds.
+    MatrixSelector(VectorSelector, i64),
+    Negate(Box<Expr>),
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        modifier: BinaryModifier,
+    },
+    Call {
+        func: String,
+        args: Vec<Expr>,
+    },
+    Aggregate {
+        op: AggregateOp,
+        expr: Box<Expr>,
+        param: Option<Box<Expr>>,
+        grouping: Grouping,
+    },
+}
+
+impl Expr {
+    /// All vector selectors in the expression with their ranges, `0` for instant selectors.
+    pub fn selectors(&self) -> Vec<(&VectorSelector, i64)> {
+        let mut selectors = Vec::new();
+        self.collect_selectors(&mut selectors);
+        selectors
+    }
+
+    fn collect_selectors<'a>(&'a self, selectors: &mut Vec<(&'a VectorSelector, i64)>) {
+        match self {
+            Expr::Number(_) | Expr::String(_) => {}
+            Expr::VectorSelector(selector) => selectors.push((selector, 0)),
+            Expr::MatrixSelector(selector, range) => selectors.push((selector, *range)),
+            Expr::Negate(expr) => expr.collect_selectors(selectors),
+            Expr::Binary { lhs, rhs, .. } => {
+                lhs.collect_selectors(selectors);
+                rhs.collect_selectors(selectors);
+            }
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    arg.collect_selectors(selectors);
+                }
+            }
+            Expr::Aggregate { expr, param, .. } => {
+                if let Some(param) = param {
+                    param.collect_selectors(selectors);
+                }
+                expr.collect_selectors(selectors);
+            }
+        }
+    }
+}
@@ -0,0 +1,995 @@
+//! Evaluation of PromQL expressions over series fetched with remote read queries.
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde_json::json;
+
+use crate::prometheus::exposition::format_value;
+use crate::prometheus::is_stale;
+use crate::promql::ast::*;
+use crate::promql::PromqlError;
+
+type Result<T> = std::result::Result<T, PromqlError>;
+
+/// Default lookback delta of instant vector selectors, 5 minutes.
+pub const LOOKBACK_DELTA_MS: i64 = 5 * 60 * 1000;
+
+pub type Labels = BTreeMap<String, String>;
+
+/// An element of an instant vector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSample {
+    pub labels: Labels,
+    pub value: f64,
+}
+
+/// A series of a range vector, points are `(timestamp_ms, value)` ordered by time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeSeries {
+    pub labels: Labels,
+    pub points: Vec<(i64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    String(String),
+    Vector(Vec<VectorSample>),
+    Matrix(Vec<RangeSeries>),
+}
+
+fn format_point(timestamp: i64, value: f64) -> serde_json::Value {
+    json!([timestamp as f64 / 1000., format_value(value)])
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Scalar(_) => "scalar",
+            Value::String(_) => "string",
+            Value::Vector(_) => "vector",
+            Value::Matrix(_) => "matrix",
+        }
+    }
+
+    /// Prometheus HTTP API query result at `timestamp` (ignored by matrix results).
+    pub fn to_json(&self, timestamp: i64) -> serde_json::Value {
+        let result = match self {
+            Value::Scalar(v) => format_point(timestamp, *v),
+            Value::String(s) => json!([timestamp as f64 / 1000., s]),
+            Value::Vector(samples) => samples
+                .iter()
+                .map(|s| json!({ "metric": s.labels, "value": format_point(timestamp, s.value) }))
+                .collect(),
+            Value::Matrix(series) => series
+                .iter()
+                .map(|s| {
+                    let values: Vec<_> =
+                        s.points.iter().map(|(t, v)| format_point(*t, *v)).collect();
+                    json!({ "metric": s.labels, "values": values })
+                })
+                .collect(),
+        };
+        json!({ "resultType": self.type_name(), "result": result })
+    }
+}
+
+fn eval_error<T>(message: impl Into<String>) -> Result<T> {
+    Err(PromqlError::Eval(message.into()))
+}
+
+fn drop_metric_name(mut labels: Labels) -> Labels {
+    labels.remove("__name__");
+    labels
+}
+
+/// Evaluates expressions at single timestamps, selectors look up series by their index.
+pub struct Evaluator<'a> {
+    pub series: &'a [Vec<RangeSeries>],
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn eval(&self, expr: &Expr, t: i64) -> Result<Value> {
+        match expr {
+            Expr::Number(v) => Ok(Value::Scalar(*v)),
+            Expr::String(s) => Ok(Value::String(s.clone())),
+            Expr::VectorSelector(selector) => Ok(Value::Vector(self.instant(selector, t))),
+            Expr::MatrixSelector(selector, range) => {
+                Ok(Value::Matrix(self.range(selector, *range, t)))
+            }
+            Expr::Negate(expr) => match self.eval(expr, t)? {
+                Value::Scalar(v) => Ok(Value::Scalar(-v)),
+                Value::Vector(samples) => Ok(Value::Vector(
+                    samples
+                        .into_iter()
+                        .map(|s| VectorSample {
+                            labels: drop_metric_name(s.labels),
+                            value: -s.value,
+                        })
+                        .collect(),
+                )),
+                value => eval_error(format!(
+                    "unary expression only allowed on scalars or instant vectors, got {}",
+                    value.type_name()
+                )),
+            },
+            Expr::Binary {
+                op,
+                lhs,
+                rhs,
+                modifier,
+            } => {
+                let lhs = self.eval(lhs, t)?;
+                let rhs = self.eval(rhs, t)?;
+                binary(*op, lhs, rhs, modifier)
+            }
+            Expr::Call { func, args } => self.call(func, args, t),
+            Expr::Aggregate {
+                op,
+                expr,
+                param,
+                grouping,
+            } => {
+                let param = match param {
+                    Some(param) => Some(self.eval(param, t)?),
+                    None => None,
+                };
+                match self.eval(expr, t)? {
+                    Value::Vector(samples) => aggregate(*op, samples, param, grouping),
+                    value => eval_error(format!(
+                        "expected instant vector in aggregation, got {}",
+                        value.type_name()
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Latest sample of each series within the lookback delta, unless it is a staleness
+    /// marker.
+    fn instant(&self, selector: &VectorSelector, t: i64) -> Vec<VectorSample> {
+        let t = t - selector.offset;
+        self.series[selector.index]
+            .iter()
+            .filter_map(|series| {
+                let end = series.points.partition_point(|(ts, _)| *ts <= t);
+                let (ts, value) = *series.points[..end].last()?;
+                if ts <= t - LOOKBACK_DELTA_MS || is_stale(value) {
+                    return None;
+                }
+                Some(VectorSample {
+                    labels: series.labels.clone(),
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Samples of each series in the left-open range `(t - range, t]`, without staleness
+    /// markers.
+    fn range(&self, selector: &VectorSelector, range: i64, t: i64) -> Vec<RangeSeries> {
+        let t = t - selector.offset;
+        self.series[selector.index]
+            .iter()
+            .filter_map(|series| {
+                let start = series.points.partition_point(|(ts, _)| *ts <= t - range);
+                let end = series.points.partition_point(|(ts, _)| *ts <= t);
+                let points: Vec<_> = series.points[start..end]
+                    .iter()
+                    .filter(|(_, value)| !is_stale(*value))
+                    .copied()
+                    .collect();
+                if points.is_empty() {
+                    return None;
+                }
+                Some(RangeSeries {
+                    labels: series.labels.clone(),
+                    points,
+                })
+            })
+            .collect()
+    }
+
+    fn scalar_arg(&self, args: &[Expr], index: usize, t: i64) -> Result<f64> {
+        match args.get(index).map(|arg| self.eval(arg, t)).transpose()? {
+            Some(Value::Scalar(v)) => Ok(v),
+            Some(value) => eval_error(format!(
+                "expected scalar argument, got {}",
+                value.type_name()
+            )),
+            None => eval_error("missing scalar argument"),
+        }
+    }
+
+    fn vector_arg(&self, args: &[Expr], index: usize, t: i64) -> Result<Vec<VectorSample>> {
+        match args.get(index).map(|arg| self.eval(arg, t)).transpose()? {
+            Some(Value::Vector(samples)) => Ok(samples),
+            Some(value) => eval_error(format!(
+                "expected instant vector argument, got {}",
+                value.type_name()
+            )),
+            None => eval_error("missing instant vector argument"),
+        }
+    }
+
+    fn string_arg(&self, args: &[Expr], index: usize) -> Result<String> {
+        match args.get(index) {
+            Some(Expr::String(s)) => Ok(s.clone()),
+            _ => eval_error("expected string argument"),
+        }
+    }
+
+    fn call(&self, func: &str, args: &[Expr], t: i64) -> Result<Value> {
+        if let Some(f) = range_function(func) {
+            let (selector, range) = match args.last() {
+                Some(Expr::MatrixSelector(selector, range)) => (selector, *range),
+                _ => return eval_error(format!("expected range vector argument in {}", func)),
+            };
+            let param = match args.len() {
+                2 => Some(self.scalar_arg(args, 0, t)?),
+                _ => None,
+            };
+            let range_end = t - selector.offset;
+            let samples = self
+                .range(selector, range, t)
+                .into_iter()
+                .filter_map(|series| {
+                    let context = RangeContext {
+                        points: &series.points,
+                        start: range_end - range,
+                        end: range_end,
+                        param,
+                    };
+                    f(&context).map(|value| VectorSample {
+                        labels: drop_metric_name(series.labels),
+                        value,
+                    })
+                })
+                .collect();
+            return Ok(Value::Vector(samples));
+        }
+        if let Some(f) = math_function(func) {
+            let samples = self.vector_arg(args, 0, t)?;
+            return Ok(Value::Vector(map_values(samples, f)));
+        }
+        match func {
+            "time" => Ok(Value::Scalar(t as f64 / 1000.)),
+            "vector" => Ok(Value::Vector(vec![VectorSample {
+                labels: Labels::new(),
+                value: self.scalar_arg(args, 0, t)?,
+            }])),
+            "scalar" => {
+                let samples = self.vector_arg(args, 0, t)?;
+                Ok(Value::Scalar(match samples.as_slice() {
+                    [sample] => sample.value,
+                    _ => f64::NAN,
+                }))
+            }
+            "round" => {
+                let samples = self.vector_arg(args, 0, t)?;
+                let to_nearest = match args.len() {
+                    1 => 1.,
+                    _ => self.scalar_arg(args, 1, t)?,
+                };
+                let inverse = 1. / to_nearest;
+                Ok(Value::Vector(map_values(samples, |v| {
+                    (v * inverse + 0.5).floor() / inverse
+                })))
+            }
+            "clamp_min" | "clamp_max" => {
+                let samples = self.vector_arg(args, 0, t)?;
+                let bound = self.scalar_arg(args, 1, t)?;
+                Ok(Value::Vector(if func == "clamp_min" {
+                    map_values(samples, |v| v.max(bound))
+                } else {
+                    map_values(samples, |v| v.min(bound))
+                }))
+            }
+            "clamp" => {
+                let samples = self.vector_arg(args, 0, t)?;
+                let min = self.scalar_arg(args, 1, t)?;
+                let max = self.scalar_arg(args, 2, t)?;
+                if max < min {
+                    return Ok(Value::Vector(Vec::new()));
+                }
+                Ok(Value::Vector(map_values(samples, |v| v.max(min).min(max))))
+            }
+            "sort" | "sort_desc" => {
+                let mut samples = self.vector_arg(args, 0, t)?;
+                samples.sort_by(|a, b| compare_values(a.value, b.value));
+                if func == "sort_desc" {
+                    samples.reverse();
+                }
+                Ok(Value::Vector(samples))
+            }
+            "absent" => {
+                if !self.vector_arg(args, 0, t)?.is_empty() {
+                    return Ok(Value::Vector(Vec::new()));
+                }
+                let mut labels = Labels::new();
+                if let Some(Expr::VectorSelector(selector)) = args.first() {
+                    for m in &selector.matchers {
+                        if m.r#type() == crate::prometheus::types::label_matcher::Type::Eq
+                            && m.name != "__name__"
+                        {
+                            labels.insert(m.name.clone(), m.value.clone());
+                        }
+                    }
+                }
+                Ok(Value::Vector(vec![VectorSample { labels, value: 1. }]))
+            }
+            "label_replace" => {
+                let samples = self.vector_arg(args, 0, t)?;
+                let dst = self.string_arg(args, 1)?;
+                let replacement = self.string_arg(args, 2)?;
+                let src = self.string_arg(args, 3)?;
+                let regex = self.string_arg(args, 4)?;
+                let regex = regex::Regex::new(&format!("^(?:{})$", regex)).map_err(|err| {
+                    PromqlError::Eval(format!("invalid regular expression: {}", err))
+                })?;
+                Ok(Value::Vector(
+                    samples
+                        .into_iter()
+                        .map(|mut sample| {
+                            let value = sample.labels.get(&src).cloned().unwrap_or_default();
+                            if let Some(captures) = regex.captures(&value) {
+                                let mut result = String::new();
+                                captures.expand(&replacement, &mut result);
+                                if result.is_empty() {
+                                    sample.labels.remove(&dst);
+                                } else {
+                                    sample.labels.insert(dst.clone(), result);
+                                }
+                            }
+                            sample
+                        })
+                        .collect(),
+                ))
+            }
+            "histogram_quantile" => {
+                let q = self.scalar_arg(args, 0, t)?;
+                let samples = self.vector_arg(args, 1, t)?;
+                Ok(Value::Vector(histogram_quantile(q, samples)))
+            }
+            _ => eval_error(format!("unknown function {:?}", func)),
+        }
+    }
+}
+
+fn map_values(samples: Vec<VectorSample>, f: impl Fn(f64) -> f64) -> Vec<VectorSample> {
+    samples
+        .into_iter()
+        .map(|s| VectorSample {
+            labels: drop_metric_name(s.labels),
+            value: f(s.value),
+        })
+        .collect()
+}
+
+fn compare_values(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b)
+        .unwrap_or_else(|| b.is_nan().cmp(&a.is_nan()))
+}
+
+fn math_function(name: &str) -> Option<fn(f64) -> f64> {
+    let f: fn(f64) -> f64 = match name {
+        "abs" => f64::abs,
+        "ceil" => f64::ceil,
+        "floor" => f64::floor,
+        "exp" => f64::exp,
+        "ln" => f64::ln,
+        "log2" => f64::log2,
+        "log10" => f64::log10,
+        "sqrt" => f64::sqrt,
+        _ => return None,
+    };
+    Some(f)
+}
+
+/// Input of functions over range vectors.
+struct RangeContext<'a> {
+    points: &'a [(i64, f64)],
+    start: i64,
+    end: i64,
+    /// Scalar parameter, e.g. the quantile of `quantile_over_time`.
+    param: Option<f64>,
+}
+
+type RangeFunction = fn(&RangeContext) -> Option<f64>;
+
+fn range_function(name: &str) -> Option<RangeFunction> {
+    fn values<'a>(ctx: &RangeContext<'a>) -> impl Iterator<Item = f64> + 'a {
+        ctx.points.iter().map(|(_, v)| *v)
+    }
+    let f: RangeFunction = match name {
+        "rate" => |ctx| extrapolated_rate(ctx, true, true),
+        "increase" => |ctx| extrapolated_rate(ctx, true, false),
+        "delta" => |ctx| extrapolated_rate(ctx, false, false),
+        "irate" => |ctx| instant_delta(ctx.points, true),
+        "idelta" => |ctx| instant_delta(ctx.points, false),
+        "changes" => |ctx| Some(ctx.points.windows(2).filter(|w| w[0].1 != w[1].1).count() as f64),
+        "resets" => |ctx| Some(ctx.points.windows(2).filter(|w| w[1].1 < w[0].1).count() as f64),
+        "sum_over_time" => |ctx| Some(values(ctx).sum()),
+        "avg_over_time" => |ctx| Some(values(ctx).sum::<f64>() / ctx.points.len() as f64),
+        "min_over_time" => |ctx| values(ctx).reduce(f64::min),
+        "max_over_time" => |ctx| values(ctx).reduce(f64::max),
+        "count_over_time" => |ctx| Some(ctx.points.len() as f64),
+        "last_over_time" => |ctx| ctx.points.last().map(|(_, v)| *v),
+        "present_over_time" => |_| Some(1.),
+        "stddev_over_time" => |ctx| Some(variance(values(ctx)).sqrt()),
+        "stdvar_over_time" => |ctx| Some(variance(values(ctx))),
+        "quantile_over_time" => |ctx| Some(quantile(ctx.param?, values(ctx).collect())),
+        _ => return None,
+    };
+    Some(f)
+}
+
+/// Rate calculation with extrapolation to the range boundaries, as Prometheus does.
+fn extrapolated_rate(ctx: &RangeContext, is_counter: bool, is_rate: bool) -> Option<f64> {
+    let points = ctx.points;
+    if points.len() < 2 {
+        return None;
+    }
+    let (first_t, first_v) = points[0];
+    let (last_t, last_v) = points[points.len() - 1];
+    let mut result = last_v - first_v;
+    if is_counter {
+        result += points
+            .windows(2)
+            .filter(|w| w[1].1 < w[0].1)
+            .map(|w| w[0].1)
+            .sum::<f64>();
+    }
+    let mut duration_to_start = (first_t - ctx.start) as f64 / 1000.;
+    let duration_to_end = (ctx.end - last_t) as f64 / 1000.;
+    let sampled_interval = (last_t - first_t) as f64 / 1000.;
+    let average_interval = sampled_interval / (points.len() - 1) as f64;
+    if is_counter && result > 0. && first_v >= 0. {
+        // counters can not be extrapolated below zero
+        let duration_to_zero = sampled_interval * (first_v / result);
+        if duration_to_zero < duration_to_start {
+            duration_to_start = duration_to_zero;
+        }
+    }
+    let threshold = average_interval * 1.1;
+    let mut extrapolate_to = sampled_interval;
+    extrapolate_to += if duration_to_start < threshold {
+        duration_to_start
+    } else {
+        average_interval / 2.
+    };
+    extrapolate_to += if duration_to_end < threshold {
+        duration_to_end
+    } else {
+        average_interval / 2.
+    };
+    result *= extrapolate_to / sampled_interval;
+    if is_rate {
+        result /= (ctx.end - ctx.start) as f64 / 1000.;
+    }
+    Some(result)
+}
+
+/// Difference of the last two samples, per second if `is_rate`.
+fn instant_delta(points: &[(i64, f64)], is_rate: bool) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let (prev_t, prev_v) = points[points.len() - 2];
+    let (last_t, last_v) = points[points.len() - 1];
+    let mut result = last_v - prev_v;
+    if is_rate {
+        if last_v < prev_v {
+            // counter reset
+            result = last_v;
+        }
+        let interval = last_t - prev_t;
+        if interval == 0 {
+            return None;
+        }
+        result /= interval as f64 / 1000.;
+    }
+    Some(result)
+}
+
+fn variance(values: impl Iterator<Item = f64>) -> f64 {
+    let (mut count, mut mean, mut m2) = (0., 0., 0.);
+    for v in values {
+        count += 1.;
+        let delta = v - mean;
+        mean += delta / count;
+        m2 += delta * (v - mean);
+    }
+    m2 / count
+}
+
+/// Quantile with linear interpolation, as `quantile` and `quantile_over_time` do.
+fn quantile(q: f64, mut values: Vec<f64>) -> f64 {
+    if values.is_empty() || q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0. {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1. {
+        return f64::INFINITY;
+    }
+    values.sort_by(|a, b| compare_values(*a, *b));
+    let n = values.len() as f64;
+    let rank = q * (n - 1.);
+    let lower = rank.floor().max(0.);
+    let upper = (lower + 1.).min(n - 1.);
+    let weight = rank - rank.floor();
+    values[lower as usize] * (1. - weight) + values[upper as usize] * weight
+}
+
+/// Quantile from cumulative histogram buckets `(upper_bound, count)`.
+fn bucket_quantile(q: f64, mut buckets: Vec<(f64, f64)>) -> f64 {
+    if q < 0. {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1. {
+        return f64::INFINITY;
+    }
+    buckets.sort_by(|a, b| compare_values(a.0, b.0));
+    match buckets.last() {
+        Some((upper, _)) if *upper == f64::INFINITY => {}
+        _ => return f64::NAN,
+    }
+    // fix non-monotonic counts, e.g. caused by scrape timing
+    let mut max = f64::NEG_INFINITY;
+    for bucket in &mut buckets {
+        max = max.max(bucket.1);
+        bucket.1 = max;
+    }
+    if buckets.len() < 2 {
+        return f64::NAN;
+    }
+    let observations = buckets[buckets.len() - 1].1;
+    if observations == 0. {
+        return f64::NAN;
+    }
+    let mut rank = q * observations;
+    let b = buckets.partition_point(|(_, count)| *count < rank);
+    if b == buckets.len() - 1 {
+        return buckets[buckets.len() - 2].0;
+    }
+    if b == 0 && buckets[0].0 <= 0. {
+        return buckets[0].0;
+    }
+    let (mut bucket_start, bucket_end, mut count) = (0., buckets[b].0, buckets[b].1);
+    if b > 0 {
+        bucket_start = buckets[b - 1].0;
+        count -= buckets[b - 1].1;
+        rank -= buckets[b - 1].1;
+    }
+    bucket_start + (bucket_end - bucket_start) * (rank / count)
+}
+
+fn histogram_quantile(q: f64, samples: Vec<VectorSample>) -> Vec<VectorSample> {
+    let mut groups: BTreeMap<Labels, Vec<(f64, f64)>> = BTreeMap::new();
+    for sample in samples {
+        let mut labels = drop_metric_name(sample.labels);
+        let upper = match labels.remove("le").and_then(|le| le.parse::<f64>().ok()) {
+            Some(upper) => upper,
+            None => continue,
+        };
+        groups
+            .entry(labels)
+            .or_default()
+            .push((upper, sample.value));
+    }
+    groups
+        .into_iter()
+        .map(|(labels, buckets)| VectorSample {
+            labels,
+            value: bucket_quantile(q, buckets),
+        })
+        .collect()
+}
+
+/// Labels used to match series of binary and set operations.
+fn signature(labels: &Labels, matching: &Option<(bool, Vec<String>)>) -> Labels {
+    match matching {
+        Some((true, on)) => labels
+            .iter()
+            .filter(|(name, _)| on.contains(name))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        Some((false, ignoring)) => labels
+            .iter()
+            .filter(|(name, _)| *name != "__name__" && !ignoring.contains(name))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        None => drop_metric_name(labels.clone()),
+    }
+}
+
+/// Apply a scalar operation, returns `None` if filtered out by a comparison.
+fn apply(op: BinaryOp, lhs: f64, rhs: f64) -> Option<f64> {
+    use BinaryOp::*;
+    let compare = |keep: bool| if keep { Some(lhs) } else { None };
+    match op {
+        Add => Some(lhs + rhs),
+        Sub => Some(lhs - rhs),
+        Mul => Some(lhs * rhs),
+        Div => Some(lhs / rhs),
+        Mod => Some(lhs % rhs),
+        Pow => Some(lhs.powf(rhs)),
+        Atan2 => Some(lhs.atan2(rhs)),
+        Eql => compare(lhs == rhs),
+        Neq => compare(lhs != rhs),
+        Gtr => compare(lhs > rhs),
+        Lss => compare(lhs < rhs),
+        Gte => compare(lhs >= rhs),
+        Lte => compare(lhs <= rhs),
+        And | Or | Unless => None,
+    }
+}
+
+/// Result of an element-wise operation, honoring the `bool` modifier of comparisons.
+/// `kept` is the value returned by filtering comparisons.
+fn apply_modified(op: BinaryOp, lhs: f64, rhs: f64, return_bool: bool, kept: f64) -> Option<f64> {
+    match apply(op, lhs, rhs) {
+        Some(_) if op.is_comparison() && return_bool => Some(1.),
+        None if op.is_comparison() && return_bool => Some(0.),
+        Some(_) if op.is_comparison() => Some(kept),
+        result => result,
+    }
+}
+
+fn binary(op: BinaryOp, lhs: Value, rhs: Value, modifier: &BinaryModifier) -> Result<Value> {
+    let drop_name = !op.is_comparison() || modifier.return_bool;
+    let result_labels = |labels: Labels| {
+        if drop_name {
+            drop_metric_name(labels)
+        } else {
+            labels
+        }
+    };
+    match (lhs, rhs) {
+        (Value::Scalar(l), Value::Scalar(r)) => {
+            if op.is_comparison() && !modifier.return_bool {
+                return eval_error("comparisons between scalars must use BOOL modifier");
+            }
+            if op.is_set() {
+                return eval_error(format!("set operator {:?} not allowed between scalars", op));
+            }
+            Ok(Value::Scalar(
+                apply_modified(op, l, r, modifier.return_bool, l).unwrap_or(0.),
+            ))
+        }
+        (Value::Vector(samples), Value::Scalar(r)) if !op.is_set() => Ok(Value::Vector(
+            samples
+                .into_iter()
+                .filter_map(|s| {
+                    let value = apply_modified(op, s.value, r, modifier.return_bool, s.value)?;
+                    Some(VectorSample {
+                        labels: result_labels(s.labels),
+                        value,
+                    })
+                })
+                .collect(),
+        )),
+        (Value::Scalar(l), Value::Vector(samples)) if !op.is_set() => Ok(Value::Vector(
+            samples
+                .into_iter()
+                .filter_map(|s| {
+                    let value = apply_modified(op, l, s.value, modifier.return_bool, s.value)?;
+                    Some(VectorSample {
+                        labels: result_labels(s.labels),
+                        value,
+                    })
+                })
+                .collect(),
+        )),
+        (Value::Vector(lhs), Value::Vector(rhs)) => {
+            if op.is_set() {
+                Ok(Value::Vector(set_operation(
+                    op,
+                    lhs,
+                    rhs,
+                    &modifier.matching,
+                )))
+            } else {
+                vector_binary(op, lhs, rhs, modifier, drop_name).map(Value::Vector)
+            }
+        }
+        (lhs, rhs) => eval_error(format!(
+            "binary operator {:?} not allowed between {} and {}",
+            op,
+            lhs.type_name(),
+            rhs.type_name()
+        )),
+    }
+}
+
+fn set_operation(
+    op: BinaryOp,
+    lhs: Vec<VectorSample>,
+    rhs: Vec<VectorSample>,
+    matching: &Option<(bool, Vec<String>)>,
+) -> Vec<VectorSample> {
+    let rhs_signatures: HashSet<Labels> =
+        rhs.iter().map(|s| signature(&s.labels, matching)).collect();
+    match op {
+        BinaryOp::And => lhs
+            .into_iter()
+            .filter(|s| rhs_signatures.contains(&signature(&s.labels, matching)))
+            .collect(),
+        BinaryOp::Unless => lhs
+            .into_iter()
+            .filter(|s| !rhs_signatures.contains(&signature(&s.labels, matching)))
+            .collect(),
+        _ => {
+            let lhs_signatures: HashSet<Labels> =
+                lhs.iter().map(|s| signature(&s.labels, matching)).collect();
+            let mut result = lhs;
+            result.extend(
+                rhs.into_iter()
+                    .filter(|s| !lhs_signatures.contains(&signature(&s.labels, matching))),
+            );
+            result
+        }
+    }
+}
+
+fn vector_binary(
+    op: BinaryOp,
+    lhs: Vec<VectorSample>,
+    rhs: Vec<VectorSample>,
+    modifier: &BinaryModifier,
+    drop_name: bool,
+) -> Result<Vec<VectorSample>> {
+    // the "many" side is iterated and matched against the unique "one" side
+    let (swapped, include) = match &modifier.cardinality {
+        Cardinality::OneToOne => (false, None),
+        Cardinality::ManyToOne(include) => (false, Some(include)),
+        Cardinality::OneToMany(include) => (true, Some(include)),
+    };
+    let (many, one) = if swapped { (rhs, lhs) } else { (lhs, rhs) };
+    let matching = &modifier.matching;
+
+    let mut one_by_signature: HashMap<Labels, &VectorSample> = HashMap::new();
+    for sample in &one {
+        if one_by_signature
+            .insert(signature(&sample.labels, matching), sample)
+            .is_some()
+        {
+            return eval_error(format!(
+                "found duplicate series for the match group on the {} hand-side of the operation",
+                if swapped { "left" } else { "right" }
+            ));
+        }
+    }
+
+    let mut matched = HashSet::new();
+    let mut result = Vec::new();
+    for sample in many {
+        let signature = signature(&sample.labels, matching);
+        let other = match one_by_signature.get(&signature) {
+            Some(other) => other,
+            None => continue,
+        };
+        let (l, r) = if swapped {
+            (other.value, sample.value)
+        } else {
+            (sample.value, other.value)
+        };
+        let value = match apply_modified(op, l, r, modifier.return_bool, l) {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut labels = if drop_name {
+            drop_metric_name(sample.labels)
+        } else {
+            sample.labels
+        };
+        match include {
+            None => {
+                if !matched.insert(signature) {
+                    return eval_error("multiple matches for labels: many-to-one matching must be explicit (group_left/group_right)");
+                }
+                match matching {
+                    Some((true, on)) => labels.retain(|name, _| on.contains(name)),
+                    Some((false, ignoring)) => labels.retain(|name, _| !ignoring.contains(name)),
+                    None => {}
+                }
+            }
+            Some(include) => {
+                for name in include {
+                    match other.labels.get(name) {
+                        Some(value) if !value.is_empty() => {
+                            labels.insert(name.clone(), value.clone());
+                        }
+                        _ => {
+                            labels.remove(name);
+                        }
+                    }
+                }
+                if !matched.insert(labels.clone()) {
+                    return eval_error(
+                        "multiple matches for labels: grouping labels must ensure unique matches",
+                    );
+                }
+            }
+        }
+        result.push(VectorSample { labels, value });
+    }
+    Ok(result)
+}
+
+fn group_labels(labels: &Labels, grouping: &Grouping) -> Labels {
+    match grouping {
+        Grouping::By(by) => labels
+            .iter()
+            .filter(|(name, _)| by.contains(name))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        Grouping::Without(without) => labels
+            .iter()
+            .filter(|(name, _)| *name != "__name__" && !without.contains(name))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+    }
+}
+
+fn aggregate(
+    op: AggregateOp,
+    samples: Vec<VectorSample>,
+    param: Option<Value>,
+    grouping: &Grouping,
+) -> Result<Value> {
+    let param = match (op.has_param(), param) {
+        (false, _) => 0.,
+        (true, Some(Value::Scalar(param))) => param,
+        (true, _) => return eval_error(format!("expected scalar parameter in {:?}", op)),
+    };
+    let mut groups: BTreeMap<Labels, Vec<VectorSample>> = BTreeMap::new();
+    for sample in samples {
+        groups
+            .entry(group_labels(&sample.labels, grouping))
+            .or_default()
+            .push(sample);
+    }
+
+    let mut result = Vec::new();
+    for (labels, group) in groups {
+        let values = || group.iter().map(|s| s.value);
+        let value = match op {
+            AggregateOp::Sum => values().sum(),
+            AggregateOp::Avg => values().sum::<f64>() / group.len() as f64,
+            AggregateOp::Min => {
+                values().fold(f64::NAN, |a, b| if a.is_nan() || b < a { b } else { a })
+            }
+            AggregateOp::Max => {
+                values().fold(f64::NAN, |a, b| if a.is_nan() || b > a { b } else { a })
+            }
+            AggregateOp::Count => group.len() as f64,
+            AggregateOp::Group => 1.,
+            AggregateOp::Stddev => variance(values()).sqrt(),
+            AggregateOp::Stdvar => variance(values()),
+            AggregateOp::Quantile => quantile(param, values().collect()),
+            AggregateOp::Topk | AggregateOp::Bottomk => {
+                let k = param as usize;
+                let mut group = group;
+                group.sort_by(|a, b| match op {
+                    AggregateOp::Topk => compare_values(b.value, a.value),
+                    _ => compare_values(a.value, b.value),
+                });
+                result.extend(group.into_iter().take(k));
+                continue;
+            }
+        };
+        result.push(VectorSample { labels, value });
+    }
+    Ok(Value::Vector(result))
+}
+
+#[cfg(test)]
+fn labels(pairs: &[(&str, &str)]) -> Labels {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_functions() {
+    // a counter increasing 1 per second, sampled every 10s, with a reset
+    let points = vec![
+        (10_000, 10.),
+        (20_000, 20.),
+        (30_000, 30.),
+        (40_000, 5.),
+        (50_000, 15.),
+    ];
+    let ctx = RangeContext {
+        points: &points,
+        start: 0,
+        end: 60_000,
+        param: None,
+    };
+    let increase = extrapolated_rate(&ctx, true, false).unwrap();
+    // 35 observed over 40s, extrapolated to the whole 60s range
+    assert!((increase - 35. * 60. / 40.).abs() < 1e-9, "{}", increase);
+    assert!((extrapolated_rate(&ctx, true, true).unwrap() - increase / 60.).abs() < 1e-9);
+    assert_eq!(instant_delta(&points, true), Some(1.));
+    assert_eq!(instant_delta(&points, false), Some(10.));
+
+    assert_eq!(quantile(0.5, vec![1., 2., 3., 4.]), 2.5);
+    let buckets = vec![(0.1, 10.), (0.5, 50.), (1., 100.), (f64::INFINITY, 100.)];
+    assert!((bucket_quantile(0.5, buckets.clone()) - 0.5).abs() < 1e-9);
+    assert!((bucket_quantile(0.75, buckets) - 0.75).abs() < 1e-9);
+}
+
+#[test]
+fn test_vector_matching() {
+    let lhs = vec![
+        VectorSample {
+            labels: labels(&[("__name__", "a"), ("job", "x"), ("instance", "1")]),
+            value: 4.,
+        },
+        VectorSample {
+            labels: labels(&[("__name__", "a"), ("job", "x"), ("instance", "2")]),
+            value: 6.,
+        },
+    ];
+    let rhs = vec![VectorSample {
+        labels: labels(&[("__name__", "b"), ("job", "x"), ("env", "prod")]),
+        value: 2.,
+    }];
+    let modifier = BinaryModifier {
+        matching: Some((true, vec!["job".to_string()])),
+        cardinality: Cardinality::ManyToOne(vec!["env".to_string()]),
+        ..Default::default()
+    };
+    let result = binary(
+        BinaryOp::Div,
+        Value::Vector(lhs.clone()),
+        Value::Vector(rhs.clone()),
+        &modifier,
+    )
+    .unwrap();
+    assert_eq!(
+        result,
+        Value::Vector(vec![
+            VectorSample {
+                labels: labels(&[("job", "x"), ("instance", "1"), ("env", "prod")]),
+                value: 2.,
+            },
+            VectorSample {
+                labels: labels(&[("job", "x"), ("instance", "2"), ("env", "prod")]),
+                value: 3.,
+            },
+        ])
+    );
+
+    // one-to-one matching fails for multiple matches
+    let modifier = BinaryModifier {
+        matching: Some((true, vec!["job".to_string()])),
+        ..Default::default()
+    };
+    assert!(binary(
+        BinaryOp::Div,
+        Value::Vector(lhs.clone()),
+        Value::Vector(rhs),
+        &modifier
+    )
+    .is_err());
+
+    let result = aggregate(
+        AggregateOp::Sum,
+        lhs,
+        None,
+        &Grouping::Without(vec!["instance".to_string()]),
+    )
+    .unwrap();
+    assert_eq!(
+        result,
+        Value::Vector(vec![VectorSample {
+            labels: labels(&[("job", "x")]),
+            value: 10.,
+        }])
+    );
+}
@@ -0,0 +1,148 @@
+//! PromQL engine backing `/api/v1/query` and `/api/v1/query_range`.
+//!
+//! Series of all selectors in an expression are fetched with a single remote read request
+//! covering the whole evaluation range, then the expression is evaluated in memory at
+//! each step.
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::prometheus::types::*;
+use crate::prometheus::{prometheus_read, PrometheusReaderError, ReadOptions};
+use crate::storage::Storage;
+
+pub mod ast;
+pub mod eval;
+pub mod parser;
+
+pub use eval::{Evaluator, Labels, RangeSeries, Value, VectorSample, LOOKBACK_DELTA_MS};
+pub use parser::{parse, parse_duration, ParseError};
+
+#[derive(Debug, Error)]
+pub enum PromqlError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Read(#[from] PrometheusReaderError),
+    #[error("{0}")]
+    Eval(String),
+}
+
+type Result<T> = std::result::Result<T, PromqlError>;
+
+/// Fetch series of all selectors to evaluate `expr` from `start` to `end`, indexed by selector.
+async fn fetch(
+    storage: &dyn Storage,
+    database: &str,
+    expr: &ast::Expr,
+    start: i64,
+    end: i64,
+    options: &ReadOptions,
+) -> Result<Vec<Vec<RangeSeries>>> {
+    let selectors = expr.selectors();
+    let mut queries = vec![Query::default(); selectors.len()];
+    for (selector, range) in selectors {
+        let lookback = range.max(LOOKBACK_DELTA_MS);
+        queries[selector.index] = Query {
+            start_timestamp_ms: start - selector.offset - lookback,
+            end_timestamp_ms: end - selector.offset,
+            matchers: selector.matchers.clone(),
+            ..Default::default()
+        };
+    }
+    if queries.is_empty() {
+        return Ok(Vec::new());
+    }
+    let response = prometheus_read(
+        storage,
+        database,
+        &ReadRequest {
+            queries,
+            ..Default::default()
+        },
+        options,
+    )
+    .await?;
+    Ok(response
+        .results
+        .into_iter()
+        .map(|result| {
+            result
+                .timeseries
+                .into_iter()
+                .map(|ts| {
+                    let labels = ts
+                        .labels
+                        .into_iter()
+                        .map(|label| (label.name, label.value))
+                        .collect();
+                    // NULL values are treated as missing samples, staleness markers are kept to end
+                    // series
+                    let mut points: Vec<_> = ts
+                        .samples
+                        .into_iter()
+                        .filter_map(|s| s.value.map(|v| (s.timestamp, v)))
+                        .collect();
+                    points.sort_by_key(|(t, _)| *t);
+                    RangeSeries { labels, points }
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// Evaluate an instant query at `time` in milliseconds.
+pub async fn instant_query(
+    storage: &dyn Storage,
+    database: &str,
+    query: &str,
+    time: i64,
+    options: &ReadOptions,
+) -> Result<Value> {
+    let expr = parse(query)?;
+    let series = fetch(storage, database, &expr, time, time, options).await?;
+    Evaluator { series: &series }.eval(&expr, time)
+}
+
+/// Evaluate a range query from `start` to `end` by `step`, all in milliseconds.
+pub async fn range_query(
+    storage: &dyn Storage,
+    database: &str,
+    query: &str,
+    start: i64,
+    end: i64,
+    step: i64,
+    options: &ReadOptions,
+) -> Result<Value> {
+    let expr = parse(query)?;
+    let series = fetch(storage, database, &expr, start, end, options).await?;
+    let evaluator = Evaluator { series: &series };
+    let mut result: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    let mut t = start;
+    while t <= end {
+        match evaluator.eval(&expr, t)? {
+            Value::Scalar(v) => result.entry(Labels::new()).or_default().push((t, v)),
+            Value::Vector(samples) => {
+                for sample in samples {
+                    result
+                        .entry(sample.labels)
+                        .or_default()
+                        .push((t, sample.value));
+                }
+            }
+            value => {
+                return Err(PromqlError::Eval(format!(
+                "invalid expression type {:?} for range query, must be scalar or instant vector",
+                value.type_name()
+            )))
+            }
+        }
+        t += step;
+    }
+    Ok(Value::Matrix(
+        result
+            .into_iter()
+            .map(|(labels, points)| RangeSeries { labels, points })
+            .collect(),
+    ))
+}
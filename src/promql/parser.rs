@@ -0,0 +1,647 @@
+//! Lexer and Pratt parser of PromQL expressions.
+use thiserror::Error;
+
+use crate::prometheus::selector::{is_empty_selector, is_name_char, is_name_start, parse_string};
+use crate::prometheus::types::*;
+use crate::promql::ast::*;
+
+#[derive(Debug, Error, PartialEq)]
+#[error("parse error: {0}")]
+pub struct ParseError(pub String);
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    /// Duration in milliseconds.
+    Duration(i64),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Colon,
+    Eof,
+}
+
+const OPERATORS: &[&str] = &[
+    "==", "!=", "=~", "!~", ">=", "<=", "=", ">", "<", "+", "-", "*", "/", "%", "^",
+];
+
+/// Duration units in milliseconds, `ms` must be checked before `m`.
+const DURATION_UNITS: &[(&str, i64)] = &[
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("d", 24 * 60 * 60 * 1000),
+    ("w", 7 * 24 * 60 * 60 * 1000),
+    ("y", 365 * 24 * 60 * 60 * 1000),
+];
+
+/// Parse a duration like `1h30m` or `100ms` into milliseconds.
+pub fn parse_duration(input: &str) -> Option<i64> {
+    let mut rest = input;
+    let mut total = 0i64;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return None;
+        }
+        let value: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let (unit, ms) = DURATION_UNITS
+            .iter()
+            .find(|(unit, _)| rest.starts_with(unit))?;
+        rest = &rest[unit.len()..];
+        total = total.checked_add(value.checked_mul(*ms)?)?;
+    }
+    Some(total)
+}
+
+fn lex(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let rest = &input[pos..];
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+        if c == '#' {
+            pos += rest.find('\n').unwrap_or(rest.len());
+            continue;
+        }
+        let single = match c {
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+            '{' => Some(Token::LBrace),
+            '}' => Some(Token::RBrace),
+            '[' => Some(Token::LBracket),
+            ']' => Some(Token::RBracket),
+            ',' => Some(Token::Comma),
+            ':' if !rest[1..].starts_with(|c: char| is_name_char(c)) => Some(Token::Colon),
+            _ => None,
+        };
+        if let Some(token) = single {
+            tokens.push(token);
+            pos += 1;
+            continue;
+        }
+        if c == '"' || c == '\'' || c == '`' {
+            let (value, len) =
+                parse_string(rest, pos).map_err(|err| ParseError(err.to_string()))?;
+            tokens.push(Token::Str(value));
+            pos += len;
+            continue;
+        }
+        if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            // durations are digits immediately followed by a unit
+            let duration_len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric()))
+                .unwrap_or(rest.len());
+            if let Some(duration) = parse_duration(&rest[..duration_len]) {
+                tokens.push(Token::Duration(duration));
+                pos += duration_len;
+                continue;
+            }
+            let len = if rest.starts_with("0x") || rest.starts_with("0X") {
+                2 + rest[2..]
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .unwrap_or(rest.len() - 2)
+            } else {
+                let mut len = rest
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                    .unwrap_or(rest.len());
+                if rest[len..].starts_with(['e', 'E']) {
+                    let exp = &rest[len + 1..];
+                    let sign = if exp.starts_with(['+', '-']) { 1 } else { 0 };
+                    let digits = exp[sign..]
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(exp.len() - sign);
+                    if digits > 0 {
+                        len += 1 + sign + digits;
+                    }
+                }
+                len
+            };
+            let literal = &rest[..len];
+            let value = if literal.len() > 2 && literal[..2].eq_ignore_ascii_case("0x") {
+                i64::from_str_radix(&literal[2..], 16)
+                    .map(|v| v as f64)
+                    .ok()
+            } else {
+                literal.parse::<f64>().ok()
+            };
+            let value =
+                value.ok_or_else(|| ParseError(format!("bad number or duration {:?}", literal)))?;
+            tokens.push(Token::Number(value));
+            pos += len;
+            continue;
+        }
+        if is_name_start(c) {
+            let len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            pos += len;
+            continue;
+        }
+        match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            Some(op) => {
+                tokens.push(Token::Op(op));
+                pos += op.len();
+            }
+            None => {
+                return Err(ParseError(format!(
+                    "unexpected character {:?} at position {}",
+                    c, pos
+                )))
+            }
+        }
+    }
+    tokens.push(Token::Eof);
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    selectors: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_at(&self, n: usize) -> &Token {
+        self.tokens.get(self.pos + n).unwrap_or(&Token::Eof)
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token != Token::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        let token = self.next();
+        if token == expected {
+            Ok(())
+        } else {
+            Err(ParseError(format!(
+                "unexpected {:?}, expected {:?}",
+                token, expected
+            )))
+        }
+    }
+
+    fn peek_ident(&self, ident: &str) -> bool {
+        matches!(self.peek(), Token::Ident(id) if id == ident)
+    }
+
+    fn peek_binary_op(&self) -> Option<BinaryOp> {
+        match self.peek() {
+            Token::Op(op) => BinaryOp::from_token(op),
+            Token::Ident(id) if matches!(id.as_str(), "and" | "or" | "unless" | "atan2") => {
+                BinaryOp::from_token(id)
+            }
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek_binary_op() {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => break,
+            };
+            self.next();
+            let modifier = self.binary_modifier(op)?;
+            let precedence = if op.is_right_associative() {
+                op.precedence()
+            } else {
+                op.precedence() + 1
+            };
+            let rhs = self.expr(precedence)?;
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                modifier,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Token::Op("-") => {
+                self.next();
+                // `-a ^ b` is `-(a ^ b)`
+                match self.expr(BinaryOp::Pow.precedence())? {
+                    Expr::Number(v) => Ok(Expr::Number(-v)),
+                    expr => Ok(Expr::Negate(Box::new(expr))),
+                }
+            }
+            Token::Op("+") => {
+                self.next();
+                self.expr(BinaryOp::Pow.precedence())
+            }
+            _ => {
+                let expr = self.primary()?;
+                self.postfix(expr)
+            }
+        }
+    }
+
+    fn binary_modifier(&mut self, op: BinaryOp) -> Result<BinaryModifier> {
+        let mut modifier = BinaryModifier::default();
+        if self.peek_ident("bool") {
+            if !op.is_comparison() {
+                return Err(ParseError(
+                    "bool modifier can only be used on comparison operators".to_string(),
+                ));
+            }
+            self.next();
+            modifier.return_bool = true;
+        }
+        if self.peek_ident("on") || self.peek_ident("ignoring") {
+            let on = self.peek_ident("on");
+            self.next();
+            modifier.matching = Some((on, self.label_list()?));
+            if self.peek_ident("group_left") || self.peek_ident("group_right") {
+                let left = self.peek_ident("group_left");
+                self.next();
+                let labels = if self.peek() == &Token::LParen {
+                    self.label_list()?
+                } else {
+                    Vec::new()
+                };
+                modifier.cardinality = if left {
+                    Cardinality::ManyToOne(labels)
+                } else {
+                    Cardinality::OneToMany(labels)
+                };
+            }
+        }
+        if op.is_set() && modifier.cardinality != Cardinality::OneToOne {
+            return Err(ParseError(format!(
+                "no grouping allowed for {:?} operation",
+                op
+            )));
+        }
+        Ok(modifier)
+    }
+
+    fn label_list(&mut self) -> Result<Vec<String>> {
+        self.expect(Token::LParen)?;
+        let mut labels = Vec::new();
+        loop {
+            match self.next() {
+                Token::RParen => return Ok(labels),
+                Token::Ident(label) => labels.push(label),
+                token => return Err(ParseError(format!("unexpected {:?} in grouping", token))),
+            }
+            match self.next() {
+                Token::Comma => continue,
+                Token::RParen => return Ok(labels),
+                token => return Err(ParseError(format!("unexpected {:?} in grouping", token))),
+            }
+        }
+    }
+
+    fn grouping(&mut self) -> Result<Grouping> {
+        match self.next() {
+            Token::Ident(id) if id == "by" => Ok(Grouping::By(self.label_list()?)),
+            Token::Ident(id) if id == "without" => Ok(Grouping::Without(self.label_list()?)),
+            token => Err(ParseError(format!("unexpected {:?}", token))),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Token::Number(v) => Ok(Expr::Number(v)),
+            Token::Str(s) => Ok(Expr::String(s)),
+            Token::LParen => {
+                let expr = self.expr(0)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Token::LBrace => self.selector(Vec::new()),
+            Token::Ident(id) => {
+                if id.eq_ignore_ascii_case("inf") {
+                    return Ok(Expr::Number(f64::INFINITY));
+                }
+                if id.eq_ignore_ascii_case("nan") {
+                    return Ok(Expr::Number(f64::NAN));
+                }
+                if let Some(op) = AggregateOp::from_name(&id) {
+                    if self.peek() == &Token::LParen
+                        || self.peek_ident("by")
+                        || self.peek_ident("without")
+                    {
+                        return self.aggregate(op);
+                    }
+                }
+                if self.peek() == &Token::LParen {
+                    return self.call(id);
+                }
+                let matchers = vec![LabelMatcher {
+                    r#type: label_matcher::Type::Eq as i32,
+                    name: "__name__".to_string(),
+                    value: id,
+                }];
+                if self.peek() == &Token::LBrace {
+                    self.next();
+                    self.selector(matchers)
+                } else {
+                    self.finish_selector(matchers)
+                }
+            }
+            token => Err(ParseError(format!("unexpected {:?}", token))),
+        }
+    }
+
+    /// Parse label matchers right after `{`.
+    fn selector(&mut self, mut matchers: Vec<LabelMatcher>) -> Result<Expr> {
+        loop {
+            let name = match self.next() {
+                Token::RBrace => break,
+                Token::Ident(name) => name,
+                token => return Err(ParseError(format!("unexpected {:?} in selector", token))),
+            };
+            let r#type = match self.next() {
+                Token::Op("=") => label_matcher::Type::Eq,
+                Token::Op("!=") => label_matcher::Type::Neq,
+                Token::Op("=~") => label_matcher::Type::Re,
+                Token::Op("!~") => label_matcher::Type::Nre,
+                token => return Err(ParseError(format!("unexpected {:?} in selector", token))),
+            };
+            let value = match self.next() {
+                Token::Str(value) => value,
+                token => return Err(ParseError(format!("unexpected {:?} in selector", token))),
+            };
+            matchers.push(LabelMatcher {
+                r#type: r#type as i32,
+                name,
+                value,
+            });
+            match self.next() {
+                Token::Comma => continue,
+                Token::RBrace => break,
+                token => return Err(ParseError(format!("unexpected {:?} in selector", token))),
+            }
+        }
+        self.finish_selector(matchers)
+    }
+
+    fn finish_selector(&mut self, matchers: Vec<LabelMatcher>) -> Result<Expr> {
+        if is_empty_selector(&matchers) {
+            return Err(ParseError(
+                "vector selector must contain at least one non-empty matcher".to_string(),
+            ));
+        }
+        let index = self.selectors;
+        self.selectors += 1;
+        Ok(Expr::VectorSelector(VectorSelector {
+            matchers,
+            offset: 0,
+            index,
+        }))
+    }
+
+    fn postfix(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            match self.peek() {
+                Token::LBracket => {
+                    self.next();
+                    let range = match self.next() {
+                        Token::Duration(range) => range,
+                        token => {
+                            return Err(ParseError(format!("unexpected {:?} in range", token)))
+                        }
+                    };
+                    match self.next() {
+                        Token::RBracket => {}
+                        Token::Colon => {
+                            return Err(ParseError("subqueries are not supported".to_string()))
+                        }
+                        token => {
+                            return Err(ParseError(format!("unexpected {:?} in range", token)))
+                        }
+                    }
+                    expr = match expr {
+                        Expr::VectorSelector(selector) => Expr::MatrixSelector(selector, range),
+                        _ => {
+                            return Err(ParseError(
+                                "ranges only allowed for vector selectors".to_string(),
+                            ))
+                        }
+                    };
+                }
+                Token::Ident(id) if id == "offset" => {
+                    self.next();
+                    let offset = match self.next() {
+                        Token::Duration(offset) => offset,
+                        Token::Op("-") => match self.next() {
+                            Token::Duration(offset) => -offset,
+                            token => return Err(ParseError(format!("unexpected {:?}", token))),
+                        },
+                        token => return Err(ParseError(format!("unexpected {:?}", token))),
+                    };
+                    match &mut expr {
+                        Expr::VectorSelector(selector) | Expr::MatrixSelector(selector, _) => {
+                            selector.offset = offset;
+                        }
+                        _ => {
+                            return Err(ParseError(
+                                "offset modifier must be preceded by a selector".to_string(),
+                            ))
+                        }
+                    }
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn aggregate(&mut self, op: AggregateOp) -> Result<Expr> {
+        let mut grouping = None;
+        if !matches!(self.peek(), Token::LParen) {
+            grouping = Some(self.grouping()?);
+        }
+        self.expect(Token::LParen)?;
+        let param = if op.has_param() {
+            let param = self.expr(0)?;
+            self.expect(Token::Comma)?;
+            Some(Box::new(param))
+        } else {
+            None
+        };
+        let expr = self.expr(0)?;
+        self.expect(Token::RParen)?;
+        if grouping.is_none() && (self.peek_ident("by") || self.peek_ident("without")) {
+            grouping = Some(self.grouping()?);
+        }
+        Ok(Expr::Aggregate {
+            op,
+            expr: Box::new(expr),
+            param,
+            grouping: grouping.unwrap_or_default(),
+        })
+    }
+
+    fn call(&mut self, func: String) -> Result<Expr> {
+        self.expect(Token::LParen)?;
+        let mut args = Vec::new();
+        if self.peek() == &Token::RParen {
+            self.next();
+        } else {
+            loop {
+                args.push(self.expr(0)?);
+                match self.next() {
+                    Token::Comma => continue,
+                    Token::RParen => break,
+                    token => {
+                        return Err(ParseError(format!(
+                            "unexpected {:?} in arguments of {}",
+                            token, func
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(Expr::Call { func, args })
+    }
+}
+
+/// Parse a PromQL expression.
+pub fn parse(input: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: lex(input)?,
+        pos: 0,
+        selectors: 0,
+    };
+    let expr = parser.expr(0)?;
+    match parser.peek_at(0) {
+        Token::Eof => Ok(expr),
+        token => Err(ParseError(format!("unexpected {:?}", token))),
+    }
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("5m"), Some(300_000));
+    assert_eq!(parse_duration("1h30m"), Some(5_400_000));
+    assert_eq!(parse_duration("100ms"), Some(100));
+    assert_eq!(parse_duration("1x"), None);
+    assert_eq!(parse_duration("m"), None);
+}
+
+#[test]
+fn test_parse() {
+    let expr =
+        parse(r#"sum by (job) (rate(http_requests_total{code=~"5.."}[5m] offset 1m)) / 2 ^ 3 ^ 2"#)
+            .unwrap();
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::Div,
+            lhs,
+            rhs,
+            ..
+        } => {
+            match *lhs {
+                Expr::Aggregate {
+                    op: AggregateOp::Sum,
+                    grouping: Grouping::By(labels),
+                    expr,
+                    ..
+                } => {
+                    assert_eq!(labels, vec!["job"]);
+                    match *expr {
+                        Expr::Call { func, args } => {
+                            assert_eq!(func, "rate");
+                            match &args[0] {
+                                Expr::MatrixSelector(selector, range) => {
+                                    assert_eq!(*range, 300_000);
+                                    assert_eq!(selector.offset, 60_000);
+                                    assert_eq!(selector.matchers.len(), 2);
+                                }
+                                expr => panic!("unexpected {:?}", expr),
+                            }
+                        }
+                        expr => panic!("unexpected {:?}", expr),
+                    }
+                }
+                expr => panic!("unexpected {:?}", expr),
+            }
+            // `^` is right associative
+            match *rhs {
+                Expr::Binary {
+                    op: BinaryOp::Pow,
+                    lhs,
+                    rhs,
+                    ..
+                } => {
+                    assert_eq!(*lhs, Expr::Number(2.));
+                    assert!(matches!(
+                        *rhs,
+                        Expr::Binary {
+                            op: BinaryOp::Pow,
+                            ..
+                        }
+                    ));
+                }
+                expr => panic!("unexpected {:?}", expr),
+            }
+        }
+        expr => panic!("unexpected {:?}", expr),
+    }
+
+    let expr = parse("a - b * on(job) group_left(env) c > bool 1").unwrap();
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::Gtr,
+            modifier,
+            lhs,
+            ..
+        } => {
+            assert!(modifier.return_bool);
+            assert!(matches!(
+                *lhs,
+                Expr::Binary {
+                    op: BinaryOp::Sub,
+                    ..
+                }
+            ));
+        }
+        expr => panic!("unexpected {:?}", expr),
+    }
+
+    assert_eq!(parse("-1 + 2").unwrap().selectors().len(), 0);
+    assert_eq!(
+        parse("topk(3, up) or up{job='a'}")
+            .unwrap()
+            .selectors()
+            .len(),
+        2
+    );
+    assert!(parse(r#"{job=""}"#).is_err());
+    assert!(parse("rate(up[5m:1m])").is_err());
+    assert!(parse("up and bool up").is_err());
+}
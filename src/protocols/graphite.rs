@@ -0,0 +1,283 @@
+//! Graphite plaintext protocol, `<path> <value> <timestamp>` lines.
+//!
+//! Dotted paths are split into a metric name and labels by templates, as in Telegraf
+//! and InfluxDB. A template is `[filter] <parts> [tag=value,...]`:
+//!
+//! - the filter selects paths by their dotted parts, `*` matching any part, the most
+//!   specific matching filter wins,
+//! - each part of the template names what the path part at its position is:
+//!   `measurement`, `field`, a label name, or empty to skip it, `measurement*` and
+//!   `field*` take the remaining parts,
+//! - the tags are extra labels of the matched series.
+//!
+//! The metric name is the measurement parts, followed by the field parts if any, joined by
+//! the separator. Without a matching template, the whole path is the metric name.
+//! Graphite tags, as in `path;tag=value`, are labels too.
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum GraphiteError {
+    #[error("invalid template {0:?}")]
+    Template(String),
+    #[error("invalid line {0:?}")]
+    Line(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    filter: Vec<String>,
+    parts: Vec<String>,
+    tags: Vec<(String, String)>,
+}
+
+impl FromStr for Template {
+    type Err = GraphiteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GraphiteError::Template(s.to_string());
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let (filter, parts, tags) = match fields.as_slice() {
+            [parts] => ("", *parts, ""),
+            [filter, parts] if !parts.contains('=') => (*filter, *parts, ""),
+            [parts, tags] => ("", *parts, *tags),
+            [filter, parts, tags] => (*filter, *parts, *tags),
+            _ => return Err(invalid()),
+        };
+        let tags = tags
+            .split(',')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.split_once('=') {
+                Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<_, _>>()?;
+        let parts: Vec<String> = parts.split('.').map(str::to_string).collect();
+        let greedy = parts
+            .iter()
+            .position(|part| part == "measurement*" || part == "field*");
+        if matches!(greedy, Some(index) if index + 1 != parts.len()) {
+            return Err(invalid());
+        }
+        Ok(Template {
+            filter: filter
+                .split('.')
+                .filter(|part| !part.is_empty())
+                .map(str::to_string)
+                .collect(),
+            parts,
+            tags,
+        })
+    }
+}
+
+impl Template {
+    fn matches(&self, path: &[&str]) -> bool {
+        self.filter.len() <= path.len()
+            && self
+                .filter
+                .iter()
+                .zip(path)
+                .all(|(filter, part)| filter == "*" || filter == part)
+    }
+
+    /// Exact filter parts rank over wildcards, longer filters over shorter ones.
+    fn specificity(&self) -> (usize, usize) {
+        let exact = self.filter.iter().filter(|part| *part != "*").count();
+        (self.filter.len(), exact)
+    }
+
+    /// Metric name and labels of a path.
+    fn apply(&self, path: &[&str], separator: &str) -> (String, Vec<(String, String)>) {
+        let mut measurement = Vec::new();
+        let mut field = Vec::new();
+        let mut tags: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (index, part) in self.parts.iter().enumerate() {
+            let value = match path.get(index) {
+                Some(value) => *value,
+                None => break,
+            };
+            match part.as_str() {
+                "" => {}
+                "measurement" => measurement.push(value),
+                "measurement*" => measurement.extend(&path[index..]),
+                "field" => field.push(value),
+                "field*" => field.extend(&path[index..]),
+                tag => tags.entry(tag).or_default().push(value),
+            }
+        }
+        if measurement.is_empty() {
+            measurement = path.to_vec();
+        }
+        let mut name = measurement.join(separator);
+        if !field.is_empty() {
+            name.push_str(separator);
+            name.push_str(&field.join(separator));
+        }
+        let labels = self
+            .tags
+            .iter()
+            .cloned()
+            .chain(
+                tags.into_iter()
+                    .map(|(tag, values)| (tag.to_string(), values.join("."))),
+            )
+            .collect();
+        (name, labels)
+    }
+}
+
+/// Templates tried in order of specificity.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: Vec<Template>,
+    separator: String,
+}
+
+impl Templates {
+    pub fn new(templates: &[String], separator: &str) -> Result<Self, GraphiteError> {
+        let mut templates = templates
+            .iter()
+            .map(|template| template.parse())
+            .collect::<Result<Vec<Template>, _>>()?;
+        // stable, templates of the same specificity are tried in configured order
+        templates.sort_by_key(|template| std::cmp::Reverse(template.specificity()));
+        Ok(Templates {
+            templates,
+            separator: separator.to_string(),
+        })
+    }
+
+    /// Metric name and labels of a dotted path.
+    pub fn apply(&self, path: &str) -> (String, Vec<(String, String)>) {
+        let parts: Vec<_> = path.split('.').collect();
+        match self.templates.iter().find(|t| t.matches(&parts)) {
+            Some(template) => template.apply(&parts, &self.separator),
+            None => (parts.join(&self.separator), Vec::new()),
+        }
+    }
+}
+
+/// A parsed line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// Timestamp in milliseconds.
+    pub timestamp: i64,
+}
+
+/// Parse a line, `None` for blank lines. Lines without a timestamp, or with `-1`, are at
+/// `now` in milliseconds.
+pub fn parse_line(
+    line: &str,
+    templates: &Templates,
+    now: i64,
+) -> Result<Option<Line>, GraphiteError> {
+    let invalid = || GraphiteError::Line(line.to_string());
+    let mut fields = line.split_whitespace();
+    let (path, value, timestamp) = match (fields.next(), fields.next(), fields.next()) {
+        (None, _, _) => return Ok(None),
+        (Some(path), Some(value), timestamp) => (path, value, timestamp),
+        _ => return Err(invalid()),
+    };
+    if fields.next().is_some() {
+        return Err(invalid());
+    }
+    let value: f64 = value.parse().map_err(|_| invalid())?;
+    let timestamp = match timestamp {
+        None | Some("-1") => now,
+        // seconds, possibly fractional
+        Some(timestamp) => {
+            let seconds: f64 = timestamp.parse().map_err(|_| invalid())?;
+            (seconds * 1000.) as i64
+        }
+    };
+    let mut tags = path.split(';');
+    let path = tags.next().unwrap_or_default();
+    if path.is_empty() {
+        return Err(invalid());
+    }
+    let (name, mut labels) = templates.apply(path);
+    for tag in tags {
+        match tag.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                labels.push((key.to_string(), value.to_string()))
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(Some(Line {
+        name,
+        labels,
+        value,
+        timestamp,
+    }))
+}
+
+#[test]
+fn test_templates() {
+    let templates = Templates::new(
+        &[
+            "servers.* .host.measurement.field* dc=east".to_string(),
+            "servers.web.* .role.host.measurement*".to_string(),
+            "stats.* .measurement..region".to_string(),
+        ],
+        "_",
+    )
+    .unwrap();
+    assert_eq!(
+        templates.apply("servers.localhost.cpu.load.1m"),
+        (
+            "cpu_load_1m".to_string(),
+            vec![
+                ("dc".to_string(), "east".to_string()),
+                ("host".to_string(), "localhost".to_string()),
+            ]
+        )
+    );
+    assert_eq!(
+        templates.apply("servers.web.web01.http.requests"),
+        (
+            "http_requests".to_string(),
+            vec![
+                ("host".to_string(), "web01".to_string()),
+                ("role".to_string(), "web".to_string()),
+            ]
+        )
+    );
+    assert_eq!(
+        templates.apply("stats.requests.x.eu"),
+        (
+            "requests".to_string(),
+            vec![("region".to_string(), "eu".to_string())]
+        )
+    );
+    assert_eq!(
+        templates.apply("other.path"),
+        ("other_path".to_string(), vec![])
+    );
+    assert!("a.measurement*.b".parse::<Template>().is_err());
+    assert!("a b c d".parse::<Template>().is_err());
+}
+
+#[test]
+fn test_parse_line() {
+    let templates = Templates::new(&[], "_").unwrap();
+    let line = parse_line("cpu.load;host=a 0.5 1600000000", &templates, 42)
+        .unwrap()
+        .unwrap();
+    assert_eq!(line.name, "cpu_load");
+    assert_eq!(line.labels, vec![("host".to_string(), "a".to_string())]);
+    assert_eq!(line.value, 0.5);
+    assert_eq!(line.timestamp, 1600000000000);
+    let line = parse_line("cpu 1 -1", &templates, 42).unwrap().unwrap();
+    assert_eq!(line.timestamp, 42);
+    assert_eq!(parse_line("  ", &templates, 42), Ok(None));
+    assert!(parse_line("cpu", &templates, 42).is_err());
+    assert!(parse_line("cpu x 1", &templates, 42).is_err());
+    assert!(parse_line("cpu 1 1 1", &templates, 42).is_err());
+}
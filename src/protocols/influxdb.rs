@@ -0,0 +1,344 @@
+//! InfluxDB line protocol.
+//!
+//! Each field of a point is a series named `<measurement>_<field>`, or `<measurement>`
+//! for a field named `value`, labeled by the tags of the point. Integer, unsigned and
+//! boolean fields are stored as doubles, string fields are skipped.
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::SeriesSet;
+
+/// Precision of line protocol timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl FromStr for Precision {
+    type Err = LineProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" | "n" => Ok(Precision::Nanoseconds),
+            "us" | "u" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            "m" => Ok(Precision::Minutes),
+            "h" => Ok(Precision::Hours),
+            _ => Err(LineProtocolError::Precision(s.to_string())),
+        }
+    }
+}
+
+impl Precision {
+    /// Timestamp in milliseconds, `None` if it overflows.
+    pub fn to_millis(self, timestamp: i64) -> Option<i64> {
+        match self {
+            Precision::Nanoseconds => Some(timestamp / 1_000_000),
+            Precision::Microseconds => Some(timestamp / 1_000),
+            Precision::Milliseconds => Some(timestamp),
+            Precision::Seconds => timestamp.checked_mul(1_000),
+            Precision::Minutes => timestamp.checked_mul(60_000),
+            Precision::Hours => timestamp.checked_mul(3_600_000),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LineProtocolError {
+    #[error("unknown precision {0}")]
+    Precision(String),
+    #[error("unable to parse line {line}: {message}")]
+    Syntax { line: usize, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Unsigned(u64),
+    Boolean(bool),
+    String(String),
+}
+
+impl FieldValue {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Float(value) => Some(*value),
+            FieldValue::Integer(value) => Some(*value as f64),
+            FieldValue::Unsigned(value) => Some(*value as f64),
+            FieldValue::Boolean(value) => Some(*value as u8 as f64),
+            FieldValue::String(_) => None,
+        }
+    }
+}
+
+impl FromStr for FieldValue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(quoted) = s.strip_prefix('"') {
+            let string = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| format!("unterminated string {}", s))?;
+            return Ok(FieldValue::String(unescape(string)));
+        }
+        let invalid = || format!("invalid field value {}", s);
+        if let Some(integer) = s.strip_suffix('i') {
+            return integer
+                .parse()
+                .map(FieldValue::Integer)
+                .map_err(|_| invalid());
+        }
+        if let Some(unsigned) = s.strip_suffix('u') {
+            return unsigned
+                .parse()
+                .map(FieldValue::Unsigned)
+                .map_err(|_| invalid());
+        }
+        match s {
+            "t" | "T" | "true" | "True" | "TRUE" => Ok(FieldValue::Boolean(true)),
+            "f" | "F" | "false" | "False" | "FALSE" => Ok(FieldValue::Boolean(false)),
+            _ => s.parse().map(FieldValue::Float).map_err(|_| invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    /// Timestamp in the precision of the request, if any.
+    pub timestamp: Option<i64>,
+}
+
+/// Remove backslashes escaping the next character.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next @ (',' | '=' | ' ' | '"' | '\\')) => unescaped.push(next),
+                Some(next) => {
+                    unescaped.push(c);
+                    unescaped.push(next);
+                }
+                None => unescaped.push(c),
+            },
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Split at separators that are not escaped, nor inside double quotes if `quoted`.
+fn split(s: &str, separator: char, quoted: bool, max: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut in_quotes = false;
+    for (index, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' && quoted {
+            in_quotes = !in_quotes;
+        } else if c == separator && !in_quotes && parts.len() + 1 < max {
+            parts.push(&s[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Split a `key=value` pair at the first unescaped `=`.
+fn pair(s: &str) -> Result<(String, &str), String> {
+    match split(s, '=', false, 2).as_slice() {
+        [key, value] if !key.is_empty() && !value.is_empty() => Ok((unescape(key), value)),
+        _ => Err(format!("invalid key value pair {}", s)),
+    }
+}
+
+/// Parse a line, `None` for blank lines and comments.
+pub fn parse_line(line: &str) -> Result<Option<Point>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (key, rest) = match split(line, ' ', false, 2).as_slice() {
+        [key, rest] => (*key, rest.trim_start()),
+        _ => return Err("missing fields".to_string()),
+    };
+    let (fields, timestamp) = match split(rest, ' ', true, 2).as_slice() {
+        [fields] => (*fields, None),
+        [fields, timestamp] => (*fields, Some(timestamp.trim())),
+        _ => unreachable!("split into at most two parts"),
+    };
+
+    let mut key = split(key, ',', false, usize::MAX).into_iter();
+    let measurement = unescape(key.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err("missing measurement".to_string());
+    }
+    let tags = key
+        .map(|tag| pair(tag).map(|(key, value)| (key, unescape(value))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let fields = split(fields, ',', true, usize::MAX)
+        .into_iter()
+        .map(|field| {
+            let (key, value) = pair(field)?;
+            Ok((key, value.parse()?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let timestamp = match timestamp {
+        Some(timestamp) if !timestamp.is_empty() => Some(
+            timestamp
+                .parse()
+                .map_err(|_| format!("invalid timestamp {}", timestamp))?,
+        ),
+        _ => None,
+    };
+    Ok(Some(Point {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    }))
+}
+
+/// Parse a request body into series, points without a timestamp are at `now` in
+/// milliseconds. Valid lines are kept if some are invalid, the errors are returned
+/// along with them.
+pub fn parse(body: &str, precision: Precision, now: i64) -> (SeriesSet, Vec<LineProtocolError>) {
+    let mut set = SeriesSet::default();
+    let mut errors = Vec::new();
+    for (index, line) in body.lines().enumerate() {
+        let point = match parse_line(line) {
+            Ok(Some(point)) => point,
+            Ok(None) => continue,
+            Err(message) => {
+                errors.push(LineProtocolError::Syntax {
+                    line: index + 1,
+                    message,
+                });
+                continue;
+            }
+        };
+        let timestamp = match point.timestamp {
+            Some(timestamp) => match precision.to_millis(timestamp) {
+                Some(timestamp) => timestamp,
+                None => {
+                    errors.push(LineProtocolError::Syntax {
+                        line: index + 1,
+                        message: format!("timestamp {} out of range", timestamp),
+                    });
+                    continue;
+                }
+            },
+            None => now,
+        };
+        for (field, value) in &point.fields {
+            let value = match value.as_f64() {
+                Some(value) => value,
+                None => {
+                    log::trace!("skip string field {}.{}", point.measurement, field);
+                    continue;
+                }
+            };
+            let name = if field == "value" {
+                point.measurement.clone()
+            } else {
+                format!("{}_{}", point.measurement, field)
+            };
+            set.push(&name, point.tags.iter().cloned(), timestamp, value);
+        }
+    }
+    (set, errors)
+}
+
+#[test]
+fn test_parse_line() {
+    let point = parse_line(
+        r#"cpu\ load,host=server\ 01,region=us\,west usage=0.5,count=3i,up=t,msg="a \"b\", c" 1556813561098000000"#,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(point.measurement, "cpu load");
+    assert_eq!(
+        point.tags,
+        vec![
+            ("host".to_string(), "server 01".to_string()),
+            ("region".to_string(), "us,west".to_string()),
+        ]
+    );
+    assert_eq!(
+        point.fields,
+        vec![
+            ("usage".to_string(), FieldValue::Float(0.5)),
+            ("count".to_string(), FieldValue::Integer(3)),
+            ("up".to_string(), FieldValue::Boolean(true)),
+            (
+                "msg".to_string(),
+                FieldValue::String(r#"a "b", c"#.to_string())
+            ),
+        ]
+    );
+    assert_eq!(point.timestamp, Some(1556813561098000000));
+
+    assert_eq!(parse_line("# comment").unwrap(), None);
+    assert!(parse_line("cpu").is_err());
+    assert!(parse_line("cpu value=x").is_err());
+    assert!(parse_line("cpu,host value=1").is_err());
+    assert!(parse_line("cpu value=1 abc").is_err());
+}
+
+#[test]
+fn test_parse() {
+    let body = "cpu,host=a value=1,idle=2u 1000\nmem used=3\nbad\ndisk path=\"/\"\n\
+                up value=1 9223372036854775807";
+    let (set, errors) = parse(body, Precision::Seconds, 42);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(
+        errors[0].to_string(),
+        "unable to parse line 3: missing fields"
+    );
+    assert_eq!(
+        errors[1].to_string(),
+        "unable to parse line 5: timestamp 9223372036854775807 out of range"
+    );
+    let mut request = set.into_write_request();
+    request
+        .timeseries
+        .sort_by(|a, b| a.labels[0].value.cmp(&b.labels[0].value));
+    let series = request
+        .timeseries
+        .iter()
+        .map(|ts| {
+            (
+                ts.labels[0].value.as_str(),
+                ts.labels.len(),
+                ts.samples[0].timestamp,
+                ts.samples[0].value,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        series,
+        vec![
+            ("cpu", 2, 1_000_000, Some(1.)),
+            ("cpu_idle", 2, 1_000_000, Some(2.)),
+            ("mem_used", 1, 42, Some(3.)),
+        ]
+    );
+    assert!("x".parse::<Precision>().is_err());
+}
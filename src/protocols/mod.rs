@@ -0,0 +1,162 @@
+//! Ingestion protocols besides Prometheus remote write.
+//!
+//! Each protocol is converted into Prometheus-style series, so that samples of any
+//! protocol are stored with the same super table and tag layout.
+use std::collections::HashMap;
+
+use crate::prometheus::types::*;
+
+pub mod graphite;
+pub mod influxdb;
+pub mod opentsdb;
+pub mod otlp;
+pub mod scrape;
+pub mod statsd;
+
+/// Metric name with characters outside `[a-zA-Z0-9_:]` replaced by `_`.
+pub fn metric_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Label name with characters outside `[a-zA-Z0-9_]` replaced by `_`.
+pub fn label_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sanitize(name: &str, valid: impl Fn(char) -> bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if valid(c) { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Samples grouped into series by metric name and labels.
+#[derive(Debug, Default)]
+pub struct SeriesSet {
+    series: HashMap<Vec<Label>, Vec<Sample>>,
+}
+
+/// Labels of a series, metric and label names are sanitized, labels with empty names or
+/// values are dropped and the last of duplicate labels is kept.
+pub fn series_labels<I, K, V>(name: &str, labels: I) -> Vec<Label>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: Into<String>,
+{
+    let mut labels: Vec<Label> = labels
+        .into_iter()
+        .map(|(name, value)| Label {
+            name: label_name(name.as_ref()),
+            value: value.into(),
+        })
+        .filter(|label| !label.name.is_empty() && !label.value.is_empty())
+        .collect();
+    labels.retain(|label| label.name != "__name__");
+    labels.reverse();
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels.dedup_by(|a, b| a.name == b.name);
+    labels.insert(
+        0,
+        Label {
+            name: "__name__".to_string(),
+            value: metric_name(name),
+        },
+    );
+    labels
+}
+
+impl SeriesSet {
+    /// Add a sample, labeled as by [series_labels].
+    pub fn push<I, K, V>(&mut self, name: &str, labels: I, timestamp: i64, value: f64)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: Into<String>,
+    {
+        self.push_series(series_labels(name, labels), timestamp, value);
+    }
+
+    /// Add a sample of a series with labels built by [series_labels].
+    pub fn push_series(&mut self, labels: Vec<Label>, timestamp: i64, value: f64) {
+        self.series.entry(labels).or_default().push(Sample {
+            value: Some(value),
+            timestamp,
+        });
+    }
+
+    /// Number of series.
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    pub fn into_write_request(self) -> WriteRequest {
+        WriteRequest {
+            timeseries: self
+                .series
+                .into_iter()
+                .map(|(labels, samples)| TimeSeries { labels, samples })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Labels of each series joined as `name=value,...`, with their sample values, sorted.
+#[cfg(test)]
+fn series_of(set: SeriesSet) -> Vec<(String, Vec<f64>)> {
+    let mut series = set
+        .into_write_request()
+        .timeseries
+        .into_iter()
+        .map(|ts| {
+            let labels = ts
+                .labels
+                .iter()
+                .map(|label| format!("{}={}", label.name, label.value))
+                .collect::<Vec<_>>()
+                .join(",");
+            let values = ts
+                .samples
+                .iter()
+                .map(|sample| sample.value.unwrap())
+                .collect();
+            (labels, values)
+        })
+        .collect::<Vec<_>>();
+    series.sort_by(|a, b| a.0.cmp(&b.0));
+    series
+}
+
+#[test]
+fn test_series_set() {
+    assert_eq!(metric_name("cpu.usage-idle"), "cpu_usage_idle");
+    assert_eq!(metric_name("node:cpu"), "node:cpu");
+    assert_eq!(label_name("1host:name"), "_1host_name");
+
+    let mut set = SeriesSet::default();
+    set.push(
+        "cpu",
+        vec![("host", "a"), ("dc", "x"), ("host", "b")],
+        1,
+        1.,
+    );
+    set.push("cpu", vec![("dc", "x"), ("host", "b")], 2, 2.);
+    set.push("cpu", vec![("dc", "x"), ("empty", "")], 3, 3.);
+    assert_eq!(set.len(), 2);
+    assert_eq!(
+        series_of(set),
+        vec![
+            ("__name__=cpu,dc=x".to_string(), vec![3.]),
+            ("__name__=cpu,dc=x,host=b".to_string(), vec![1., 2.]),
+        ]
+    );
+}
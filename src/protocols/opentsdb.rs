@@ -0,0 +1,153 @@
+//! OpenTSDB `/api/put` JSON and telnet `put` protocol.
+//!
+//! A metric is a series of the same name, labeled by its tags. Timestamps are in seconds,
+//! or in milliseconds if they do not fit 32 bits, as OpenTSDB tells them apart.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::SeriesSet;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataPoint {
+    pub metric: String,
+    pub timestamp: i64,
+    /// Number, or number in a string.
+    pub value: serde_json::Value,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Body of `/api/put`, a data point or an array of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Put {
+    One(DataPoint),
+    Many(Vec<DataPoint>),
+}
+
+/// A data point that could not be stored, listed by `/api/put?details`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PutError {
+    pub datapoint: DataPoint,
+    pub error: String,
+}
+
+impl DataPoint {
+    /// Value of a valid data point.
+    pub fn validate(&self) -> Result<f64, String> {
+        if self.metric.is_empty() {
+            return Err("metric name is empty".to_string());
+        }
+        if self.tags.is_empty() {
+            return Err("at least one tag is required".to_string());
+        }
+        if self.timestamp <= 0 {
+            return Err(format!("invalid timestamp {}", self.timestamp));
+        }
+        let value = match &self.value {
+            serde_json::Value::Number(value) => value.as_f64(),
+            serde_json::Value::String(value) => value.parse().ok(),
+            _ => None,
+        };
+        value.ok_or_else(|| format!("invalid value {}", self.value))
+    }
+
+    pub fn timestamp_millis(&self) -> i64 {
+        if self.timestamp > u32::MAX as i64 {
+            self.timestamp
+        } else {
+            self.timestamp * 1000
+        }
+    }
+}
+
+pub fn parse_put(body: &[u8]) -> serde_json::Result<Vec<DataPoint>> {
+    Ok(match serde_json::from_slice(body)? {
+        Put::One(point) => vec![point],
+        Put::Many(points) => points,
+    })
+}
+
+/// Parse a telnet `put <metric> <timestamp> <value> <tagk=tagv>...` command, without
+/// the leading `put`.
+pub fn parse_telnet_put(args: &str) -> Result<DataPoint, String> {
+    let mut args = args.split_whitespace();
+    let (metric, timestamp, value) = match (args.next(), args.next(), args.next()) {
+        (Some(metric), Some(timestamp), Some(value)) => (metric, timestamp, value),
+        _ => return Err("not enough arguments, need a metric, timestamp and value".to_string()),
+    };
+    let timestamp = timestamp
+        .parse()
+        .map_err(|_| format!("invalid timestamp {}", timestamp))?;
+    let tags = args
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+                Ok((key.to_string(), value.to_string()))
+            }
+            _ => Err(format!("invalid tag {}", tag)),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(DataPoint {
+        metric: metric.to_string(),
+        timestamp,
+        value: serde_json::Value::String(value.to_string()),
+        tags,
+    })
+}
+
+/// Convert data points into series, invalid ones are returned as errors.
+pub fn convert(points: Vec<DataPoint>, set: &mut SeriesSet) -> Vec<PutError> {
+    let mut errors = Vec::new();
+    for datapoint in points {
+        match datapoint.validate() {
+            Ok(value) => set.push(
+                &datapoint.metric,
+                &datapoint.tags,
+                datapoint.timestamp_millis(),
+                value,
+            ),
+            Err(error) => errors.push(PutError { datapoint, error }),
+        }
+    }
+    errors
+}
+
+#[test]
+fn test_put() {
+    let points = parse_put(
+        br#"[
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 18, "tags": {"host": "web01"}},
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400500, "value": "9.5", "tags": {"host": "web02"}},
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 1, "tags": {}},
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": "x", "tags": {"host": "web01"}}
+        ]"#,
+    )
+    .unwrap();
+    let mut set = SeriesSet::default();
+    let errors = convert(points, &mut set);
+    assert_eq!(set.len(), 2);
+    assert_eq!(
+        errors.iter().map(|e| e.error.as_str()).collect::<Vec<_>>(),
+        vec!["at least one tag is required", "invalid value \"x\""]
+    );
+    let mut request = set.into_write_request();
+    request.timeseries.sort_by_key(|ts| ts.samples[0].timestamp);
+    assert_eq!(request.timeseries[0].labels[0].value, "sys_cpu_nice");
+    assert_eq!(request.timeseries[0].samples[0].timestamp, 1346846400000);
+    assert_eq!(request.timeseries[1].samples[0].timestamp, 1346846400500);
+    assert_eq!(request.timeseries[1].samples[0].value, Some(9.5));
+
+    let point = parse_put(br#"{"metric": "m", "timestamp": 1, "value": 1}"#).unwrap();
+    assert_eq!(point.len(), 1);
+}
+
+#[test]
+fn test_telnet_put() {
+    let point = parse_telnet_put("sys.cpu.user 1356998400 42.5 host=web01 cpu=0").unwrap();
+    assert_eq!(point.metric, "sys.cpu.user");
+    assert_eq!(point.validate(), Ok(42.5));
+    assert_eq!(point.tags.len(), 2);
+    assert!(parse_telnet_put("sys.cpu.user 1356998400").is_err());
+    assert!(parse_telnet_put("sys.cpu.user 1356998400 1 host").is_err());
+}
@@ -0,0 +1,963 @@
+//! OpenTelemetry metrics of OTLP/HTTP requests, in protobuf or JSON encoding.
+//!
+//! Metrics are converted into Prometheus series following the OpenTelemetry to
+//! Prometheus conventions:
+//!
+//! - names get a unit suffix, and `_total` for monotonic sums,
+//! - histograms and exponential histograms become `_bucket`, `_count` and `_sum` series,
+//!   summaries become quantile, `_count` and `_sum` series,
+//! - `service.name` and `service.instance.id` resource attributes become `job` and
+//!   `instance` labels, other resource attributes are labels of a `target_info` series.
+//!
+//! Delta sums and histograms are accumulated into cumulative series in memory.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::{de, Deserialize, Deserializer};
+
+#[cfg(test)]
+use super::series_of;
+use super::{series_labels, SeriesSet};
+use crate::prometheus::types::*;
+
+/// Aggregation temporality of sums and histograms.
+pub const TEMPORALITY_DELTA: i32 = 1;
+/// Data point flag of a point without a value, e.g. after its series disappeared.
+const FLAG_NO_RECORDED_VALUE: u32 = 1;
+
+/// 64-bit integers are strings in the JSON encoding, numbers are accepted too.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Int<T> {
+    Number(T),
+    String(String),
+}
+
+impl<T: FromStr> Int<T> {
+    fn value<E: de::Error>(self) -> Result<T, E> {
+        match self {
+            Int::Number(value) => Ok(value),
+            Int::String(value) => value
+                .parse()
+                .map_err(|_| E::custom(format!("invalid integer {}", value))),
+        }
+    }
+}
+
+fn int<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+{
+    Int::deserialize(deserializer)?.value()
+}
+
+fn ints<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+{
+    Vec::<Int<T>>::deserialize(deserializer)?
+        .into_iter()
+        .map(Int::value)
+        .collect()
+}
+
+fn base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    base64::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+/// Empty response of a successful export.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExportMetricsServiceResponse {}
+
+/// Response of a failed export, `google.rpc.Status`.
+#[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AnyValue {
+    #[prost(oneof = "Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+    #[serde(flatten)]
+    pub value: Option<Value>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Value {
+    #[prost(string, tag = "1")]
+    StringValue(String),
+    #[prost(bool, tag = "2")]
+    BoolValue(bool),
+    #[prost(int64, tag = "3")]
+    #[serde(deserialize_with = "int")]
+    IntValue(i64),
+    #[prost(double, tag = "4")]
+    DoubleValue(f64),
+    #[prost(message, tag = "5")]
+    ArrayValue(ArrayValue),
+    #[prost(message, tag = "6")]
+    KvlistValue(KeyValueList),
+    #[prost(bytes = "vec", tag = "7")]
+    #[serde(deserialize_with = "base64")]
+    BytesValue(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    #[prost(oneof = "Data", tags = "5, 7, 9, 10, 11")]
+    #[serde(flatten)]
+    pub data: Option<Data>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Data {
+    #[prost(message, tag = "5")]
+    Gauge(Gauge),
+    #[prost(message, tag = "7")]
+    Sum(Sum),
+    #[prost(message, tag = "9")]
+    Histogram(Histogram),
+    #[prost(message, tag = "10")]
+    ExponentialHistogram(ExponentialHistogram),
+    #[prost(message, tag = "11")]
+    Summary(Summary),
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    pub is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Histogram {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<HistogramDataPoint>,
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExponentialHistogram {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<ExponentialHistogramDataPoint>,
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Summary {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<SummaryDataPoint>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "int")]
+    pub time_unix_nano: u64,
+    #[prost(oneof = "NumberValue", tags = "4, 6")]
+    #[serde(flatten)]
+    pub value: Option<NumberValue>,
+    #[prost(uint32, tag = "8")]
+    pub flags: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NumberValue {
+    #[prost(double, tag = "4")]
+    AsDouble(f64),
+    #[prost(sfixed64, tag = "6")]
+    #[serde(deserialize_with = "int")]
+    AsInt(i64),
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HistogramDataPoint {
+    #[prost(message, repeated, tag = "9")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "int")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(deserialize_with = "int")]
+    pub count: u64,
+    #[prost(double, optional, tag = "5")]
+    pub sum: Option<f64>,
+    /// Counts of each bucket, not cumulative.
+    #[prost(fixed64, repeated, tag = "6")]
+    #[serde(deserialize_with = "ints")]
+    pub bucket_counts: Vec<u64>,
+    #[prost(double, repeated, tag = "7")]
+    pub explicit_bounds: Vec<f64>,
+    #[prost(uint32, tag = "10")]
+    pub flags: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExponentialHistogramDataPoint {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "int")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(deserialize_with = "int")]
+    pub count: u64,
+    #[prost(double, optional, tag = "5")]
+    pub sum: Option<f64>,
+    #[prost(sint32, tag = "6")]
+    pub scale: i32,
+    #[prost(fixed64, tag = "7")]
+    #[serde(deserialize_with = "int")]
+    pub zero_count: u64,
+    #[prost(message, optional, tag = "8")]
+    pub positive: Option<Buckets>,
+    #[prost(message, optional, tag = "9")]
+    pub negative: Option<Buckets>,
+    #[prost(uint32, tag = "10")]
+    pub flags: u32,
+    #[prost(double, tag = "14")]
+    pub zero_threshold: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Buckets {
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint64, repeated, tag = "2")]
+    #[serde(deserialize_with = "ints")]
+    pub bucket_counts: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SummaryDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "int")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(deserialize_with = "int")]
+    pub count: u64,
+    #[prost(double, tag = "5")]
+    pub sum: f64,
+    #[prost(message, repeated, tag = "6")]
+    pub quantile_values: Vec<ValueAtQuantile>,
+    #[prost(uint32, tag = "8")]
+    pub flags: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ValueAtQuantile {
+    #[prost(double, tag = "1")]
+    pub quantile: f64,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+}
+
+impl fmt::Display for AnyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            None => Ok(()),
+            Some(Value::StringValue(value)) => f.write_str(value),
+            Some(Value::BoolValue(value)) => write!(f, "{}", value),
+            Some(Value::IntValue(value)) => write!(f, "{}", value),
+            Some(Value::DoubleValue(value)) => write!(f, "{}", value),
+            Some(Value::BytesValue(value)) => f.write_str(&base64::encode(value)),
+            Some(Value::ArrayValue(array)) => {
+                f.write_str("[")?;
+                for (index, value) in array.values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            Some(Value::KvlistValue(list)) => {
+                f.write_str("{")?;
+                for (index, kv) in list.values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:", kv.key)?;
+                    if let Some(value) = &kv.value {
+                        write!(f, "{}", value)?;
+                    }
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn attributes(attributes: &[KeyValue]) -> impl Iterator<Item = (&str, String)> {
+    attributes.iter().map(|kv| {
+        let value = kv.value.as_ref().map(|v| v.to_string()).unwrap_or_default();
+        (kv.key.as_str(), value)
+    })
+}
+
+/// Prometheus name of a unit, e.g. `seconds` for `s`.
+fn unit_name(unit: &str) -> &str {
+    match unit {
+        "d" => "days",
+        "h" => "hours",
+        "min" => "minutes",
+        "s" => "seconds",
+        "ms" => "milliseconds",
+        "us" => "microseconds",
+        "ns" => "nanoseconds",
+        "By" => "bytes",
+        "KiBy" => "kibibytes",
+        "MiBy" => "mebibytes",
+        "GiBy" => "gibibytes",
+        "TiBy" => "tibibytes",
+        "KBy" => "kilobytes",
+        "MBy" => "megabytes",
+        "GBy" => "gigabytes",
+        "TBy" => "terabytes",
+        "m" => "meters",
+        "V" => "volts",
+        "A" => "amperes",
+        "J" => "joules",
+        "W" => "watts",
+        "g" => "grams",
+        "Cel" => "celsius",
+        "Hz" => "hertz",
+        "%" => "percent",
+        unit => unit,
+    }
+}
+
+/// Prometheus name of a unit in a rate, e.g. `second` for `By/s`.
+fn per_unit_name(unit: &str) -> &str {
+    match unit {
+        "s" => "second",
+        "m" => "minute",
+        "h" => "hour",
+        "d" => "day",
+        "w" => "week",
+        "mo" => "month",
+        "y" => "year",
+        unit => unit,
+    }
+}
+
+/// Metric name with unit suffix, `_total` suffix for counters.
+pub fn prometheus_name(name: &str, unit: &str, gauge: bool, counter: bool) -> String {
+    let mut name = name.to_string();
+    // annotations in braces are not units
+    let mut unit = unit.to_string();
+    while let (Some(start), Some(end)) = (unit.find('{'), unit.find('}')) {
+        if end < start {
+            break;
+        }
+        unit.replace_range(start..=end, "");
+    }
+    let suffix = match unit.split_once('/') {
+        _ if unit == "1" && gauge => "ratio".to_string(),
+        _ if unit == "1" => String::new(),
+        Some(("", per)) => format!("per_{}", per_unit_name(per)),
+        Some((unit, per)) => format!("{}_per_{}", unit_name(unit), per_unit_name(per)),
+        None => unit_name(&unit).to_string(),
+    };
+    if !suffix.is_empty() && !name.contains(&suffix) {
+        name = format!("{}_{}", name, suffix);
+    }
+    if counter && !name.ends_with("_total") {
+        name.push_str("_total");
+    }
+    name
+}
+
+/// Time in milliseconds after which the total of a delta series without points is
+/// forgotten, it restarts from zero like a counter reset if points come again.
+const DELTA_IDLE_MS: i64 = 60 * 60 * 1000;
+
+/// Running total of a delta series.
+#[derive(Debug)]
+struct DeltaTotal {
+    total: f64,
+    /// Time of the latest point added, points of a request sent again are not added twice.
+    time_unix_nano: u64,
+    /// When the latest point was received, in milliseconds.
+    updated: i64,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    series: HashMap<Vec<Label>, DeltaTotal>,
+    /// When idle series were evicted, in milliseconds.
+    evicted: i64,
+}
+
+/// Running totals of delta series, to store them as cumulative series.
+#[derive(Debug, Default)]
+pub struct DeltaTotals(Mutex<Totals>);
+
+impl DeltaTotals {
+    /// Add the delta of a point received at `now`, returns the total at the point, or
+    /// `None` if the point is older than the latest point of the series.
+    fn add(&self, labels: &[Label], delta: f64, time_unix_nano: u64, now: i64) -> Option<f64> {
+        let mut totals = self.0.lock().unwrap();
+        if now - totals.evicted >= DELTA_IDLE_MS {
+            totals
+                .series
+                .retain(|_, total| now - total.updated < DELTA_IDLE_MS);
+            totals.evicted = now;
+        }
+        match totals.series.get_mut(labels) {
+            Some(total) if time_unix_nano < total.time_unix_nano => None,
+            Some(total) => {
+                // the same point again, e.g. a retry after a failed write
+                if time_unix_nano > total.time_unix_nano {
+                    total.total += delta;
+                    total.time_unix_nano = time_unix_nano;
+                }
+                total.updated = now;
+                Some(total.total)
+            }
+            None => {
+                let total = DeltaTotal {
+                    total: delta,
+                    time_unix_nano,
+                    updated: now,
+                };
+                totals.series.insert(labels.to_vec(), total);
+                Some(delta)
+            }
+        }
+    }
+}
+
+/// Converts metrics of a resource and scope into series.
+struct Converter<'a> {
+    set: &'a mut SeriesSet,
+    totals: &'a DeltaTotals,
+    /// Labels of the resource and scope.
+    labels: Vec<(String, String)>,
+    /// Time the request was received, in milliseconds.
+    now: i64,
+}
+
+impl Converter<'_> {
+    fn push(
+        &mut self,
+        name: &str,
+        point_attributes: &[KeyValue],
+        extra: Option<(&str, String)>,
+        time_unix_nano: u64,
+        value: f64,
+        delta: bool,
+    ) {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.clone()))
+            .chain(attributes(point_attributes))
+            .chain(extra);
+        let labels = series_labels(name, labels);
+        let value = if delta {
+            match self.totals.add(&labels, value, time_unix_nano, self.now) {
+                Some(total) => total,
+                None => return,
+            }
+        } else {
+            value
+        };
+        self.set
+            .push_series(labels, (time_unix_nano / 1_000_000) as i64, value);
+    }
+
+    fn metric(&mut self, metric: &Metric) {
+        let data = match &metric.data {
+            Some(data) => data,
+            None => return,
+        };
+        match data {
+            Data::Gauge(gauge) => {
+                let name = prometheus_name(&metric.name, &metric.unit, true, false);
+                self.numbers(&name, &gauge.data_points, false);
+            }
+            Data::Sum(sum) => {
+                let name = prometheus_name(
+                    &metric.name,
+                    &metric.unit,
+                    !sum.is_monotonic,
+                    sum.is_monotonic,
+                );
+                let delta = sum.aggregation_temporality == TEMPORALITY_DELTA;
+                self.numbers(&name, &sum.data_points, delta);
+            }
+            Data::Histogram(histogram) => {
+                let name = prometheus_name(&metric.name, &metric.unit, false, false);
+                let delta = histogram.aggregation_temporality == TEMPORALITY_DELTA;
+                for point in &histogram.data_points {
+                    if point.flags & FLAG_NO_RECORDED_VALUE != 0 {
+                        continue;
+                    }
+                    let bounds = point
+                        .explicit_bounds
+                        .iter()
+                        .copied()
+                        .chain(std::iter::once(f64::INFINITY));
+                    let buckets = bounds.zip(point.bucket_counts.iter().copied());
+                    self.buckets(
+                        &name,
+                        &point.attributes,
+                        point.time_unix_nano,
+                        buckets,
+                        delta,
+                    );
+                    self.count_sum(
+                        &name,
+                        &point.attributes,
+                        point.time_unix_nano,
+                        point.count,
+                        point.sum,
+                        delta,
+                    );
+                }
+            }
+            Data::ExponentialHistogram(histogram) => {
+                let name = prometheus_name(&metric.name, &metric.unit, false, false);
+                let delta = histogram.aggregation_temporality == TEMPORALITY_DELTA;
+                for point in &histogram.data_points {
+                    if point.flags & FLAG_NO_RECORDED_VALUE != 0 {
+                        continue;
+                    }
+                    let buckets = exponential_buckets(point);
+                    self.buckets(
+                        &name,
+                        &point.attributes,
+                        point.time_unix_nano,
+                        buckets.into_iter(),
+                        delta,
+                    );
+                    self.count_sum(
+                        &name,
+                        &point.attributes,
+                        point.time_unix_nano,
+                        point.count,
+                        point.sum,
+                        delta,
+                    );
+                }
+            }
+            Data::Summary(summary) => {
+                let name = prometheus_name(&metric.name, &metric.unit, false, false);
+                for point in &summary.data_points {
+                    if point.flags & FLAG_NO_RECORDED_VALUE != 0 {
+                        continue;
+                    }
+                    for quantile in &point.quantile_values {
+                        let label = ("quantile", float_label(quantile.quantile));
+                        self.push(
+                            &name,
+                            &point.attributes,
+                            Some(label),
+                            point.time_unix_nano,
+                            quantile.value,
+                            false,
+                        );
+                    }
+                    self.count_sum(
+                        &name,
+                        &point.attributes,
+                        point.time_unix_nano,
+                        point.count,
+                        Some(point.sum),
+                        false,
+                    );
+                }
+            }
+        }
+    }
+
+    fn numbers(&mut self, name: &str, points: &[NumberDataPoint], delta: bool) {
+        for point in points {
+            if point.flags & FLAG_NO_RECORDED_VALUE != 0 {
+                continue;
+            }
+            let value = match point.value {
+                Some(NumberValue::AsDouble(value)) => value,
+                Some(NumberValue::AsInt(value)) => value as f64,
+                None => continue,
+            };
+            self.push(
+                name,
+                &point.attributes,
+                None,
+                point.time_unix_nano,
+                value,
+                delta,
+            );
+        }
+    }
+
+    /// Cumulative `_bucket` series of bucket upper bounds and their counts.
+    fn buckets(
+        &mut self,
+        name: &str,
+        attributes: &[KeyValue],
+        time_unix_nano: u64,
+        buckets: impl Iterator<Item = (f64, u64)>,
+        delta: bool,
+    ) {
+        let name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in buckets {
+            cumulative += count;
+            let label = ("le", float_label(bound));
+            self.push(
+                &name,
+                attributes,
+                Some(label),
+                time_unix_nano,
+                cumulative as f64,
+                delta,
+            );
+        }
+    }
+
+    fn count_sum(
+        &mut self,
+        name: &str,
+        attributes: &[KeyValue],
+        time_unix_nano: u64,
+        count: u64,
+        sum: Option<f64>,
+        delta: bool,
+    ) {
+        let count_name = format!("{}_count", name);
+        self.push(
+            &count_name,
+            attributes,
+            None,
+            time_unix_nano,
+            count as f64,
+            delta,
+        );
+        if let Some(sum) = sum {
+            let sum_name = format!("{}_sum", name);
+            self.push(&sum_name, attributes, None, time_unix_nano, sum, delta);
+        }
+    }
+}
+
+/// Label value of a bucket bound or quantile, `+Inf` for infinity.
+fn float_label(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Upper bounds and counts of exponential buckets in ascending order, ending with `+Inf`.
+fn exponential_buckets(point: &ExponentialHistogramDataPoint) -> Vec<(f64, u64)> {
+    let base = 2f64.powf(2f64.powi(-point.scale));
+    let mut buckets = Vec::new();
+    // negative bucket `index` covers [-base^(index + 1), -base^index)
+    if let Some(negative) = &point.negative {
+        for (i, count) in negative.bucket_counts.iter().enumerate().rev() {
+            let index = negative.offset + i as i32;
+            buckets.push((-base.powi(index), *count));
+        }
+    }
+    buckets.push((point.zero_threshold, point.zero_count));
+    // positive bucket `index` covers (base^index, base^(index + 1)]
+    if let Some(positive) = &point.positive {
+        for (i, count) in positive.bucket_counts.iter().enumerate() {
+            let index = positive.offset + i as i32;
+            buckets.push((base.powi(index + 1), *count));
+        }
+    }
+    buckets.push((f64::INFINITY, 0));
+    buckets
+}
+
+/// Convert the metrics of a request into series, `now` in milliseconds is the time of
+/// `target_info` series.
+pub fn convert(request: &ExportMetricsServiceRequest, totals: &DeltaTotals, now: i64) -> SeriesSet {
+    let mut set = SeriesSet::default();
+    for resource_metrics in &request.resource_metrics {
+        let resource = resource_metrics
+            .resource
+            .as_ref()
+            .map(|resource| resource.attributes.as_slice())
+            .unwrap_or_default();
+        let get = |key: &str| {
+            attributes(resource)
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| value)
+        };
+        let job = match (get("service.namespace"), get("service.name")) {
+            (Some(namespace), Some(name)) => Some(format!("{}/{}", namespace, name)),
+            (None, name) => name,
+            (Some(_), None) => None,
+        };
+        let mut labels = Vec::new();
+        labels.extend(job.map(|job| ("job".to_string(), job)));
+        labels.extend(get("service.instance.id").map(|id| ("instance".to_string(), id)));
+
+        let info = attributes(resource)
+            .filter(|(key, _)| {
+                !matches!(
+                    *key,
+                    "service.name" | "service.namespace" | "service.instance.id"
+                )
+            })
+            .collect::<Vec<_>>();
+        if !info.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.clone()))
+                .chain(info);
+            set.push("target_info", labels, now, 1.);
+        }
+
+        for scope_metrics in &resource_metrics.scope_metrics {
+            let mut labels = labels.clone();
+            if let Some(scope) = &scope_metrics.scope {
+                labels.push(("otel_scope_name".to_string(), scope.name.clone()));
+                labels.push(("otel_scope_version".to_string(), scope.version.clone()));
+            }
+            let mut converter = Converter {
+                set: &mut set,
+                totals,
+                labels,
+                now,
+            };
+            for metric in &scope_metrics.metrics {
+                converter.metric(metric);
+            }
+        }
+    }
+    set
+}
+
+#[test]
+fn test_prometheus_name() {
+    assert_eq!(
+        prometheus_name("http.server.duration", "ms", false, false),
+        "http.server.duration_milliseconds"
+    );
+    assert_eq!(
+        prometheus_name("system.network.io", "By", false, true),
+        "system.network.io_bytes_total"
+    );
+    assert_eq!(
+        prometheus_name("requests", "{request}", false, true),
+        "requests_total"
+    );
+    assert_eq!(
+        prometheus_name("cpu.utilization", "1", true, false),
+        "cpu.utilization_ratio"
+    );
+    assert_eq!(
+        prometheus_name("rate", "By/s", true, false),
+        "rate_bytes_per_second"
+    );
+    assert_eq!(
+        prometheus_name("latency_seconds", "s", true, false),
+        "latency_seconds"
+    );
+}
+
+#[test]
+fn test_convert_json() {
+    let request: ExportMetricsServiceRequest = serde_json::from_str(
+        r#"{"resourceMetrics": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": "api"}},
+                {"key": "service.instance.id", "value": {"stringValue": "pod-1"}},
+                {"key": "host.arch", "value": {"stringValue": "amd64"}}
+            ]},
+            "scopeMetrics": [{
+                "scope": {"name": "meter", "version": "1.0"},
+                "metrics": [
+                    {"name": "requests", "unit": "1", "sum": {
+                        "aggregationTemporality": 1, "isMonotonic": true,
+                        "dataPoints": [
+                            {"timeUnixNano": "1000000000", "asInt": "2", "attributes": [{"key": "code", "value": {"intValue": "200"}}]},
+                            {"timeUnixNano": "2000000000", "asInt": "3", "attributes": [{"key": "code", "value": {"intValue": "200"}}]}
+                        ]
+                    }},
+                    {"name": "latency", "unit": "s", "histogram": {
+                        "aggregationTemporality": 2,
+                        "dataPoints": [{"timeUnixNano": "1000000000", "count": "3", "sum": 1.5,
+                            "bucketCounts": ["1", "2"], "explicitBounds": [0.5]}]
+                    }},
+                    {"name": "temperature", "unit": "Cel", "gauge": {
+                        "dataPoints": [{"timeUnixNano": 1000000000, "asDouble": 21.5, "flags": 0},
+                                       {"timeUnixNano": 2000000000, "flags": 1}]
+                    }}
+                ]
+            }]
+        }]}"#,
+    )
+    .unwrap();
+    let totals = DeltaTotals::default();
+    let set = convert(&request, &totals, 5000);
+    let scope = "instance=pod-1,job=api,otel_scope_name=meter,otel_scope_version=1.0";
+    assert_eq!(
+        series_of(set),
+        vec![
+            ("__name__=latency_seconds_bucket,instance=pod-1,job=api,le=+Inf,otel_scope_name=meter,otel_scope_version=1.0".to_string(), vec![3.]),
+            ("__name__=latency_seconds_bucket,instance=pod-1,job=api,le=0.5,otel_scope_name=meter,otel_scope_version=1.0".to_string(), vec![1.]),
+            (format!("__name__=latency_seconds_count,{}", scope), vec![3.]),
+            (format!("__name__=latency_seconds_sum,{}", scope), vec![1.5]),
+            (format!("__name__=requests_total,code=200,{}", scope), vec![2., 5.]),
+            ("__name__=target_info,host_arch=amd64,instance=pod-1,job=api".to_string(), vec![1.]),
+            (format!("__name__=temperature_celsius,{}", scope), vec![21.5]),
+        ]
+    );
+}
+
+#[test]
+fn test_delta_totals() {
+    let totals = DeltaTotals::default();
+    let labels = [Label {
+        name: "__name__".to_string(),
+        value: "requests_total".to_string(),
+    }];
+    assert_eq!(totals.add(&labels, 2., 1000, 0), Some(2.));
+    assert_eq!(totals.add(&labels, 3., 2000, 1000), Some(5.));
+    // sent again after a failed write, and out of order
+    assert_eq!(totals.add(&labels, 3., 2000, 2000), Some(5.));
+    assert_eq!(totals.add(&labels, 2., 1000, 2000), None);
+    // idle for an hour
+    assert_eq!(
+        totals.add(&labels, 1., 3000, 2000 + DELTA_IDLE_MS),
+        Some(1.)
+    );
+}
+
+#[test]
+fn test_exponential_buckets() {
+    let point = ExponentialHistogramDataPoint {
+        scale: 0,
+        zero_count: 1,
+        positive: Some(Buckets {
+            offset: 0,
+            bucket_counts: vec![2, 3],
+        }),
+        negative: Some(Buckets {
+            offset: 1,
+            bucket_counts: vec![4],
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        exponential_buckets(&point),
+        vec![(-2., 4), (0., 1), (2., 2), (4., 3), (f64::INFINITY, 0)]
+    );
+}
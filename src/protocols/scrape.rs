@@ -0,0 +1,137 @@
+//! Scraped Prometheus exposition and OpenMetrics samples, stored as Prometheus does.
+//!
+//! Series are labeled by the labels of their target, e.g. `job` and `instance`, scraped
+//! labels of the same names are kept as `exported_<name>`. Each scrape adds `up`, 1 if it
+//! succeeded or 0 if not, and `scrape_duration_seconds`. Series of the previous scrape
+//! missing in this one, or all of them if it failed, get a staleness marker.
+use std::collections::HashSet;
+use std::time::Duration;
+
+#[cfg(test)]
+use super::series_of;
+use super::{series_labels, SeriesSet};
+use crate::prometheus::exposition::ParsedSample;
+use crate::prometheus::types::Label;
+use crate::prometheus::STALE_NAN;
+
+/// A scrape target and its series in the previous scrape.
+#[derive(Debug, Default)]
+pub struct Target {
+    labels: Vec<(String, String)>,
+    previous: HashSet<Vec<Label>>,
+}
+
+impl Target {
+    pub fn new(labels: Vec<(String, String)>) -> Self {
+        Target {
+            labels,
+            previous: HashSet::new(),
+        }
+    }
+
+    /// Series of a scrape at `now` in milliseconds, `samples` are `None` if it failed.
+    pub fn report(
+        &mut self,
+        samples: Option<Vec<ParsedSample>>,
+        duration: Duration,
+        now: i64,
+    ) -> SeriesSet {
+        let mut set = SeriesSet::default();
+        let mut current = HashSet::new();
+        let up = samples.is_some();
+        for sample in samples.into_iter().flatten() {
+            let labels = sample
+                .labels
+                .into_iter()
+                .map(|(name, value)| {
+                    if self.labels.iter().any(|(target, _)| *target == name) {
+                        (format!("exported_{}", name), value)
+                    } else {
+                        (name, value)
+                    }
+                })
+                .chain(self.labels.iter().cloned());
+            let labels = series_labels(&sample.name, labels);
+            current.insert(labels.clone());
+            set.push_series(labels, sample.timestamp.unwrap_or(now), sample.value);
+        }
+        for labels in self.previous.difference(&current) {
+            set.push_series(labels.clone(), now, f64::from_bits(STALE_NAN));
+        }
+        self.previous = current;
+        set.push("up", self.labels.iter().cloned(), now, up as u8 as f64);
+        set.push(
+            "scrape_duration_seconds",
+            self.labels.iter().cloned(),
+            now,
+            duration.as_secs_f64(),
+        );
+        set
+    }
+}
+
+#[test]
+fn test_report() {
+    let sample = |name: &str, labels: &[(&str, &str)]| ParsedSample {
+        name: name.to_string(),
+        labels: labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        value: 1.,
+        timestamp: None,
+    };
+    let mut target = Target::new(vec![
+        ("instance".to_string(), "localhost:9100".to_string()),
+        ("job".to_string(), "node".to_string()),
+    ]);
+    let series = |set: SeriesSet| {
+        series_of(set)
+            .into_iter()
+            .map(|(labels, values)| (labels, values[0].to_bits()))
+            .collect::<Vec<_>>()
+    };
+    let set = target.report(
+        Some(vec![
+            sample("a", &[("job", "exported")]),
+            sample("b", &[("x", "1")]),
+        ]),
+        Duration::from_millis(500),
+        1000,
+    );
+    assert_eq!(
+        series(set),
+        vec![
+            (
+                "__name__=a,exported_job=exported,instance=localhost:9100,job=node".to_string(),
+                1f64.to_bits()
+            ),
+            (
+                "__name__=b,instance=localhost:9100,job=node,x=1".to_string(),
+                1f64.to_bits()
+            ),
+            (
+                "__name__=scrape_duration_seconds,instance=localhost:9100,job=node".to_string(),
+                0.5f64.to_bits()
+            ),
+            (
+                "__name__=up,instance=localhost:9100,job=node".to_string(),
+                1f64.to_bits()
+            ),
+        ]
+    );
+
+    let set = target.report(Some(vec![sample("b", &[("x", "1")])]), Duration::ZERO, 2000);
+    let series = series(set);
+    assert_eq!(series.len(), 4);
+    assert_eq!(
+        series[0],
+        (
+            "__name__=a,exported_job=exported,instance=localhost:9100,job=node".to_string(),
+            STALE_NAN
+        )
+    );
+
+    let set = target.report(None, Duration::ZERO, 3000);
+    assert_eq!(set.len(), 3);
+}
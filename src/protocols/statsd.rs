@@ -0,0 +1,312 @@
+//! StatsD protocol with DogStatsD tags, aggregated over flush intervals.
+//!
+//! Lines are `<name>:<value>[:<value>...]|<type>[|@<sample rate>][|#<tag>:<value>,...]`,
+//! aggregated per name and tags until flushed:
+//!
+//! - counters (`c`) are summed into `<name>_total` series, cumulative across flushes,
+//! - gauges (`g`) keep their last value, a value with a sign is added to it,
+//! - timers (`ms`), histograms (`h`) and distributions (`d`) are summaries of the
+//!   configured quantiles of the interval, with cumulative `_sum` and `_count`,
+//! - sets (`s`) are gauges of the number of unique values of the interval.
+//!
+//! Only series updated during an interval are flushed.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[cfg(test)]
+use super::series_of;
+use super::SeriesSet;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum StatsdError {
+    #[error("invalid line {0:?}")]
+    Line(String),
+    #[error("unknown metric type {0:?}")]
+    Type(String),
+    #[error("invalid value {0:?}")]
+    Value(String),
+    #[error("invalid sample rate {0:?}")]
+    SampleRate(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Timer,
+    Histogram,
+    Distribution,
+    Set,
+}
+
+impl FromStr for MetricType {
+    type Err = StatsdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(MetricType::Counter),
+            "g" => Ok(MetricType::Gauge),
+            "ms" => Ok(MetricType::Timer),
+            "h" => Ok(MetricType::Histogram),
+            "d" => Ok(MetricType::Distribution),
+            "s" => Ok(MetricType::Set),
+            _ => Err(StatsdError::Type(s.to_string())),
+        }
+    }
+}
+
+/// A parsed line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    /// Values as sent, set members are not numbers.
+    pub values: Vec<String>,
+    pub kind: MetricType,
+    pub sample_rate: f64,
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Parse a line, `None` for blank lines.
+pub fn parse_line(line: &str) -> Result<Option<Metric>, StatsdError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || StatsdError::Line(line.to_string());
+    let mut sections = line.split('|');
+    let (name, values) = sections
+        .next()
+        .and_then(|metric| metric.split_once(':'))
+        .ok_or_else(invalid)?;
+    if name.is_empty() || values.is_empty() {
+        return Err(invalid());
+    }
+    let kind = sections.next().ok_or_else(invalid)?.parse()?;
+    let mut sample_rate = 1.;
+    let mut tags = BTreeMap::new();
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            sample_rate = rate
+                .parse()
+                .ok()
+                .filter(|rate| *rate > 0. && *rate <= 1.)
+                .ok_or_else(|| StatsdError::SampleRate(rate.to_string()))?;
+        } else if let Some(list) = section.strip_prefix('#') {
+            for tag in list.split(',').filter(|tag| !tag.is_empty()) {
+                let (key, value) = tag.split_once(':').unwrap_or((tag, ""));
+                tags.insert(key.to_string(), value.to_string());
+            }
+        }
+        // other DogStatsD extensions, e.g. container ids, are ignored
+    }
+    Ok(Some(Metric {
+        name: name.to_string(),
+        values: values.split(':').map(str::to_string).collect(),
+        kind,
+        sample_rate,
+        tags,
+    }))
+}
+
+type Key = (String, BTreeMap<String, String>);
+
+/// Flushes after which metrics not updated are forgotten, counters and summaries
+/// restart from zero if they are updated again.
+const IDLE_FLUSHES: u32 = 5;
+
+#[derive(Debug, Default)]
+struct Summary {
+    values: Vec<f64>,
+    sum: f64,
+    count: f64,
+    /// Flushes since the last update.
+    idle: u32,
+}
+
+/// Aggregated metrics of a listener, counters and gauges with the flushes since their
+/// last update.
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    quantiles: Vec<f64>,
+    counters: HashMap<Key, (f64, u32)>,
+    gauges: HashMap<Key, (f64, u32)>,
+    summaries: HashMap<Key, Summary>,
+    sets: HashMap<Key, HashSet<String>>,
+}
+
+fn parse_value(value: &str) -> Result<f64, StatsdError> {
+    value
+        .parse()
+        .map_err(|_| StatsdError::Value(value.to_string()))
+}
+
+impl Aggregator {
+    /// Aggregator of timers into the quantiles, each between 0 and 1.
+    pub fn new(quantiles: &[f64]) -> Self {
+        Aggregator {
+            quantiles: quantiles.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, metric: Metric) -> Result<(), StatsdError> {
+        let values = match metric.kind {
+            MetricType::Set => Vec::new(),
+            _ => metric
+                .values
+                .iter()
+                .map(|value| parse_value(value))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        let key = (metric.name, metric.tags);
+        match metric.kind {
+            MetricType::Counter => {
+                let counter = self.counters.entry(key).or_default();
+                counter.0 += values.iter().sum::<f64>() / metric.sample_rate;
+                counter.1 = 0;
+            }
+            MetricType::Gauge => {
+                let gauge = self.gauges.entry(key).or_default();
+                for (value, raw) in values.into_iter().zip(&metric.values) {
+                    if raw.starts_with(['+', '-']) {
+                        gauge.0 += value;
+                    } else {
+                        gauge.0 = value;
+                    }
+                }
+                gauge.1 = 0;
+            }
+            MetricType::Timer | MetricType::Histogram | MetricType::Distribution => {
+                let summary = self.summaries.entry(key).or_default();
+                summary.sum += values.iter().sum::<f64>() / metric.sample_rate;
+                summary.count += values.len() as f64 / metric.sample_rate;
+                summary.values.extend(values);
+                summary.idle = 0;
+            }
+            MetricType::Set => {
+                self.sets.entry(key).or_default().extend(metric.values);
+            }
+        }
+        Ok(())
+    }
+
+    /// Series of the metrics updated since the last flush, at `now` in milliseconds.
+    pub fn flush(&mut self, now: i64) -> SeriesSet {
+        let mut set = SeriesSet::default();
+        for ((name, tags), (value, idle)) in &mut self.counters {
+            if *idle == 0 {
+                let name = if name.ends_with("_total") {
+                    name.clone()
+                } else {
+                    format!("{}_total", name)
+                };
+                set.push(&name, tags.iter(), now, *value);
+            }
+            *idle += 1;
+        }
+        for ((name, tags), (value, idle)) in &mut self.gauges {
+            if *idle == 0 {
+                set.push(name, tags.iter(), now, *value);
+            }
+            *idle += 1;
+        }
+        for ((name, tags), summary) in &mut self.summaries {
+            summary.idle += 1;
+            if summary.idle > 1 {
+                continue;
+            }
+            let mut values = std::mem::take(&mut summary.values);
+            values.sort_by(|a, b| a.total_cmp(b));
+            for quantile in &self.quantiles {
+                let index = ((values.len() as f64 * quantile).ceil() as usize).max(1) - 1;
+                let labels = tags
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.clone()))
+                    .chain(Some(("quantile", quantile.to_string())));
+                set.push(name, labels, now, values[index.min(values.len() - 1)]);
+            }
+            set.push(&format!("{}_sum", name), tags.iter(), now, summary.sum);
+            set.push(&format!("{}_count", name), tags.iter(), now, summary.count);
+        }
+        for ((name, tags), members) in self.sets.drain() {
+            set.push(&name, &tags, now, members.len() as f64);
+        }
+        self.counters.retain(|_, (_, idle)| *idle <= IDLE_FLUSHES);
+        self.gauges.retain(|_, (_, idle)| *idle <= IDLE_FLUSHES);
+        self.summaries
+            .retain(|_, summary| summary.idle <= IDLE_FLUSHES);
+        set
+    }
+}
+
+#[test]
+fn test_parse_line() {
+    let metric = parse_line("page.views:1|c|@0.5|#env:prod,canary")
+        .unwrap()
+        .unwrap();
+    assert_eq!(metric.name, "page.views");
+    assert_eq!(metric.kind, MetricType::Counter);
+    assert_eq!(metric.sample_rate, 0.5);
+    assert_eq!(
+        metric.tags.into_iter().collect::<Vec<_>>(),
+        vec![
+            ("canary".to_string(), String::new()),
+            ("env".to_string(), "prod".to_string()),
+        ]
+    );
+    let metric = parse_line("latency:10:20|ms").unwrap().unwrap();
+    assert_eq!(metric.values, vec!["10", "20"]);
+    assert_eq!(parse_line(" ").unwrap(), None);
+    assert!(parse_line("latency").is_err());
+    assert!(parse_line("latency:1").is_err());
+    assert!(parse_line("latency:1|x").is_err());
+    assert!(parse_line("latency:1|c|@2").is_err());
+}
+
+#[test]
+fn test_aggregator() {
+    let mut aggregator = Aggregator::new(&[0.5, 0.9]);
+    for line in [
+        "hits:1|c",
+        "hits:1|c|@0.5",
+        "temperature:20|g",
+        "temperature:-5|g",
+        "latency:1:2:3:4:5:6:7:8:9:10|ms",
+        "users:a|s",
+        "users:b|s",
+        "users:a|s",
+    ] {
+        aggregator.add(parse_line(line).unwrap().unwrap()).unwrap();
+    }
+    assert!(aggregator
+        .add(parse_line("hits:x|c").unwrap().unwrap())
+        .is_err());
+    assert_eq!(
+        series_of(aggregator.flush(1000)),
+        vec![
+            ("__name__=hits_total".to_string(), vec![3.]),
+            ("__name__=latency,quantile=0.5".to_string(), vec![5.]),
+            ("__name__=latency,quantile=0.9".to_string(), vec![9.]),
+            ("__name__=latency_count".to_string(), vec![10.]),
+            ("__name__=latency_sum".to_string(), vec![55.]),
+            ("__name__=temperature".to_string(), vec![15.]),
+            ("__name__=users".to_string(), vec![2.]),
+        ]
+    );
+    aggregator
+        .add(parse_line("hits:2|c").unwrap().unwrap())
+        .unwrap();
+    assert_eq!(
+        series_of(aggregator.flush(2000)),
+        vec![("__name__=hits_total".to_string(), vec![5.])]
+    );
+    for flush in 1..IDLE_FLUSHES {
+        assert!(aggregator.flush(3000 + flush as i64).is_empty());
+    }
+    assert_eq!(aggregator.counters.len(), 1);
+    assert!(aggregator.gauges.is_empty() && aggregator.summaries.is_empty());
+    aggregator.flush(4000);
+    assert!(aggregator.counters.is_empty());
+}
@@ -0,0 +1,97 @@
+//! Retries of failed TDengine operations with exponential backoff and jitter.
+//!
+//! Errors are classified by [ErrorKind]: retryable errors are retried, then answered
+//! with 5xx so that Prometheus sends the samples again later, bad data is answered with
+//! 4xx right away so that Prometheus drops it.
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use bailongma::storage::{ErrorKind, StorageError};
+use bailongma::PrometheusReaderError;
+
+use crate::config::RetryConfig;
+
+/// Delays between attempts, growing exponentially up to the max backoff.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    retries: u32,
+}
+
+impl RetryConfig {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            next: Duration::from_millis(self.initial_backoff_ms),
+            max: Duration::from_millis(self.max_backoff_ms),
+            multiplier: self.multiplier,
+            jitter: self.jitter,
+            retries: self.max_retries,
+        }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    /// Delay before the next attempt, `None` if retries are exhausted.
+    fn next(&mut self) -> Option<Duration> {
+        if self.retries == 0 {
+            return None;
+        }
+        self.retries -= 1;
+        let delay = self.next.min(self.max);
+        self.next = self.next.mul_f64(self.multiplier).min(self.max);
+        // spread retries of concurrent requests
+        Some(delay.mul_f64(1. - self.jitter * rand::random::<f64>()))
+    }
+}
+
+/// Kind of an error of the write path, errors besides storage errors are retryable.
+pub fn write_error_kind(err: &anyhow::Error) -> ErrorKind {
+    err.downcast_ref::<StorageError>()
+        .map_or(ErrorKind::Retryable, StorageError::kind)
+}
+
+/// Status of a write that failed with an error of the kind, as Prometheus retries 5xx
+/// and drops 4xx.
+pub fn write_status(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::BadData => StatusCode::BAD_REQUEST,
+        ErrorKind::Retryable | ErrorKind::Schema => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Status of a remote read that failed with the error.
+pub fn read_status(err: &PrometheusReaderError) -> StatusCode {
+    match err {
+        PrometheusReaderError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        err if err.is_guardrail() => StatusCode::UNPROCESSABLE_ENTITY,
+        err if err.is_retryable() => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let config = RetryConfig {
+        max_retries: 4,
+        initial_backoff_ms: 100,
+        max_backoff_ms: 300,
+        multiplier: 2.,
+        jitter: 0.,
+    };
+    let delays: Vec<_> = config.backoff().map(|d| d.as_millis()).collect();
+    assert_eq!(delays, vec![100, 200, 300, 300]);
+
+    let config = RetryConfig {
+        jitter: 0.5,
+        ..config
+    };
+    for delay in config.backoff() {
+        assert!(delay <= Duration::from_millis(300));
+        assert!(delay >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,141 @@
+//! Graceful shutdown: stop accepting, drain or spool in-flight writes, then close the pool.
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::AppState;
+
+/// Time reserved before the server deadline to spool writes that are still running.
+const SPOOL_MARGIN: Duration = Duration::from_secs(1);
+
+/// Shutdown state shared by request handlers and listeners.
+#[derive(Debug)]
+pub struct Shutdown {
+    sender: watch::Sender<Option<Instant>>,
+    receiver: watch::Receiver<Option<Instant>>,
+    /// Cloned into [Listening] guards, dropped once listeners are waited for.
+    listening: Mutex<Option<mpsc::Sender<()>>>,
+    stopped: Mutex<Option<mpsc::Receiver<()>>>,
+}
+
+/// Held by a listener task until it stopped and flushed its buffered samples.
+#[derive(Debug)]
+pub struct Listening {
+    _sender: Option<mpsc::Sender<()>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(None);
+        let (listening, stopped) = mpsc::channel(1);
+        Shutdown {
+            sender,
+            receiver,
+            listening: Mutex::new(Some(listening)),
+            stopped: Mutex::new(Some(stopped)),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Start draining, in-flight writes should finish by the deadline.
+    pub fn begin(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout.saturating_sub(SPOOL_MARGIN);
+        let _ = self.sender.send(Some(deadline));
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.receiver.borrow().is_some()
+    }
+
+    /// Resolves when draining started, listeners should flush and stop then.
+    pub async fn draining(&self) {
+        self.deadline().await;
+    }
+
+    /// Deadline of in-flight writes, once draining started.
+    async fn deadline(&self) -> Instant {
+        let mut receiver = self.receiver.clone();
+        loop {
+            let deadline = *receiver.borrow();
+            if let Some(deadline) = deadline {
+                return deadline;
+            }
+            if receiver.changed().await.is_err() {
+                // never shutting down
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Resolves when draining started and the deadline passed.
+    async fn expired(&self) {
+        tokio::time::sleep_until(self.deadline().await).await;
+    }
+
+    /// Guard of a listener task, see [Shutdown::listeners_stopped].
+    pub fn listening(&self) -> Listening {
+        Listening {
+            _sender: self.listening.lock().unwrap().clone(),
+        }
+    }
+
+    /// Resolves when all [Listening] guards are dropped.
+    pub async fn listeners_stopped(&self) {
+        self.listening.lock().unwrap().take();
+        let stopped = self.stopped.lock().unwrap().take();
+        if let Some(mut stopped) = stopped {
+            // nothing is sent, it only ends once all senders are dropped
+            while stopped.recv().await.is_some() {}
+        }
+    }
+
+    /// Run the future, or give up with `None` if it is still running at the deadline.
+    pub async fn until_deadline<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.expired() => None,
+        }
+    }
+}
+
+/// Wait for SIGTERM or Ctrl-C, then stop the server gracefully by `stop` within the timeout.
+pub fn watch_signal<F>(state: Arc<AppState>, timeout: Duration, stop: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    actix_web::rt::spawn(async move {
+        terminated().await;
+        log::info!(
+            "shutting down, draining in-flight requests in {}s",
+            timeout.as_secs()
+        );
+        state.shutdown.begin(timeout);
+        stop.await;
+    });
+}
+
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(err) => {
+            log::error!("listen to SIGTERM error: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,502 @@
+//! In-memory backend, for hermetic tests and `--dry-run` servers.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use futures::future;
+
+use super::*;
+use crate::prometheus::{Label, LabelCondition, Sample};
+use crate::utils::tag_value_escape;
+
+#[derive(Debug, Default)]
+struct Table {
+    tags: BTreeMap<String, String>,
+    /// Values by timestamp, the first written value is kept as TDengine does.
+    samples: BTreeMap<i64, Option<f64>>,
+}
+
+#[derive(Debug, Default)]
+struct Stable {
+    tags: Vec<String>,
+    tables: BTreeMap<String, Table>,
+}
+
+#[derive(Debug, Default)]
+struct Database {
+    stables: BTreeMap<String, Stable>,
+    /// Super table of each child table.
+    tables: HashMap<String, String>,
+}
+
+/// Storage of super tables and samples in memory, lost on exit.
+#[derive(Debug, Default)]
+pub struct Memory {
+    databases: RwLock<HashMap<String, Database>>,
+}
+
+fn ready<'a, T: 'a>(result: Result<T>) -> StorageFuture<'a, T> {
+    Box::pin(future::ready(result))
+}
+
+/// Whether tags of a child table match the plan, with the semantics of
+/// [QueryPlan::tag_conditions] where missing tags are empty.
+fn matches(plan: &QueryPlan, tags: &BTreeMap<String, String>) -> bool {
+    let value = |name: &str| {
+        tags.get(&format!("t_{}", name))
+            .map(String::as_str)
+            .unwrap_or_default()
+    };
+    let conditions = plan
+        .conditions
+        .iter()
+        .all(|(name, condition)| match condition {
+            LabelCondition::Eq(expected) => tag_value_escape(value(name)) == *expected,
+            LabelCondition::Neq(expected) => tag_value_escape(value(name)) != *expected,
+        });
+    conditions
+        && plan
+            .filters
+            .iter()
+            .all(|(name, filter)| filter.is_match(value(name)))
+}
+
+fn in_range(timestamp: i64, start: Option<i64>, end: Option<i64>) -> bool {
+    start.map_or(true, |start| timestamp >= start) && end.map_or(true, |end| timestamp <= end)
+}
+
+impl Memory {
+    /// Read the super table, `f` is not called if it does not exist.
+    fn with_stable<T: Default>(
+        &self,
+        database: &str,
+        stable: &str,
+        f: impl FnOnce(&Stable) -> T,
+    ) -> Result<T> {
+        let databases = self.databases.read().unwrap();
+        let db = databases
+            .get(database)
+            .ok_or_else(|| StorageError::DatabaseNotFound(database.to_string()))?;
+        Ok(db.stables.get(stable).map(f).unwrap_or_default())
+    }
+
+    fn with_database_mut<T>(
+        &self,
+        database: &str,
+        f: impl FnOnce(&mut Database) -> Result<T>,
+    ) -> Result<T> {
+        let mut databases = self.databases.write().unwrap();
+        let db = databases
+            .get_mut(database)
+            .ok_or_else(|| StorageError::DatabaseNotFound(database.to_string()))?;
+        f(db)
+    }
+
+    /// Samples of the series in the range, series without samples are dropped.
+    fn samples(
+        &self,
+        database: &str,
+        stable: &str,
+        series: Vec<Series>,
+        start: Option<i64>,
+        end: Option<i64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<TimeSeries>> {
+        let mut remaining = limit.unwrap_or(usize::MAX);
+        self.with_stable(database, stable, |st| {
+            series
+                .into_iter()
+                .filter_map(|Series { table, labels }| {
+                    let samples: Vec<_> = st
+                        .tables
+                        .get(&table)?
+                        .samples
+                        .iter()
+                        .filter(|(timestamp, _)| in_range(**timestamp, start, end))
+                        .take(remaining)
+                        .map(|(timestamp, value)| Sample {
+                            timestamp: *timestamp,
+                            value: *value,
+                        })
+                        .collect();
+                    remaining -= samples.len();
+                    if samples.is_empty() {
+                        return None;
+                    }
+                    Some(TimeSeries { labels, samples })
+                })
+                .collect()
+        })
+    }
+}
+
+impl Storage for Memory {
+    fn ping(&self, _timeout: Duration) -> StorageFuture<'_, ()> {
+        ready(Ok(()))
+    }
+
+    fn create_database<'a>(&'a self, database: &'a str) -> StorageFuture<'a, ()> {
+        self.databases
+            .write()
+            .unwrap()
+            .entry(database.to_string())
+            .or_default();
+        ready(Ok(()))
+    }
+
+    fn create_stable<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tags: &'a [String],
+    ) -> StorageFuture<'a, ()> {
+        ready(self.with_database_mut(database, |db| {
+            db.stables
+                .entry(stable.to_string())
+                .or_insert_with(|| Stable {
+                    tags: std::iter::once("taghash".to_string())
+                        .chain(tags.iter().cloned())
+                        .collect(),
+                    tables: BTreeMap::new(),
+                });
+            Ok(())
+        }))
+    }
+
+    fn add_tag<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tag: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        ready(self.with_database_mut(database, |db| {
+            let st = db
+                .stables
+                .get_mut(stable)
+                .ok_or_else(|| StorageError::TableNotFound(format!("{}.{}", database, stable)))?;
+            if !st.tags.iter().any(|t| t == tag) {
+                st.tags.push(tag.to_string());
+            }
+            Ok(())
+        }))
+    }
+
+    fn create_table<'a>(
+        &'a self,
+        database: &'a str,
+        table: &'a str,
+        stable: &'a str,
+        tags: &'a [(String, String)],
+    ) -> StorageFuture<'a, ()> {
+        ready(self.with_database_mut(database, |db| {
+            let st = db
+                .stables
+                .get_mut(stable)
+                .ok_or_else(|| StorageError::TableNotFound(format!("{}.{}", database, stable)))?;
+            st.tables.entry(table.to_string()).or_insert_with(|| Table {
+                tags: tags.iter().cloned().collect(),
+                samples: BTreeMap::new(),
+            });
+            db.tables.insert(table.to_string(), stable.to_string());
+            Ok(())
+        }))
+    }
+
+    fn describe<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+    ) -> StorageFuture<'a, Option<Schema>> {
+        ready(self.with_stable(database, stable, |st| {
+            Some(Schema {
+                columns: vec![
+                    "ts".to_string(),
+                    "value".to_string(),
+                    STALE_COLUMN.to_string(),
+                ],
+                tags: st.tags.clone(),
+            })
+        }))
+    }
+
+    fn insert<'a>(&'a self, database: &'a str, rows: &'a [Row<'a>]) -> StorageFuture<'a, ()> {
+        ready(self.with_database_mut(database, |db| {
+            // the whole insert fails if any table is missing, as a TDengine statement does
+            if let Some(row) = rows.iter().find(|row| !db.tables.contains_key(row.table)) {
+                return Err(StorageError::TableNotFound(format!(
+                    "{}.{}",
+                    database, row.table
+                )));
+            }
+            for row in rows {
+                let stable = &db.tables[row.table];
+                let table = db
+                    .stables
+                    .get_mut(stable)
+                    .and_then(|st| st.tables.get_mut(row.table))
+                    .expect("child table should be in its super table");
+                table.samples.entry(row.timestamp).or_insert(row.value);
+            }
+            Ok(())
+        }))
+    }
+
+    fn stables<'a>(&'a self, database: &'a str) -> StorageFuture<'a, Vec<String>> {
+        let databases = self.databases.read().unwrap();
+        ready(
+            databases
+                .get(database)
+                .map(|db| db.stables.keys().cloned().collect())
+                .ok_or_else(|| StorageError::DatabaseNotFound(database.to_string())),
+        )
+    }
+
+    fn resolve_series<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        plan: &'a QueryPlan,
+        limit: Option<usize>,
+    ) -> StorageFuture<'a, Vec<Series>> {
+        ready(self.with_stable(database, stable, |st| {
+            st.tables
+                .iter()
+                .filter(|(_, table)| matches(plan, &table.tags))
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(name, table)| {
+                    let mut labels: Vec<_> = table
+                        .tags
+                        .iter()
+                        .filter(|(tag, _)| tag.as_str() != "taghash")
+                        .map(|(tag, value)| Label {
+                            name: tag.replacen("t_", "", 1),
+                            value: value.clone(),
+                        })
+                        .chain(std::iter::once(Label {
+                            name: "__name__".to_string(),
+                            value: stable.to_string(),
+                        }))
+                        .collect();
+                    labels.sort_by(|a, b| a.name.cmp(&b.name));
+                    Series {
+                        table: name.clone(),
+                        labels,
+                    }
+                })
+                .collect()
+        }))
+    }
+
+    fn fetch_samples<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+        plan: &'a QueryPlan,
+        limit: Option<usize>,
+    ) -> StorageFuture<'a, Vec<TimeSeries>> {
+        ready(self.samples(
+            database,
+            stable,
+            series,
+            Some(plan.start_timestamp_ms),
+            Some(plan.end_timestamp_ms),
+            limit,
+        ))
+    }
+
+    fn series_in_range<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+        range: TimeRange,
+    ) -> StorageFuture<'a, Vec<Series>> {
+        ready(self.with_stable(database, stable, |st| {
+            series
+                .into_iter()
+                .filter(|s| {
+                    st.tables.get(&s.table).map_or(false, |table| {
+                        table
+                            .samples
+                            .keys()
+                            .any(|timestamp| in_range(*timestamp, range.start, range.end))
+                    })
+                })
+                .collect()
+        }))
+    }
+
+    fn tag_values<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tag: &'a str,
+    ) -> StorageFuture<'a, Vec<String>> {
+        ready(self.with_stable(database, stable, |st| {
+            let mut values: Vec<_> = st
+                .tables
+                .values()
+                .filter_map(|table| table.tags.get(tag).cloned())
+                .collect();
+            values.sort();
+            values.dedup();
+            values
+        }))
+    }
+
+    fn last_rows<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+    ) -> StorageFuture<'a, Vec<TimeSeries>> {
+        ready(self.with_stable(database, stable, |st| {
+            series
+                .into_iter()
+                .filter_map(|Series { table, labels }| {
+                    let (timestamp, value) = st.tables.get(&table)?.samples.iter().next_back()?;
+                    Some(TimeSeries {
+                        labels,
+                        samples: vec![Sample {
+                            timestamp: *timestamp,
+                            value: *value,
+                        }],
+                    })
+                })
+                .collect()
+        }))
+    }
+}
+
+#[tokio::test]
+async fn test_round_trip() {
+    use crate::prometheus::discovery::{self, TimeRange};
+    use crate::prometheus::{federate, prometheus_read, LabelMatcher, Query, ReadOptions};
+    use crate::prometheus::{label_matcher, ReadRequest};
+
+    let storage = Memory::default();
+    let tags = vec!["t_job".to_string()];
+    assert!(matches!(
+        storage.describe("prom", "up").await,
+        Err(StorageError::DatabaseNotFound(_))
+    ));
+    storage.create_database("prom").await.unwrap();
+    assert_eq!(storage.describe("prom", "up").await.unwrap(), None);
+    storage.create_stable("prom", "up", &tags).await.unwrap();
+    storage.add_tag("prom", "up", "t_instance").await.unwrap();
+    let rows = [Row {
+        table: "md5_a",
+        timestamp: 1000,
+        value: Some(1.),
+    }];
+    assert!(matches!(
+        storage.insert("prom", &rows).await,
+        Err(StorageError::TableNotFound(_))
+    ));
+    for (table, job) in [("md5_a", "api"), ("md5_b", "db")] {
+        let tags = [
+            ("taghash".to_string(), table.to_string()),
+            ("t_job".to_string(), job.to_string()),
+            ("t_instance".to_string(), "localhost".to_string()),
+        ];
+        storage
+            .create_table("prom", table, "up", &tags)
+            .await
+            .unwrap();
+    }
+    let rows: Vec<_> = (0..3)
+        .flat_map(|i| {
+            let timestamp = 1000 * (i + 1);
+            vec![
+                Row {
+                    table: "md5_a",
+                    timestamp,
+                    value: Some(i as f64),
+                },
+                Row {
+                    table: "md5_b",
+                    timestamp,
+                    value: None,
+                },
+            ]
+        })
+        .collect();
+    storage.insert("prom", &rows).await.unwrap();
+
+    let matcher = |name: &str, r#type: label_matcher::Type, value: &str| LabelMatcher {
+        r#type: r#type as i32,
+        name: name.to_string(),
+        value: value.to_string(),
+    };
+    let req = ReadRequest {
+        queries: vec![Query {
+            start_timestamp_ms: 1500,
+            end_timestamp_ms: 3000,
+            matchers: vec![
+                matcher("__name__", label_matcher::Type::Eq, "up"),
+                matcher("job", label_matcher::Type::Re, "^a.*"),
+            ],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let res = prometheus_read(&storage, "prom", &req, &ReadOptions::default())
+        .await
+        .unwrap();
+    let series = &res.results[0].timeseries;
+    assert_eq!(series.len(), 1);
+    assert_eq!(
+        series[0]
+            .labels
+            .iter()
+            .map(|label| format!("{}={}", label.name, label.value))
+            .collect::<Vec<_>>(),
+        vec!["__name__=up", "instance=localhost", "job=api"]
+    );
+    assert_eq!(
+        series[0]
+            .samples
+            .iter()
+            .map(|s| (s.timestamp, s.value))
+            .collect::<Vec<_>>(),
+        vec![(2000, Some(1.)), (3000, Some(2.))]
+    );
+
+    let names = discovery::label_names(&storage, "prom", &[], TimeRange::default())
+        .await
+        .unwrap();
+    assert_eq!(names, vec!["__name__", "instance", "job"]);
+    let jobs = discovery::label_values(&storage, "prom", "job", &[], TimeRange::default())
+        .await
+        .unwrap();
+    assert_eq!(jobs, vec!["api", "db"]);
+    let selectors = vec![vec![matcher("job", label_matcher::Type::Neq, "api")]];
+    let range = TimeRange {
+        start: Some(4000),
+        end: None,
+    };
+    let series = discovery::series(&storage, "prom", &selectors, range)
+        .await
+        .unwrap();
+    assert!(series.is_empty());
+    let names = discovery::label_names(&storage, "prom", &[], range)
+        .await
+        .unwrap();
+    assert!(names.is_empty());
+    let jobs = discovery::label_values(&storage, "prom", "job", &[], range)
+        .await
+        .unwrap();
+    assert!(jobs.is_empty());
+
+    let latest = federate::latest(&storage, "prom", &selectors, 4000)
+        .await
+        .unwrap();
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].samples[0].timestamp, 3000);
+    assert_eq!(latest[0].samples[0].value, None);
+    let latest = federate::latest(&storage, "prom", &selectors, 3000 + 5 * 60 * 1000)
+        .await
+        .unwrap();
+    assert!(latest.is_empty());
+}
@@ -0,0 +1,233 @@
+//! Storage backends of the adapter.
+//!
+//! Writers and readers work on a [Storage] instead of TDengine connections, so that
+//! TDengine is one of the backends, and [Memory] keeps everything in process for
+//! hermetic tests and dry runs.
+use std::fmt;
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+use thiserror::Error;
+
+use crate::prometheus::discovery::TimeRange;
+use crate::prometheus::{QueryPlan, Series, TimeSeries};
+
+mod memory;
+mod tdengine;
+
+pub use memory::Memory;
+pub use tdengine::{AdapterOptions, Connector, NativeOptions, TDengine};
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("database {0} does not exist")]
+    DatabaseNotFound(String),
+    #[error("table {0} does not exist")]
+    TableNotFound(String),
+    #[error("tag value too long")]
+    TagValueTooLong,
+    #[cfg(feature = "native")]
+    #[error("TDengine error: {0}")]
+    Taos(#[from] libtaos::Error),
+    #[cfg(feature = "native")]
+    #[error("TDengine connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+    #[error("TDengine error {code:#06x}: {message}")]
+    Adapter { code: i32, message: String },
+    #[error("taosAdapter request error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("taosAdapter WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("invalid taosAdapter response: {0}")]
+    Response(String),
+    #[error("TDengine request timed out")]
+    Timeout,
+    #[error("storage is closed")]
+    Closed,
+}
+
+/// How a failed operation should be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Transient, e.g. TDengine is unreachable or busy, retry later.
+    Retryable,
+    /// The data could never be written as is, e.g. timestamps out of range.
+    BadData,
+    /// The database or table is missing, create it and retry.
+    Schema,
+}
+
+impl StorageError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            StorageError::DatabaseNotFound(_) | StorageError::TableNotFound(_) => ErrorKind::Schema,
+            StorageError::TagValueTooLong => ErrorKind::BadData,
+            err if tdengine::is_bad_data(err) => ErrorKind::BadData,
+            _ => ErrorKind::Retryable,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// Future returned by [Storage] methods.
+pub type StorageFuture<'a, T> = LocalBoxFuture<'a, Result<T>>;
+
+/// Column of super tables telling staleness markers apart from other NaN values, both
+/// stored as NULL values.
+pub const STALE_COLUMN: &str = "stale";
+
+/// Columns and tags of a super table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    pub columns: Vec<String>,
+    /// Tag columns, `taghash` and `t_<label>`.
+    pub tags: Vec<String>,
+}
+
+/// A sample to insert into a child table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Row<'a> {
+    pub table: &'a str,
+    pub timestamp: i64,
+    /// `None` for NULL, NaN values are read back as written, staleness markers too.
+    pub value: Option<f64>,
+}
+
+/// Connection pool usage of a backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolState {
+    pub connections: u32,
+    pub idle_connections: u32,
+    /// Callers waiting for a connection.
+    pub waiting: usize,
+}
+
+/// Health of a TDengine endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointState {
+    /// `host:port` or taosAdapter URL.
+    pub address: String,
+    pub up: bool,
+    /// Connections and requests failed since start.
+    pub failures: u64,
+}
+
+/// A storage backend of super tables, i.e. metrics, and their child tables, i.e. series.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Check the backend is serving, waiting at most `timeout` for a connection.
+    fn ping(&self, timeout: Duration) -> StorageFuture<'_, ()>;
+
+    /// Connection pool usage, `None` if the backend has no pool.
+    fn pool_state(&self) -> Option<PoolState> {
+        None
+    }
+
+    /// Health of the endpoints the backend connects to.
+    fn endpoints(&self) -> Vec<EndpointState> {
+        Vec::new()
+    }
+
+    /// Close connections on shutdown, later operations fail with [StorageError::Closed].
+    fn close(&self) -> StorageFuture<'_, ()> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn create_database<'a>(&'a self, database: &'a str) -> StorageFuture<'a, ()>;
+
+    /// Create a super table of `ts`, `value` and [STALE_COLUMN] columns, tagged by
+    /// `taghash` and `tags`.
+    fn create_stable<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tags: &'a [String],
+    ) -> StorageFuture<'a, ()>;
+
+    /// Add a tag column to a super table, succeeds if it already exists.
+    fn add_tag<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tag: &'a str,
+    ) -> StorageFuture<'a, ()>;
+
+    /// Add [STALE_COLUMN] to a super table created without it, succeeds if it already
+    /// exists.
+    fn add_stale_column<'a>(
+        &'a self,
+        _database: &'a str,
+        _stable: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    /// Create a child table with tag values if not exists.
+    fn create_table<'a>(
+        &'a self,
+        database: &'a str,
+        table: &'a str,
+        stable: &'a str,
+        tags: &'a [(String, String)],
+    ) -> StorageFuture<'a, ()>;
+
+    /// Schema of a super table, `None` if it does not exist.
+    fn describe<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+    ) -> StorageFuture<'a, Option<Schema>>;
+
+    /// Insert rows, fails with [StorageError::TableNotFound] or
+    /// [StorageError::DatabaseNotFound] if the schema should be created first.
+    fn insert<'a>(&'a self, database: &'a str, rows: &'a [Row<'a>]) -> StorageFuture<'a, ()>;
+
+    /// Super table names.
+    fn stables<'a>(&'a self, database: &'a str) -> StorageFuture<'a, Vec<String>>;
+
+    /// Series of a super table matched by the plan, see [QueryPlan::tag_conditions],
+    /// stopping after `limit` series.
+    fn resolve_series<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        plan: &'a QueryPlan,
+        limit: Option<usize>,
+    ) -> StorageFuture<'a, Vec<Series>>;
+
+    /// Samples of the series in the time range of the plan, stopping after `limit`
+    /// samples, series without samples are dropped.
+    fn fetch_samples<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+        plan: &'a QueryPlan,
+        limit: Option<usize>,
+    ) -> StorageFuture<'a, Vec<TimeSeries>>;
+
+    /// Keep series with samples in the time range.
+    fn series_in_range<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+        range: TimeRange,
+    ) -> StorageFuture<'a, Vec<Series>>;
+
+    /// Distinct values of a tag column.
+    fn tag_values<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tag: &'a str,
+    ) -> StorageFuture<'a, Vec<String>>;
+
+    /// Latest sample of each series, series without samples are dropped.
+    fn last_rows<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+    ) -> StorageFuture<'a, Vec<TimeSeries>>;
+}
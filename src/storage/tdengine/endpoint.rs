@@ -0,0 +1,120 @@
+//! Health tracking of TDengine endpoints, i.e. dnodes or taosAdapter instances.
+use std::sync::atomic::AtomicU64;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::*;
+
+/// Time before an endpoint that went down is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Endpoint {
+    address: String,
+    /// Time of the last failure if the endpoint is down.
+    down_since: Mutex<Option<Instant>>,
+    failures: AtomicU64,
+}
+
+#[derive(Debug)]
+pub(super) struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    next: AtomicUsize,
+}
+
+impl Endpoints {
+    pub(super) fn new(addresses: impl IntoIterator<Item = String>) -> Self {
+        let endpoints: Vec<_> = addresses
+            .into_iter()
+            .map(|address| Endpoint {
+                address,
+                down_since: Mutex::new(None),
+                failures: AtomicU64::new(0),
+            })
+            .collect();
+        assert!(!endpoints.is_empty(), "at least one TDengine endpoint");
+        Endpoints {
+            endpoints,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub(super) fn address(&self, index: usize) -> &str {
+        &self.endpoints[index].address
+    }
+
+    pub(super) fn is_up(&self, index: usize) -> bool {
+        self.endpoints[index].down_since.lock().unwrap().is_none()
+    }
+
+    /// Endpoints to connect to in order of preference: endpoints down for the retry
+    /// interval are tried again first, then endpoints up in round robin, then the other
+    /// endpoints down as a last resort.
+    pub(super) fn candidates(&self) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        let now = Instant::now();
+        let mut candidates = (0..count).map(|i| (start + i) % count).collect_vec();
+        candidates.sort_by_key(
+            |&index| match *self.endpoints[index].down_since.lock().unwrap() {
+                Some(since) if now.duration_since(since) >= RETRY_INTERVAL => 0,
+                None => 1,
+                Some(_) => 2,
+            },
+        );
+        candidates
+    }
+
+    pub(super) fn succeeded(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        if endpoint.down_since.lock().unwrap().take().is_some() {
+            log::info!("TDengine endpoint {} is up", endpoint.address);
+        }
+    }
+
+    pub(super) fn failed(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        endpoint.failures.fetch_add(1, Ordering::Relaxed);
+        if endpoint
+            .down_since
+            .lock()
+            .unwrap()
+            .replace(Instant::now())
+            .is_none()
+        {
+            log::warn!("TDengine endpoint {} is down", endpoint.address);
+        }
+    }
+
+    pub(super) fn states(&self) -> Vec<EndpointState> {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .map(|(index, endpoint)| EndpointState {
+                address: endpoint.address.clone(),
+                up: self.is_up(index),
+                failures: endpoint.failures.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_endpoints() {
+    let endpoints = Endpoints::new(vec!["td1:6030".to_string(), "td2:6030".to_string()]);
+    assert_eq!(endpoints.candidates(), vec![0, 1]);
+    assert_eq!(endpoints.candidates(), vec![1, 0]);
+
+    endpoints.failed(0);
+    assert!(!endpoints.is_up(0));
+    assert_eq!(endpoints.candidates(), vec![1, 0]);
+    assert_eq!(endpoints.candidates(), vec![1, 0]);
+
+    // due for a retry
+    *endpoints.endpoints[0].down_since.lock().unwrap() = Some(Instant::now() - RETRY_INTERVAL);
+    assert_eq!(endpoints.candidates(), vec![0, 1]);
+    endpoints.succeeded(0);
+    let states = endpoints.states();
+    assert!(states[0].up);
+    assert_eq!(states[0].failures, 1);
+}
@@ -0,0 +1,944 @@
+//! TDengine backend over a native, REST or WebSocket connection.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use itertools::Itertools;
+use serde::Deserialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::*;
+use crate::prometheus::{Label, Sample, STALE_NAN, TABLES_PER_QUERY};
+use crate::utils::tag_value_escape;
+use endpoint::Endpoints;
+
+mod endpoint;
+#[cfg(feature = "native")]
+mod native;
+mod rest;
+mod websocket;
+
+/// Max length of binary tags.
+const TAG_LENGTH: usize = 128;
+
+/// Length of the `taghash` tag, md5 in hex.
+const TAGHASH_LENGTH: usize = 34;
+
+/// How to connect to TDengine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Connector {
+    /// TDengine client library.
+    Native,
+    /// taosAdapter REST API.
+    Rest,
+    /// taosAdapter WebSocket API.
+    Websocket,
+}
+
+impl FromStr for Connector {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "native" => Ok(Connector::Native),
+            "rest" => Ok(Connector::Rest),
+            "websocket" | "ws" => Ok(Connector::Websocket),
+            _ => Err(format!(
+                "unknown connector {}, expect native, rest or websocket",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Connector::Native => "native",
+            Connector::Rest => "rest",
+            Connector::Websocket => "websocket",
+        })
+    }
+}
+
+/// Settings of the native connector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeOptions {
+    /// Host and port of the dnodes to connect to.
+    pub endpoints: Vec<(String, u16)>,
+    pub user: String,
+    pub password: String,
+    pub max_connections: u32,
+}
+
+/// Settings of the REST and WebSocket connectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterOptions {
+    /// Endpoints, e.g. `http://localhost:6041/rest/sqlt` or `ws://localhost:6041/rest/ws`.
+    pub urls: Vec<String>,
+    pub user: String,
+    pub password: String,
+    /// Token used instead of user and password if set.
+    pub token: Option<String>,
+    /// Max concurrent requests, or WebSocket connections.
+    pub max_connections: u32,
+    /// Max time of a request, including connecting.
+    pub timeout: Duration,
+}
+
+/// A field of a query result.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Int(i64),
+    Double(f64),
+    /// Raw timestamp in the precision of the database.
+    Timestamp(i64),
+    String(String),
+}
+
+impl Value {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Booleans are integers in JSON, and strings of the native connector.
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Int(value) => Some(*value != 0),
+            Value::String(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_double(&self) -> Option<f64> {
+        match self {
+            Value::Double(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn as_timestamp(&self) -> Option<i64> {
+        match self {
+            Value::Timestamp(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Column type of taosAdapter results, as far as conversion of values is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    Timestamp,
+    Float,
+    Other,
+}
+
+impl ColumnType {
+    /// Type of a column, a type code in TDengine 2.x or a type name in 3.x.
+    fn from_json(ty: &serde_json::Value) -> Self {
+        match ty {
+            serde_json::Value::Number(code) => match code.as_i64() {
+                Some(9) => ColumnType::Timestamp,
+                Some(6) | Some(7) => ColumnType::Float,
+                _ => ColumnType::Other,
+            },
+            serde_json::Value::String(name) => match name.to_ascii_uppercase().as_str() {
+                "TIMESTAMP" => ColumnType::Timestamp,
+                "FLOAT" | "DOUBLE" => ColumnType::Float,
+                _ => ColumnType::Other,
+            },
+            _ => ColumnType::Other,
+        }
+    }
+}
+
+/// Longest prefix of a tag value that fits a tag column, cut at a character boundary.
+fn truncate_tag(value: &str) -> &str {
+    let mut end = value.len().min(TAG_LENGTH - 1);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Parse a timestamp string of taosAdapter into milliseconds: RFC 3339 of `/rest/sqlutc`
+/// and TDengine 3.x, with or without a colon in the offset, or the local time of
+/// TDengine 2.x `/rest/sql`, taken in the timezone of the adapter.
+fn parse_timestamp(ts: &str) -> Option<i64> {
+    use chrono::TimeZone;
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(ts) {
+        return Some(ts.timestamp_millis());
+    }
+    if let Ok(ts) = chrono::DateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S%.f%z") {
+        return Some(ts.timestamp_millis());
+    }
+    let local = chrono::NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S%.f").ok()?;
+    chrono::Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|ts| ts.timestamp_millis())
+}
+
+impl Value {
+    /// Convert a taosAdapter JSON value, timestamps are raw numbers, or strings parsed by
+    /// [parse_timestamp].
+    fn from_json(value: serde_json::Value, ty: ColumnType) -> Self {
+        use serde_json::Value as Json;
+        match (value, ty) {
+            (Json::Null, _) => Value::Null,
+            (Json::Number(ts), ColumnType::Timestamp) if ts.is_i64() => {
+                Value::Timestamp(ts.as_i64().unwrap_or_default())
+            }
+            (Json::String(ts), ColumnType::Timestamp) => match parse_timestamp(&ts) {
+                Some(ts) => Value::Timestamp(ts),
+                None => Value::String(ts),
+            },
+            (Json::Number(value), ColumnType::Float) => {
+                Value::Double(value.as_f64().unwrap_or(f64::NAN))
+            }
+            (Json::Number(value), _) => match value.as_i64() {
+                Some(value) => Value::Int(value),
+                None => Value::Double(value.as_f64().unwrap_or(f64::NAN)),
+            },
+            (Json::Bool(value), _) => Value::Int(value as i64),
+            (Json::String(value), _) => Value::String(value),
+            (value, _) => Value::String(value.to_string()),
+        }
+    }
+}
+
+/// Query result, converted from the connector representation.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct QueryData {
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+/// TDengine errors handled by the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Code {
+    /// The endpoint could not be reached.
+    Unavailable,
+    DatabaseNotSelected,
+    InvalidTableName,
+    FieldAlreadyExist,
+    TooManyTags,
+    TimestampOutOfRange,
+    /// A column does not exist, TDengine 3.x only, 2.x reports an invalid operation.
+    InvalidColumn,
+    /// SQL rejected, e.g. names or values TDengine does not accept.
+    InvalidOperation,
+}
+
+impl Code {
+    /// Code of a TDengine error, whatever connector reports it.
+    fn of(err: &StorageError) -> Option<Code> {
+        match err {
+            #[cfg(feature = "native")]
+            StorageError::Taos(err) => native::code(err),
+            StorageError::Adapter { code, .. } => Code::from_raw(*code),
+            _ => None,
+        }
+    }
+
+    /// Code of a raw TDengine error code, as taosAdapter responds.
+    fn from_raw(code: i32) -> Option<Code> {
+        match code & 0xffff {
+            0x000B => Some(Code::Unavailable),
+            0x0380 => Some(Code::DatabaseNotSelected),
+            0x0362 => Some(Code::InvalidTableName),
+            0x036B => Some(Code::FieldAlreadyExist),
+            0x0364 => Some(Code::TooManyTags),
+            0x060B => Some(Code::TimestampOutOfRange),
+            0x2602 => Some(Code::InvalidColumn),
+            0x0200 => Some(Code::InvalidOperation),
+            _ => None,
+        }
+    }
+}
+
+/// If TDengine rejects the data itself, so that retrying will not help.
+pub(super) fn is_bad_data(err: &StorageError) -> bool {
+    matches!(
+        Code::of(err),
+        Some(Code::TooManyTags) | Some(Code::TimestampOutOfRange) | Some(Code::InvalidOperation)
+    )
+}
+
+/// A connection to TDengine, or a pool of them.
+trait Connection: fmt::Debug + Send + Sync {
+    fn query<'a>(&'a self, sql: &'a str) -> StorageFuture<'a, QueryData>;
+
+    /// Check TDengine is serving, waiting at most `timeout`.
+    fn ping(&self, timeout: Duration) -> StorageFuture<'_, ()>;
+
+    fn pool_state(&self) -> PoolState;
+
+    fn endpoints(&self) -> &Endpoints;
+
+    /// Close idle connections, those in use are closed once returned.
+    fn close(&self) -> StorageFuture<'_, ()>;
+}
+
+/// Limits concurrent requests of the REST and WebSocket connectors.
+#[derive(Debug)]
+struct Limiter {
+    semaphore: Semaphore,
+    max: u32,
+    waiting: AtomicUsize,
+}
+
+impl Limiter {
+    fn new(max: u32) -> Self {
+        Limiter {
+            semaphore: Semaphore::new(max as usize),
+            max,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a slot, callers waiting for exhausted slots are counted.
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let permit = self.semaphore.acquire().await;
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        permit.expect("semaphore is never closed")
+    }
+
+    fn state(&self) -> PoolState {
+        PoolState {
+            connections: self.max,
+            idle_connections: self.semaphore.available_permits() as u32,
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TDengine {
+    connection: Box<dyn Connection>,
+}
+
+/// Map TDengine errors of missing database or table.
+fn not_found(err: StorageError, database: &str, table: &str) -> StorageError {
+    match Code::of(&err) {
+        Some(Code::DatabaseNotSelected) => StorageError::DatabaseNotFound(database.to_string()),
+        Some(Code::InvalidTableName) => {
+            StorageError::TableNotFound(format!("{}.{}", database, table))
+        }
+        _ => err,
+    }
+}
+
+/// Value of a sample, NULL values are NaN or a staleness marker as told by the `stale`
+/// field, or missing if the super table has no such column or the field is NULL.
+fn sample_value(value: &Value, stale: Option<&Value>) -> Option<f64> {
+    match (value, stale.and_then(Value::as_bool)) {
+        (Value::Null, Some(true)) => Some(f64::from_bits(STALE_NAN)),
+        (Value::Null, Some(false)) => Some(f64::NAN),
+        (value, _) => value.as_double(),
+    }
+}
+
+/// Series of a `tbname, <tags>` row, `None` if a regex filter of the plan rejects it.
+fn matched_series(
+    stable: &str,
+    row: Vec<Value>,
+    columns: &[String],
+    plan: &QueryPlan,
+) -> Option<Series> {
+    let mut fields = row.into_iter().zip(columns);
+    let table = match fields.next() {
+        Some((Value::String(table), _)) => table,
+        _ => return None,
+    };
+    let mut labels = vec![Label {
+        name: "__name__".to_string(),
+        value: stable.to_string(),
+    }];
+    for (field, column) in fields {
+        let name = column.replacen("t_", "", 1);
+        let value = match field {
+            Value::String(value) => Some(value),
+            _ => None,
+        };
+        if let Some(filter) = plan.filters.get(&name) {
+            if !filter.is_match(value.as_deref().unwrap_or_default()) {
+                return None;
+            }
+        }
+        if let Some(value) = value {
+            labels.push(Label { name, value });
+        }
+    }
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    Some(Series { table, labels })
+}
+
+impl TDengine {
+    /// Connect with the TDengine client library, connections are opened lazily.
+    #[cfg(feature = "native")]
+    pub fn native(options: &NativeOptions) -> Self {
+        TDengine {
+            connection: Box::new(native::Native::new(options)),
+        }
+    }
+
+    /// Connect to the taosAdapter REST API.
+    pub fn rest(options: &AdapterOptions) -> Result<Self> {
+        Ok(TDengine {
+            connection: Box::new(rest::Rest::new(options)?),
+        })
+    }
+
+    /// Connect to the taosAdapter WebSocket API.
+    pub fn websocket(options: &AdapterOptions) -> Self {
+        TDengine {
+            connection: Box::new(websocket::WebSocket::new(options)),
+        }
+    }
+
+    async fn query(&self, sql: &str) -> Result<QueryData> {
+        self.connection.query(sql).await
+    }
+
+    async fn exec(&self, sql: &str) -> Result<()> {
+        log::trace!("exec sql: {}", sql);
+        self.query(sql).await?;
+        Ok(())
+    }
+
+    async fn describe(&self, database: &str, stable: &str) -> Result<Option<Schema>> {
+        let sql = format!("describe {}.{}", database, stable);
+        let QueryData { rows, .. } = match self.query(&sql).await {
+            Ok(data) => data,
+            Err(err) => {
+                return match not_found(err, database, stable) {
+                    StorageError::TableNotFound(_) => Ok(None),
+                    err => Err(err),
+                }
+            }
+        };
+        // describe columns: Field, Type, Length, Note
+        let mut schema = Schema::default();
+        for row in rows {
+            let is_tag = row.get(3).and_then(Value::as_str) == Some("TAG");
+            let name = match row.into_iter().next() {
+                Some(Value::String(name)) => name,
+                _ => continue,
+            };
+            if is_tag {
+                schema.tags.push(name);
+            } else {
+                schema.columns.push(name);
+            }
+        }
+        Ok(Some(schema))
+    }
+
+    async fn insert(&self, database: &str, rows: &[Row<'_>]) -> Result<()> {
+        let values = rows
+            .iter()
+            .map(|row| match row.value {
+                Some(value) if value.is_nan() => format!(
+                    " {}.{} (ts, value, {}) values ({}, NULL, {})",
+                    database,
+                    row.table,
+                    STALE_COLUMN,
+                    row.timestamp,
+                    value.to_bits() == STALE_NAN
+                ),
+                Some(value) => format!(
+                    " {}.{} values ({}, {})",
+                    database, row.table, row.timestamp, value
+                ),
+                None => format!(
+                    " {}.{} values ({}, NULL)",
+                    database, row.table, row.timestamp
+                ),
+            })
+            .join("");
+        let sql = format!("insert into {}", values);
+        log::debug!("chunk sql length is {}", sql.len());
+        match self.query(&sql).await {
+            Ok(_) => Ok(()),
+            // super tables created before the stale column, it is added with the schema
+            Err(err)
+                if matches!(
+                    Code::of(&err),
+                    Some(Code::InvalidColumn) | Some(Code::InvalidOperation)
+                ) && rows.iter().filter_map(|row| row.value).any(f64::is_nan) =>
+            {
+                log::debug!("insert NaN values error: {}", err);
+                Err(StorageError::TableNotFound(format!("{}.*", database)))
+            }
+            Err(err) => Err(not_found(err, database, "*")),
+        }
+    }
+
+    /// Whether a super table has the stale column, the column is selected then.
+    async fn stale_column(&self, database: &str, stable: &str) -> Result<Option<&'static str>> {
+        let schema = self.describe(database, stable).await?;
+        Ok(schema
+            .filter(|schema| schema.columns.iter().any(|column| column == STALE_COLUMN))
+            .map(|_| STALE_COLUMN))
+    }
+
+    async fn stables(&self, database: &str) -> Result<Vec<String>> {
+        let sql = format!("show {}.stables", database);
+        let QueryData { rows, .. } = self
+            .query(&sql)
+            .await
+            .map_err(|err| not_found(err, database, "*"))?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| match row.into_iter().next() {
+                Some(Value::String(name)) => Some(name),
+                _ => None,
+            })
+            .collect_vec())
+    }
+
+    async fn resolve_series(
+        &self,
+        database: &str,
+        stable: &str,
+        plan: &QueryPlan,
+        limit: Option<usize>,
+    ) -> Result<Vec<Series>> {
+        let tags = match self.describe(database, stable).await? {
+            Some(schema) => schema.tags,
+            None => return Ok(Vec::new()),
+        };
+        let conditions = match plan.tag_conditions(tags.as_slice()) {
+            Some(conditions) => conditions,
+            None => {
+                log::debug!("super table {}.{} pruned", database, stable);
+                return Ok(Vec::new());
+            }
+        };
+        let columns = tags
+            .iter()
+            .filter(|tag| tag.as_str() != "taghash")
+            .collect_vec();
+        let select = format!(
+            "select tbname{} from {}.{}",
+            columns.iter().map(|tag| format!(", {}", tag)).join(""),
+            database,
+            stable
+        );
+        let page_sql = |conditions: &[String]| {
+            let mut sql = select.clone();
+            if !conditions.is_empty() {
+                sql.push_str(" WHERE ");
+                sql.push_str(&conditions.join(" AND "));
+            }
+            sql
+        };
+
+        // regex filters are applied here, so pages are read until enough series matched,
+        // each page starts after the last table name of the previous one
+        let mut series = Vec::new();
+        let mut last: Option<String> = None;
+        loop {
+            let page = match limit {
+                Some(limit) => {
+                    let mut conditions = conditions.clone();
+                    conditions.extend(last.as_ref().map(|last| format!("tbname > '{}'", last)));
+                    format!("{} ORDER BY tbname LIMIT {}", page_sql(&conditions), limit)
+                }
+                None => page_sql(&conditions),
+            };
+            log::debug!("series sql: {}", page);
+            let QueryData { columns, rows } = self.query(&page).await?;
+            let len = rows.len();
+            for row in rows {
+                if let Some(Value::String(table)) = row.first() {
+                    last = Some(table.clone());
+                }
+                if let Some(matched) = matched_series(stable, row, &columns, plan) {
+                    series.push(matched);
+                }
+            }
+            match limit {
+                Some(limit) if len == limit && series.len() < limit => {}
+                Some(limit) => {
+                    series.truncate(limit);
+                    break;
+                }
+                None => break,
+            }
+        }
+        Ok(series)
+    }
+
+    async fn fetch_samples(
+        &self,
+        database: &str,
+        stable: &str,
+        series: Vec<Series>,
+        plan: &QueryPlan,
+        limit: Option<usize>,
+    ) -> Result<Vec<TimeSeries>> {
+        let mut samples: HashMap<String, Vec<Sample>> = HashMap::new();
+        if series.is_empty() {
+            return Ok(Vec::new());
+        }
+        let stale = self.stale_column(database, stable).await?;
+        let mut sql = format!(
+            "select ts, value, {}tbname from {}.{} WHERE tbname in ({}) AND {}",
+            stale
+                .map(|column| format!("{}, ", column))
+                .unwrap_or_default(),
+            database,
+            stable,
+            series.iter().map(|s| format!("'{}'", s.table)).join(","),
+            plan.time_condition()
+        );
+        if let Some(limit) = limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        log::debug!("sql: {}", sql);
+        let QueryData { rows, .. } = self.query(&sql).await?;
+        for row in rows {
+            let mut fields = row.into_iter();
+            let (ts, value) = match (fields.next(), fields.next()) {
+                (Some(ts), Some(value)) => (ts, value),
+                _ => continue,
+            };
+            let stale = stale.and_then(|_| fields.next());
+            let table = match fields.next() {
+                Some(Value::String(table)) => table,
+                _ => continue,
+            };
+            let timestamp = ts
+                .as_timestamp()
+                .ok_or_else(|| StorageError::Response(format!("invalid timestamp {:?}", ts)))?;
+            let sample = Sample {
+                timestamp,
+                value: sample_value(&value, stale.as_ref()),
+            };
+            samples.entry(table).or_default().push(sample);
+        }
+        Ok(series
+            .into_iter()
+            .filter_map(|Series { table, labels }| {
+                let mut samples = samples.remove(&table)?;
+                samples.sort_by_key(|sample| sample.timestamp);
+                Some(TimeSeries { labels, samples })
+            })
+            .collect())
+    }
+
+    async fn series_in_range(
+        &self,
+        database: &str,
+        stable: &str,
+        series: Vec<Series>,
+        range: TimeRange,
+    ) -> Result<Vec<Series>> {
+        let condition = match range.condition() {
+            Some(condition) => condition,
+            None => return Ok(series),
+        };
+        let mut tables = HashSet::new();
+        for batch in &series.iter().chunks(TABLES_PER_QUERY) {
+            let sql = format!(
+                "select count(*) from {}.{} WHERE tbname in ({}) AND {} group by tbname",
+                database,
+                stable,
+                batch.map(|s| format!("'{}'", s.table)).join(","),
+                condition
+            );
+            log::debug!("series in range sql: {}", sql);
+            let QueryData { rows, .. } = self.query(&sql).await?;
+            tables.extend(
+                rows.into_iter()
+                    .filter_map(|row| row.into_iter().last())
+                    .filter_map(|field| match field {
+                        Value::String(table) => Some(table),
+                        _ => None,
+                    }),
+            );
+        }
+        Ok(series
+            .into_iter()
+            .filter(|s| tables.contains(&s.table))
+            .collect())
+    }
+
+    async fn tag_values(&self, database: &str, stable: &str, tag: &str) -> Result<Vec<String>> {
+        let sql = format!("select distinct {} from {}.{}", tag, database, stable);
+        log::debug!("label values sql: {}", sql);
+        let QueryData { rows, .. } = self.query(&sql).await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| match row.into_iter().next() {
+                Some(Value::String(value)) => Some(value),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Fetch the last row of each series with TDengine `last_row`.
+    async fn last_rows(
+        &self,
+        database: &str,
+        stable: &str,
+        series: Vec<Series>,
+    ) -> Result<Vec<TimeSeries>> {
+        let mut samples = BTreeMap::new();
+        let stale = self.stale_column(database, stable).await?;
+        for batch in &series.iter().chunks(TABLES_PER_QUERY) {
+            let sql = format!(
+                "select last_row(ts), last_row(value){} from {}.{} WHERE tbname in ({}) group by tbname",
+                stale
+                    .map(|column| format!(", last_row({})", column))
+                    .unwrap_or_default(),
+                database,
+                stable,
+                batch.map(|s| format!("'{}'", s.table)).join(",")
+            );
+            log::debug!("last row sql: {}", sql);
+            let QueryData { rows, .. } = self.query(&sql).await?;
+            for row in rows {
+                let mut fields = row.into_iter();
+                let (ts, value) = match (fields.next(), fields.next()) {
+                    (Some(ts), Some(value)) => (ts, value),
+                    _ => continue,
+                };
+                let stale = stale.and_then(|_| fields.next());
+                let table = match fields.last() {
+                    Some(Value::String(table)) => table,
+                    _ => continue,
+                };
+                let timestamp = match ts.as_timestamp() {
+                    Some(timestamp) => timestamp,
+                    None => continue,
+                };
+                let sample = Sample {
+                    timestamp,
+                    value: sample_value(&value, stale.as_ref()),
+                };
+                samples.insert(table, sample);
+            }
+        }
+        Ok(series
+            .into_iter()
+            .filter_map(|Series { table, labels }| {
+                let sample = samples.remove(&table)?;
+                Some(TimeSeries {
+                    labels,
+                    samples: vec![sample],
+                })
+            })
+            .collect())
+    }
+}
+
+impl Storage for TDengine {
+    fn ping(&self, timeout: Duration) -> StorageFuture<'_, ()> {
+        self.connection.ping(timeout)
+    }
+
+    fn pool_state(&self) -> Option<PoolState> {
+        Some(self.connection.pool_state())
+    }
+
+    fn endpoints(&self) -> Vec<EndpointState> {
+        self.connection.endpoints().states()
+    }
+
+    fn close(&self) -> StorageFuture<'_, ()> {
+        self.connection.close()
+    }
+
+    fn create_database<'a>(&'a self, database: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.exec(&format!("create database if not exists {}", database))
+                .await
+        })
+    }
+
+    fn create_stable<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tags: &'a [String],
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let sql = format!(
+                "create stable if not exists {}.{} (ts timestamp, value double, {} bool) tags (taghash binary({}){})",
+                database,
+                stable,
+                STALE_COLUMN,
+                TAGHASH_LENGTH,
+                tags.iter()
+                    .map(|tag| format!(", {} binary({})", tag, TAG_LENGTH))
+                    .join("")
+            );
+            self.exec(&sql)
+                .await
+                .map_err(|err| not_found(err, database, stable))
+        })
+    }
+
+    fn add_tag<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tag: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let sql = format!(
+                "alter stable {}.{} add tag {} binary({})",
+                database, stable, tag, TAG_LENGTH
+            );
+            match self.exec(&sql).await {
+                Err(err) if Code::of(&err) == Some(Code::FieldAlreadyExist) => Ok(()),
+                res => res,
+            }
+        })
+    }
+
+    fn add_stale_column<'a>(&'a self, database: &'a str, stable: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let sql = format!(
+                "alter stable {}.{} add column {} bool",
+                database, stable, STALE_COLUMN
+            );
+            match self.exec(&sql).await {
+                Err(err) if Code::of(&err) == Some(Code::FieldAlreadyExist) => Ok(()),
+                res => res,
+            }
+        })
+    }
+
+    fn create_table<'a>(
+        &'a self,
+        database: &'a str,
+        table: &'a str,
+        stable: &'a str,
+        tags: &'a [(String, String)],
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let sql = format!(
+                "create table if not exists {}.{} using {}.{} ({}) tags({})",
+                database,
+                table,
+                database,
+                stable,
+                tags.iter().map(|(name, _)| name).join(","),
+                tags.iter()
+                    .map(|(_, value)| format!("\"{}\"", tag_value_escape(truncate_tag(value))))
+                    .join(",")
+            );
+            match self.exec(&sql).await {
+                Err(err) if err.to_string().contains("tag value too long") => {
+                    Err(StorageError::TagValueTooLong)
+                }
+                res => res,
+            }
+        })
+    }
+
+    fn describe<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+    ) -> StorageFuture<'a, Option<Schema>> {
+        Box::pin(TDengine::describe(self, database, stable))
+    }
+
+    fn insert<'a>(&'a self, database: &'a str, rows: &'a [Row<'a>]) -> StorageFuture<'a, ()> {
+        Box::pin(TDengine::insert(self, database, rows))
+    }
+
+    fn stables<'a>(&'a self, database: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(TDengine::stables(self, database))
+    }
+
+    fn resolve_series<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        plan: &'a QueryPlan,
+        limit: Option<usize>,
+    ) -> StorageFuture<'a, Vec<Series>> {
+        Box::pin(TDengine::resolve_series(
+            self, database, stable, plan, limit,
+        ))
+    }
+
+    fn fetch_samples<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+        plan: &'a QueryPlan,
+        limit: Option<usize>,
+    ) -> StorageFuture<'a, Vec<TimeSeries>> {
+        Box::pin(TDengine::fetch_samples(
+            self, database, stable, series, plan, limit,
+        ))
+    }
+
+    fn series_in_range<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+        range: TimeRange,
+    ) -> StorageFuture<'a, Vec<Series>> {
+        Box::pin(TDengine::series_in_range(
+            self, database, stable, series, range,
+        ))
+    }
+
+    fn tag_values<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        tag: &'a str,
+    ) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(TDengine::tag_values(self, database, stable, tag))
+    }
+
+    fn last_rows<'a>(
+        &'a self,
+        database: &'a str,
+        stable: &'a str,
+        series: Vec<Series>,
+    ) -> StorageFuture<'a, Vec<TimeSeries>> {
+        Box::pin(TDengine::last_rows(self, database, stable, series))
+    }
+}
+
+#[test]
+fn test_parse_timestamp() {
+    use chrono::TimeZone;
+    let ms = 1621511073123;
+    assert_eq!(parse_timestamp("2021-05-20T11:44:33.123Z"), Some(ms));
+    assert_eq!(parse_timestamp("2021-05-20T19:44:33.123+08:00"), Some(ms));
+    assert_eq!(parse_timestamp("2021-05-20T19:44:33.123+0800"), Some(ms));
+    let local = chrono::Local.timestamp_millis_opt(ms).unwrap();
+    let local = local.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+    assert_eq!(parse_timestamp(&local), Some(ms));
+    assert_eq!(parse_timestamp("yesterday"), None);
+}
+
+#[test]
+fn test_truncate_tag() {
+    assert_eq!(truncate_tag("node"), "node");
+    assert_eq!(truncate_tag(&"a".repeat(200)).len(), TAG_LENGTH - 1);
+    // byte 127 falls inside the 43rd character
+    let value = "涛".repeat(50);
+    assert_eq!(truncate_tag(&value), "涛".repeat(42));
+}
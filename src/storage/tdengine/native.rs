@@ -0,0 +1,233 @@
+//! Native connections of the TDengine client library.
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+use libtaos::field::{Field, TaosQueryData};
+use libtaos::{Taos, TaosCfg, TaosCfgBuilder, TaosCode, TaosError};
+use r2d2::PooledConnection;
+
+use super::*;
+
+pub(super) fn code(err: &libtaos::Error) -> Option<Code> {
+    match err {
+        libtaos::Error::RawTaosError(TaosError { code, .. }) => match code {
+            TaosCode::RpcNetworkUnavail => Some(Code::Unavailable),
+            TaosCode::MndDbNotSelected => Some(Code::DatabaseNotSelected),
+            TaosCode::MndInvalidTableName => Some(Code::InvalidTableName),
+            TaosCode::MndFieldAlreayExist => Some(Code::FieldAlreadyExist),
+            TaosCode::MndTooManyTags => Some(Code::TooManyTags),
+            TaosCode::TdbTimestampOutOfRange => Some(Code::TimestampOutOfRange),
+            TaosCode::TscInvalidOperation => Some(Code::InvalidOperation),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn value(field: Field) -> Value {
+    if let Field::Null = field {
+        return Value::Null;
+    }
+    if let Some(timestamp) = field.as_raw_timestamp() {
+        Value::Timestamp(timestamp)
+    } else if let Some(value) = field.as_double() {
+        Value::Double(*value)
+    } else if let Some(value) = field.as_string() {
+        Value::String(value.to_string())
+    } else {
+        let value = field.to_string();
+        value.parse().map_or(Value::String(value), Value::Int)
+    }
+}
+
+/// A connection and the endpoint it is connected to.
+pub(super) struct EndpointTaos {
+    endpoint: usize,
+    taos: Taos,
+}
+
+impl Deref for EndpointTaos {
+    type Target = Taos;
+
+    fn deref(&self) -> &Taos {
+        &self.taos
+    }
+}
+
+/// Opens connections to the endpoints up, connections to endpoints down are dropped
+/// when returned to the pool.
+#[derive(Debug)]
+pub(super) struct Manager {
+    cfgs: Vec<TaosCfg>,
+    endpoints: Arc<Endpoints>,
+}
+
+impl r2d2::ManageConnection for Manager {
+    type Connection = EndpointTaos;
+    type Error = libtaos::Error;
+
+    fn connect(&self) -> std::result::Result<EndpointTaos, libtaos::Error> {
+        let mut last_err = None;
+        for endpoint in self.endpoints.candidates() {
+            match self.cfgs[endpoint].connect() {
+                Ok(taos) => {
+                    self.endpoints.succeeded(endpoint);
+                    return Ok(EndpointTaos { endpoint, taos });
+                }
+                Err(err) => {
+                    log::debug!(
+                        "connect to {} error: {}",
+                        self.endpoints.address(endpoint),
+                        err
+                    );
+                    self.endpoints.failed(endpoint);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least one TDengine endpoint"))
+    }
+
+    fn is_valid(&self, _taos: &mut EndpointTaos) -> std::result::Result<(), libtaos::Error> {
+        Ok(())
+    }
+
+    fn has_broken(&self, taos: &mut EndpointTaos) -> bool {
+        !self.endpoints.is_up(taos.endpoint)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Native {
+    /// `None` once closed.
+    pool: RwLock<Option<r2d2::Pool<Manager>>>,
+    endpoints: Arc<Endpoints>,
+    waiting: AtomicUsize,
+}
+
+impl Native {
+    pub(super) fn new(options: &NativeOptions) -> Self {
+        let endpoints = Arc::new(Endpoints::new(
+            options
+                .endpoints
+                .iter()
+                .map(|(host, port)| format!("{}:{}", host, port)),
+        ));
+        let cfgs = options
+            .endpoints
+            .iter()
+            .map(|(host, port)| {
+                TaosCfgBuilder::default()
+                    .ip(host)
+                    .user(&options.user)
+                    .pass(&options.password)
+                    .db("log")
+                    .port(*port)
+                    .build()
+                    .expect("ToasCfg builder error")
+            })
+            .collect();
+        let manager = Manager {
+            cfgs,
+            endpoints: endpoints.clone(),
+        };
+        let pool = r2d2::Pool::builder()
+            .max_size(options.max_connections)
+            .test_on_check_out(false)
+            .connection_timeout(Duration::from_secs(500))
+            .max_lifetime(Some(Duration::from_secs(600)))
+            .idle_timeout(Some(Duration::from_secs(300)))
+            // start even if TDengine is down, readiness reports it instead
+            .build_unchecked(manager);
+        Native {
+            pool: RwLock::new(Some(pool)),
+            endpoints,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get a pooled connection, within `timeout` if any or the pool connection timeout.
+    ///
+    /// The pool blocks, so it waits on a blocking thread, callers waiting for exhausted
+    /// connections are counted until they get one or are dropped, e.g. on a read timeout.
+    async fn get(&self, timeout: Option<Duration>) -> Result<PooledConnection<Manager>> {
+        struct Waiting<'a>(&'a AtomicUsize);
+
+        impl Drop for Waiting<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        let pool = self.pool.read().unwrap().clone();
+        let pool = pool.ok_or(StorageError::Closed)?;
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+        let taos = tokio::task::spawn_blocking(move || match timeout {
+            Some(timeout) => pool.get_timeout(timeout),
+            None => pool.get(),
+        })
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+        Ok(taos?)
+    }
+
+    /// Run a query, marking the endpoint down if it is unreachable.
+    async fn query_on(&self, taos: &EndpointTaos, sql: &str) -> Result<TaosQueryData> {
+        match taos.query(sql).await {
+            Ok(data) => Ok(data),
+            Err(err) => {
+                if code(&err) == Some(Code::Unavailable) {
+                    self.endpoints.failed(taos.endpoint);
+                }
+                Err(err.into())
+            }
+        }
+    }
+}
+
+impl Connection for Native {
+    fn query<'a>(&'a self, sql: &'a str) -> StorageFuture<'a, QueryData> {
+        Box::pin(async move {
+            let taos = self.get(None).await?;
+            let TaosQueryData { column_meta, rows } = self.query_on(&taos, sql).await?;
+            Ok(QueryData {
+                columns: column_meta.into_iter().map(|meta| meta.name).collect(),
+                rows: rows
+                    .into_iter()
+                    .map(|row| row.into_iter().map(value).collect())
+                    .collect(),
+            })
+        })
+    }
+
+    fn ping(&self, timeout: Duration) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let taos = self.get(Some(timeout)).await?;
+            self.query_on(&taos, "select server_status()").await?;
+            Ok(())
+        })
+    }
+
+    fn pool_state(&self) -> PoolState {
+        let state = match &*self.pool.read().unwrap() {
+            Some(pool) => pool.state(),
+            None => return PoolState::default(),
+        };
+        PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+
+    fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    fn close(&self) -> StorageFuture<'_, ()> {
+        // the pool closes its connections once the last handle is dropped
+        self.pool.write().unwrap().take();
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
@@ -0,0 +1,167 @@
+//! taosAdapter REST API connector.
+use std::sync::RwLock;
+
+use reqwest::header::AUTHORIZATION;
+
+use super::*;
+
+#[derive(Debug)]
+pub(super) struct Rest {
+    /// `None` once closed.
+    client: RwLock<Option<reqwest::Client>>,
+    endpoints: Endpoints,
+    authorization: String,
+    limiter: Limiter,
+}
+
+/// Response of `/rest/sql` and `/rest/sqlt`, in TDengine 2.x or 3.x format.
+#[derive(Debug, Deserialize)]
+struct Response {
+    /// `succ` or `error` in 2.x.
+    status: Option<String>,
+    #[serde(default)]
+    code: i32,
+    desc: Option<String>,
+    /// Column name, type and length.
+    #[serde(default)]
+    column_meta: Vec<(String, serde_json::Value, serde_json::Value)>,
+    #[serde(default)]
+    data: Vec<Vec<serde_json::Value>>,
+}
+
+impl Rest {
+    pub(super) fn new(options: &AdapterOptions) -> Result<Self> {
+        let authorization = match &options.token {
+            Some(token) => format!("Taosd {}", token),
+            None => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", options.user, options.password))
+            ),
+        };
+        let client = reqwest::Client::builder()
+            .pool_max_idle_per_host(options.max_connections as usize)
+            .timeout(options.timeout)
+            .build()?;
+        Ok(Rest {
+            client: RwLock::new(Some(client)),
+            endpoints: Endpoints::new(options.urls.iter().cloned()),
+            authorization,
+            limiter: Limiter::new(options.max_connections),
+        })
+    }
+
+    /// Post the SQL to the endpoints up, failing over to the next one if an endpoint
+    /// could not be connected.
+    async fn send(&self, sql: &str) -> Result<reqwest::Response> {
+        let client = self.client.read().unwrap().clone();
+        let client = client.ok_or(StorageError::Closed)?;
+        let mut last_err = None;
+        for endpoint in self.endpoints.candidates() {
+            let response = client
+                .post(self.endpoints.address(endpoint))
+                .header(AUTHORIZATION, &self.authorization)
+                .body(sql.to_string())
+                .send()
+                .await;
+            match response {
+                Ok(response) => {
+                    self.endpoints.succeeded(endpoint);
+                    return Ok(response);
+                }
+                Err(err) => {
+                    self.endpoints.failed(endpoint);
+                    if !err.is_connect() {
+                        return Err(err.into());
+                    }
+                    log::debug!(
+                        "connect to {} error: {}",
+                        self.endpoints.address(endpoint),
+                        err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least one TDengine endpoint").into())
+    }
+
+    async fn request(&self, sql: &str) -> Result<QueryData> {
+        let response = self.send(sql).await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        let response: Response = match serde_json::from_slice(&body) {
+            Ok(response) => response,
+            Err(_) if !status.is_success() => {
+                return Err(StorageError::Adapter {
+                    code: status.as_u16() as i32,
+                    message: String::from_utf8_lossy(&body).into_owned(),
+                })
+            }
+            Err(err) => return Err(StorageError::Response(err.to_string())),
+        };
+        if response.code != 0 || response.status.as_deref() == Some("error") {
+            return Err(StorageError::Adapter {
+                code: response.code,
+                message: response.desc.unwrap_or_default(),
+            });
+        }
+        let types = response
+            .column_meta
+            .iter()
+            .map(|(_, ty, _)| ColumnType::from_json(ty))
+            .collect_vec();
+        Ok(QueryData {
+            columns: response
+                .column_meta
+                .into_iter()
+                .map(|(name, _, _)| name)
+                .collect(),
+            rows: response
+                .data
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .zip(&types)
+                        .map(|(value, ty)| Value::from_json(value, *ty))
+                        .collect()
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Connection for Rest {
+    fn query<'a>(&'a self, sql: &'a str) -> StorageFuture<'a, QueryData> {
+        Box::pin(async move {
+            let _permit = self.limiter.acquire().await;
+            self.request(sql).await
+        })
+    }
+
+    fn ping(&self, timeout: Duration) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let ping = async {
+                let _permit = self.limiter.acquire().await;
+                self.request("select server_status()").await
+            };
+            tokio::time::timeout(timeout, ping)
+                .await
+                .map_err(|_| StorageError::Timeout)??;
+            Ok(())
+        })
+    }
+
+    fn pool_state(&self) -> PoolState {
+        self.limiter.state()
+    }
+
+    fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    fn close(&self) -> StorageFuture<'_, ()> {
+        // idle connections are closed once the last handle of the client is dropped
+        self.client.write().unwrap().take();
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
@@ -0,0 +1,259 @@
+//! taosAdapter WebSocket API connector.
+//!
+//! Requests are JSON messages of `{"action": ..., "args": {"req_id": ..., ...}}`, a query
+//! runs `query`, then `fetch` and `fetch_json` until completed, then `free_result`.
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::Mutex;
+
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use super::*;
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub(super) struct WebSocket {
+    endpoints: Endpoints,
+    user: String,
+    password: String,
+    limiter: Limiter,
+    /// Idle connections and their endpoints, at most one per slot of the limiter.
+    idle: Mutex<Vec<(usize, Stream)>>,
+    req_id: AtomicU64,
+    closed: AtomicBool,
+    timeout: Duration,
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("endpoints", &self.endpoints)
+            .field("user", &self.user)
+            .field("limiter", &self.limiter)
+            .finish()
+    }
+}
+
+/// Response of a request, fields depend on the action.
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(default)]
+    code: i32,
+    #[serde(default)]
+    message: String,
+    /// Result id of `query`.
+    #[serde(default)]
+    id: u64,
+    #[serde(default)]
+    fields_count: usize,
+    #[serde(default)]
+    fields_names: Vec<String>,
+    #[serde(default)]
+    fields_types: Vec<serde_json::Value>,
+    /// If `fetch` reached the end of the result.
+    #[serde(default)]
+    completed: bool,
+    /// Rows of `fetch_json`.
+    #[serde(default)]
+    data: Vec<Vec<serde_json::Value>>,
+}
+
+impl WebSocket {
+    pub(super) fn new(options: &AdapterOptions) -> Self {
+        let urls = options.urls.iter().map(|url| match &options.token {
+            Some(token) if url.contains('?') => format!("{}&token={}", url, token),
+            Some(token) => format!("{}?token={}", url, token),
+            None => url.clone(),
+        });
+        WebSocket {
+            endpoints: Endpoints::new(urls),
+            user: options.user.clone(),
+            password: options.password.clone(),
+            limiter: Limiter::new(options.max_connections),
+            idle: Mutex::new(Vec::new()),
+            req_id: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            timeout: options.timeout,
+        }
+    }
+
+    async fn request(
+        &self,
+        stream: &mut Stream,
+        action: &str,
+        mut args: serde_json::Value,
+    ) -> Result<Response> {
+        args["req_id"] = self.req_id.fetch_add(1, Ordering::Relaxed).into();
+        let request = json!({ "action": action, "args": args });
+        stream.send(Message::Text(request.to_string())).await?;
+        loop {
+            let text = match stream.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(StorageError::Response("connection closed".to_string()))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err.into()),
+            };
+            let response: Response = serde_json::from_str(&text)
+                .map_err(|err| StorageError::Response(err.to_string()))?;
+            if response.code != 0 {
+                return Err(StorageError::Adapter {
+                    code: response.code,
+                    message: response.message,
+                });
+            }
+            return Ok(response);
+        }
+    }
+
+    async fn connect_to(&self, url: &str) -> Result<Stream> {
+        let connect = async {
+            let (mut stream, _) = tokio_tungstenite::connect_async(url).await?;
+            let args = json!({ "user": self.user, "password": self.password, "db": "" });
+            self.request(&mut stream, "conn", args).await?;
+            Ok(stream)
+        };
+        tokio::time::timeout(self.timeout, connect)
+            .await
+            .map_err(|_| StorageError::Timeout)?
+    }
+
+    /// Connect to the first endpoint reachable.
+    async fn connect(&self) -> Result<(usize, Stream)> {
+        let mut last_err = None;
+        for endpoint in self.endpoints.candidates() {
+            match self.connect_to(self.endpoints.address(endpoint)).await {
+                Ok(stream) => {
+                    self.endpoints.succeeded(endpoint);
+                    return Ok((endpoint, stream));
+                }
+                Err(err) => {
+                    log::debug!(
+                        "connect to {} error: {}",
+                        self.endpoints.address(endpoint),
+                        err
+                    );
+                    self.endpoints.failed(endpoint);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least one TDengine endpoint"))
+    }
+
+    async fn run(&self, stream: &mut Stream, sql: &str) -> Result<QueryData> {
+        let query = self.request(stream, "query", json!({ "sql": sql })).await?;
+        let mut data = QueryData::default();
+        if query.fields_count == 0 {
+            return Ok(data);
+        }
+        let types = query
+            .fields_types
+            .iter()
+            .map(ColumnType::from_json)
+            .collect_vec();
+        data.columns = query.fields_names;
+        let id = query.id;
+        let rows = self.fetch(stream, id, &types).await;
+        // freed after errors of TDengine too, the connection is reused then
+        let request = json!({ "action": "free_result", "args": { "id": id } });
+        let freed = stream.send(Message::Text(request.to_string())).await;
+        data.rows = rows?;
+        freed?;
+        Ok(data)
+    }
+
+    /// Fetch the rows of a query result until completed.
+    async fn fetch(
+        &self,
+        stream: &mut Stream,
+        id: u64,
+        types: &[ColumnType],
+    ) -> Result<Vec<Vec<Value>>> {
+        let mut data = Vec::new();
+        loop {
+            let fetch = self.request(stream, "fetch", json!({ "id": id })).await?;
+            if fetch.completed {
+                return Ok(data);
+            }
+            let rows = self
+                .request(stream, "fetch_json", json!({ "id": id }))
+                .await?;
+            data.extend(rows.data.into_iter().map(|row| {
+                row.into_iter()
+                    .zip(types)
+                    .map(|(value, ty)| Value::from_json(value, *ty))
+                    .collect_vec()
+            }));
+        }
+    }
+
+    async fn query(&self, sql: &str) -> Result<QueryData> {
+        let _permit = self.limiter.acquire().await;
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(StorageError::Closed);
+        }
+        let idle = {
+            let mut idle = self.idle.lock().unwrap();
+            // drop connections to endpoints down
+            idle.retain(|(endpoint, _)| self.endpoints.is_up(*endpoint));
+            idle.pop()
+        };
+        let (endpoint, mut stream) = match idle {
+            Some(idle) => idle,
+            None => self.connect().await?,
+        };
+        let result = tokio::time::timeout(self.timeout, self.run(&mut stream, sql))
+            .await
+            .unwrap_or(Err(StorageError::Timeout));
+        match &result {
+            Ok(_) | Err(StorageError::Adapter { .. }) if !self.closed.load(Ordering::Relaxed) => {
+                self.idle.lock().unwrap().push((endpoint, stream));
+            }
+            Err(StorageError::WebSocket(_)) => self.endpoints.failed(endpoint),
+            // closed, or out of sync with the server, drop the connection
+            _ => {}
+        }
+        result
+    }
+}
+
+impl Connection for WebSocket {
+    fn query<'a>(&'a self, sql: &'a str) -> StorageFuture<'a, QueryData> {
+        Box::pin(WebSocket::query(self, sql))
+    }
+
+    fn ping(&self, timeout: Duration) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            tokio::time::timeout(timeout, self.query("select server_status()"))
+                .await
+                .map_err(|_| StorageError::Timeout)??;
+            Ok(())
+        })
+    }
+
+    fn pool_state(&self) -> PoolState {
+        self.limiter.state()
+    }
+
+    fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    fn close(&self) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            self.closed.store(true, Ordering::Relaxed);
+            let idle = std::mem::take(&mut *self.idle.lock().unwrap());
+            for (_, mut stream) in idle {
+                if let Err(err) = stream.close(None).await {
+                    log::debug!("close WebSocket connection error: {}", err);
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -3,7 +3,7 @@ use libtaos::*;
 pub fn var_or_default(env: &str, default: &str) -> String {
     std::env::var(env).unwrap_or(default.to_string())
 }
-pub fn taos() -> Result<Taos, Error> {
+pub fn cfg() -> TaosCfg {
     TaosCfgBuilder::default()
         .ip(&var_or_default("TEST_TAOS_IP", "127.0.0.1"))
         .user(&var_or_default("TEST_TAOS_USER", "root"))
@@ -16,5 +16,20 @@ pub fn taos() -> Result<Taos, Error> {
         )
         .build()
         .expect("ToasCfg builder error")
-        .connect()
+}
+pub fn taos() -> Result<Taos, Error> {
+    cfg().connect()
+}
+pub fn native_options() -> crate::storage::NativeOptions {
+    crate::storage::NativeOptions {
+        endpoints: vec![(
+            var_or_default("TEST_TAOS_IP", "127.0.0.1"),
+            var_or_default("TEST_TAOS_PORT", "6030")
+                .parse::<u16>()
+                .unwrap(),
+        )],
+        user: var_or_default("TEST_TAOS_USER", "root"),
+        password: var_or_default("TEST_TAOS_PASS", "taosdata"),
+        max_connections: 8,
+    }
 }
@@ -0,0 +1,144 @@
+//! TLS and mutual TLS of the HTTP listener, certificates are reloaded on rotation.
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+
+use crate::config::TlsConfig;
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("read PEM file {}", path.display()))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs: Vec<_> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    anyhow::ensure!(!certs.is_empty(), "no certificate in {}", path.display());
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .with_context(|| format!("no RSA or PKCS8 private key in {}", path.display()))
+}
+
+/// Latest modification time of the files.
+fn modified(paths: &[&Path]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Server certificate resolver, serves the latest loaded certificate and key.
+pub struct CertResolver {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl CertResolver {
+    pub fn new(cert_file: &Path, key_file: &Path) -> Result<Self> {
+        let modified = modified(&[cert_file, key_file]);
+        let key = Self::load(cert_file, key_file)?;
+        Ok(CertResolver {
+            cert_file: cert_file.to_path_buf(),
+            key_file: key_file.to_path_buf(),
+            key: RwLock::new(Arc::new(key)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    fn load(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey> {
+        let certs = read_certs(cert_file)?;
+        let key = rustls::sign::any_supported_type(&read_key(key_file)?)
+            .map_err(|_| anyhow::anyhow!("unsupported private key type"))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    /// Reload the certificate and key, the current ones are kept on error.
+    pub fn reload(&self) -> Result<()> {
+        let modified = modified(&[&self.cert_file, &self.key_file]);
+        let key = Self::load(&self.cert_file, &self.key_file)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        *self.modified.lock().unwrap() = modified;
+        log::info!("TLS certificate {} reloaded", self.cert_file.display());
+        Ok(())
+    }
+
+    /// Reload if the files are modified since last load.
+    fn reload_if_modified(&self) -> Result<()> {
+        let modified = modified(&[&self.cert_file, &self.key_file]);
+        if modified != *self.modified.lock().unwrap() {
+            self.reload()?;
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Build the rustls server config, client certificates are required if a client CA is set.
+pub fn server_config(config: &TlsConfig, resolver: Arc<CertResolver>) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("add client CA from {}", path.display()))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+/// Poll the certificate files and reload them on rotation.
+pub fn watch(resolver: Arc<CertResolver>, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = resolver.reload_if_modified() {
+                log::error!("reload TLS certificate error: {:#}", err);
+            }
+        }
+    });
+}
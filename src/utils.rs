@@ -26,6 +26,13 @@ fn test_md5hash() {
     assert_eq!(md5sum(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
 }
 
+pub fn tag_name_escape(name: &str) -> String {
+    name.replace(":", "_")
+        .replace(".", "_")
+        .replace("-", "_")
+        .to_lowercase()
+}
+
 pub fn tag_value_escape(value: &str) -> String {
     value.replace("\"", "\\\"")
 }